@@ -0,0 +1,179 @@
+use crate::language::decimal;
+use crate::language::heap::alloc;
+use crate::language::sized::IntType;
+use cranelift_jit::JITBuilder;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{FromPrimitive, One, Signed, ToPrimitive, Zero};
+
+/// Multiplications, powers and shifts that would produce more bits than this
+/// return null (which compiled code reports) instead of exhausting memory,
+/// e.g. `2 ^ 10 ^ 10`.
+const MAX_BITS: u64 = 1 << 20;
+
+/// Read a big integer handed out to compiled code. Null only shows up in
+/// variables whose assignment never ran, which read as zero.
+pub fn read(ptr: *const BigInt) -> BigInt {
+    value(ptr).clone()
+}
+
+fn value<'a>(ptr: *const BigInt) -> &'a BigInt {
+    static ZERO: BigInt = BigInt::ZERO;
+    unsafe { ptr.as_ref() }.unwrap_or(&ZERO)
+}
+
+pub fn register_symbols(builder: &mut JITBuilder) {
+    builder.symbol("calc_big_from_i64", from_i64 as *const u8);
+    builder.symbol("calc_big_from_f64", from_f64 as *const u8);
+    builder.symbol("calc_big_to_f64", to_f64 as *const u8);
+    builder.symbol("calc_big_fits_i64", fits_i64 as *const u8);
+    builder.symbol("calc_big_to_i64", to_i64 as *const u8);
+    builder.symbol("calc_big_fits_int", fits_int as *const u8);
+    builder.symbol("calc_big_to_int", to_int as *const u8);
+    builder.symbol("calc_big_sign", sign as *const u8);
+    builder.symbol("calc_big_cmp", cmp as *const u8);
+    builder.symbol("calc_big_neg", neg as *const u8);
+    builder.symbol("calc_big_not", not as *const u8);
+    builder.symbol("calc_big_abs", abs as *const u8);
+    builder.symbol("calc_big_add", add as *const u8);
+    builder.symbol("calc_big_sub", sub as *const u8);
+    builder.symbol("calc_big_mul", mul as *const u8);
+    builder.symbol("calc_big_floor_div", floor_div as *const u8);
+    builder.symbol("calc_big_rem", rem as *const u8);
+    builder.symbol("calc_big_pow", pow as *const u8);
+    builder.symbol("calc_big_and", and as *const u8);
+    builder.symbol("calc_big_or", or as *const u8);
+    builder.symbol("calc_big_xor", xor as *const u8);
+    builder.symbol("calc_big_shl", shl as *const u8);
+    builder.symbol("calc_big_shr", shr as *const u8);
+}
+
+extern "C" fn from_i64(n: i64) -> *mut BigInt {
+    alloc(BigInt::from(n))
+}
+
+/// `x` truncated toward zero. Compiled code checks that it is finite first.
+extern "C" fn from_f64(x: f64) -> *mut BigInt {
+    alloc(BigInt::from_f64(x.trunc()).unwrap_or_default())
+}
+
+extern "C" fn to_f64(a: *const BigInt) -> f64 {
+    value(a).to_f64().unwrap_or(f64::NAN)
+}
+
+/// Whether `a` fits in an `i64`, as 1 or 0.
+extern "C" fn fits_i64(a: *const BigInt) -> i64 {
+    value(a).to_i64().is_some().into()
+}
+
+/// `a` as an `i64`, which it has to fit in.
+extern "C" fn to_i64(a: *const BigInt) -> i64 {
+    value(a).to_i64().unwrap_or_default()
+}
+
+/// Whether `a` fits in a sized integer type.
+extern "C" fn fits_int(a: *const BigInt, signed: i64, bits: i64) -> i64 {
+    let ty = IntType {
+        signed: signed != 0,
+        bits: bits as u32,
+    };
+    ty.contains(value(a)).into()
+}
+
+/// The low 128 bits of `a`, which has to fit in a sized integer type.
+extern "C" fn to_int(a: *const BigInt) -> decimal::Parts {
+    let mask = BigInt::from(u128::MAX);
+    decimal::Parts::from((value(a) & mask).to_u128().unwrap_or_default() as i128)
+}
+
+extern "C" fn sign(a: *const BigInt) -> i64 {
+    value(a).signum().to_i64().unwrap_or_default()
+}
+
+extern "C" fn cmp(a: *const BigInt, b: *const BigInt) -> i64 {
+    value(a).cmp(value(b)) as i64
+}
+
+extern "C" fn neg(a: *const BigInt) -> *mut BigInt {
+    alloc(-value(a))
+}
+
+extern "C" fn not(a: *const BigInt) -> *mut BigInt {
+    alloc(!value(a))
+}
+
+extern "C" fn abs(a: *const BigInt) -> *mut BigInt {
+    alloc(value(a).abs())
+}
+
+extern "C" fn add(a: *const BigInt, b: *const BigInt) -> *mut BigInt {
+    alloc(value(a) + value(b))
+}
+
+extern "C" fn sub(a: *const BigInt, b: *const BigInt) -> *mut BigInt {
+    alloc(value(a) - value(b))
+}
+
+extern "C" fn mul(a: *const BigInt, b: *const BigInt) -> *mut BigInt {
+    if value(a).bits() + value(b).bits() > MAX_BITS + 1 {
+        return std::ptr::null_mut();
+    }
+    alloc(value(a) * value(b))
+}
+
+/// Rounds toward negative infinity like `//` on 64-bit integers. Compiled
+/// code checks for a zero divisor first.
+extern "C" fn floor_div(a: *const BigInt, b: *const BigInt) -> *mut BigInt {
+    alloc(value(a).div_floor(value(b)))
+}
+
+/// Truncated remainder like `srem`. Compiled code checks for a zero divisor
+/// first.
+extern "C" fn rem(a: *const BigInt, b: *const BigInt) -> *mut BigInt {
+    alloc(value(a) % value(b))
+}
+
+/// Compiled code checks for a negative exponent first.
+extern "C" fn pow(base: *const BigInt, exponent: *const BigInt) -> *mut BigInt {
+    let (base, exponent) = (value(base), value(exponent));
+    match exponent.to_u32() {
+        Some(exponent) if base.bits() * u64::from(exponent) <= MAX_BITS => {
+            alloc(base.pow(exponent))
+        }
+        // Only 0, 1 and -1 survive an exponent this large
+        _ if base.bits() <= 1 => match base.is_negative() && exponent.is_odd() {
+            true => alloc(base.clone()),
+            false => alloc(base.abs()),
+        },
+        _ => std::ptr::null_mut(),
+    }
+}
+
+extern "C" fn and(a: *const BigInt, b: *const BigInt) -> *mut BigInt {
+    alloc(value(a) & value(b))
+}
+
+extern "C" fn or(a: *const BigInt, b: *const BigInt) -> *mut BigInt {
+    alloc(value(a) | value(b))
+}
+
+extern "C" fn xor(a: *const BigInt, b: *const BigInt) -> *mut BigInt {
+    alloc(value(a) ^ value(b))
+}
+
+/// Compiled code checks for a negative amount first, for both shifts.
+extern "C" fn shl(a: *const BigInt, amount: *const BigInt) -> *mut BigInt {
+    match value(amount).to_u64() {
+        Some(amount) if value(a).bits() + amount <= MAX_BITS => alloc(value(a) << amount),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+extern "C" fn shr(a: *const BigInt, amount: *const BigInt) -> *mut BigInt {
+    match value(amount).to_u64() {
+        Some(amount) => alloc(value(a) >> amount),
+        // Everything has been shifted out
+        None if value(a).is_negative() => alloc(-BigInt::one()),
+        None => alloc(BigInt::zero()),
+    }
+}
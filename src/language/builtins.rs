@@ -0,0 +1,200 @@
+use crate::language::CalcValue;
+use cranelift_jit::JITBuilder;
+use num_bigint::BigInt;
+use num_complex::Complex64;
+
+/// Named constants, replaced by their value when an expression is parsed.
+const CONSTANTS: &[(&str, f64)] = &[
+    ("pi", std::f64::consts::PI),
+    ("e", std::f64::consts::E),
+    ("tau", std::f64::consts::TAU),
+    ("inf", f64::INFINITY),
+    ("nan", f64::NAN),
+];
+
+pub fn constant(name: &str) -> Option<f64> {
+    CONSTANTS
+        .iter()
+        .find(|(constant, _)| *constant == name)
+        .map(|&(_, value)| value)
+}
+
+pub fn constant_names() -> impl Iterator<Item = &'static str> {
+    CONSTANTS.iter().map(|(name, _)| *name)
+}
+
+/// Functions provided by the calculator itself. A user-defined function with
+/// the same name takes precedence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    Sqrt,
+    Sin,
+    Cos,
+    Exp,
+    Ln,
+    Pow,
+    Abs,
+    Floor,
+    Ceil,
+    Round,
+    Min,
+    Max,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 12] = [
+        Builtin::Sqrt,
+        Builtin::Sin,
+        Builtin::Cos,
+        Builtin::Exp,
+        Builtin::Ln,
+        Builtin::Pow,
+        Builtin::Abs,
+        Builtin::Floor,
+        Builtin::Ceil,
+        Builtin::Round,
+        Builtin::Min,
+        Builtin::Max,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|builtin| builtin.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Sqrt => "sqrt",
+            Builtin::Sin => "sin",
+            Builtin::Cos => "cos",
+            Builtin::Exp => "exp",
+            Builtin::Ln => "ln",
+            Builtin::Pow => "pow",
+            Builtin::Abs => "abs",
+            Builtin::Floor => "floor",
+            Builtin::Ceil => "ceil",
+            Builtin::Round => "round",
+            Builtin::Min => "min",
+            Builtin::Max => "max",
+        }
+    }
+
+    pub fn params(self) -> &'static [&'static str] {
+        match self {
+            Builtin::Pow => &["x", "y"],
+            Builtin::Min | Builtin::Max => &["a", "b"],
+            _ => &["x"],
+        }
+    }
+
+    /// Result type for the given argument types. Transcendental functions
+    /// produce floats, or complex numbers for complex arguments (and with
+    /// `complex` set, `sqrt`, `ln` and `pow` always do, since they were
+    /// found to be taken of a negative number). The rest keep integers as
+    /// integers unless a float, rational or decimal argument is involved (or
+    /// a big integer one, which they keep).
+    pub fn return_type(self, arg_types: &[CalcValue], complex: bool) -> CalcValue {
+        let any_complex = arg_types
+            .iter()
+            .any(|ty| matches!(ty, CalcValue::Complex(_)));
+        match self {
+            Builtin::Sqrt | Builtin::Ln | Builtin::Pow if complex || any_complex => {
+                CalcValue::Complex(Complex64::default())
+            }
+            Builtin::Sin | Builtin::Cos | Builtin::Exp if any_complex => {
+                CalcValue::Complex(Complex64::default())
+            }
+            // The magnitude of a complex number
+            Builtin::Abs if any_complex => CalcValue::Float(0.0),
+            Builtin::Sqrt
+            | Builtin::Sin
+            | Builtin::Cos
+            | Builtin::Exp
+            | Builtin::Ln
+            | Builtin::Pow => CalcValue::Float(0.0),
+            Builtin::Abs
+            | Builtin::Floor
+            | Builtin::Ceil
+            | Builtin::Round
+            | Builtin::Min
+            | Builtin::Max => {
+                if arg_types.iter().any(|ty| {
+                    matches!(
+                        ty,
+                        CalcValue::Float(_) | CalcValue::Rational(_) | CalcValue::Decimal(_)
+                    )
+                }) {
+                    CalcValue::Float(0.0)
+                } else if arg_types
+                    .iter()
+                    .any(|ty| matches!(ty, CalcValue::BigInt(_)))
+                {
+                    CalcValue::BigInt(BigInt::ZERO)
+                } else {
+                    CalcValue::Integer(0)
+                }
+            }
+        }
+    }
+
+    /// Symbol registered on the `JITBuilder` for builtins without a native
+    /// Cranelift instruction.
+    pub fn symbol(self) -> Option<&'static str> {
+        match self {
+            Builtin::Sin => Some("calc_sin"),
+            Builtin::Cos => Some("calc_cos"),
+            Builtin::Exp => Some("calc_exp"),
+            Builtin::Ln => Some("calc_ln"),
+            Builtin::Pow => Some("calc_pow"),
+            _ => None,
+        }
+    }
+
+    /// Symbol of the `complex` helper for builtins defined on complex
+    /// numbers.
+    pub fn complex_symbol(self) -> Option<&'static str> {
+        match self {
+            Builtin::Sqrt => Some("calc_complex_sqrt"),
+            Builtin::Sin => Some("calc_complex_sin"),
+            Builtin::Cos => Some("calc_complex_cos"),
+            Builtin::Exp => Some("calc_complex_exp"),
+            Builtin::Ln => Some("calc_complex_ln"),
+            Builtin::Pow => Some("calc_complex_pow"),
+            Builtin::Abs => Some("calc_complex_abs"),
+            _ => None,
+        }
+    }
+}
+
+pub fn register_symbols(builder: &mut JITBuilder) {
+    builder.symbol("calc_sin", sin as *const u8);
+    builder.symbol("calc_cos", cos as *const u8);
+    builder.symbol("calc_exp", exp as *const u8);
+    builder.symbol("calc_ln", ln as *const u8);
+    builder.symbol("calc_pow", pow as *const u8);
+    // Not a builtin, but `%` on floats needs it
+    builder.symbol("calc_fmod", fmod as *const u8);
+}
+
+extern "C" fn sin(x: f64) -> f64 {
+    x.sin()
+}
+
+extern "C" fn cos(x: f64) -> f64 {
+    x.cos()
+}
+
+extern "C" fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+extern "C" fn ln(x: f64) -> f64 {
+    x.ln()
+}
+
+extern "C" fn pow(x: f64, y: f64) -> f64 {
+    x.powf(y)
+}
+
+extern "C" fn fmod(x: f64, y: f64) -> f64 {
+    x % y
+}
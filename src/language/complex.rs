@@ -0,0 +1,55 @@
+use cranelift_jit::JITBuilder;
+use num_complex::Complex64;
+use num_traits::Zero;
+
+// Compiled code passes complex numbers as `(re, im)` pairs of `f64`s, and
+// gets them back the same way: `Complex64` is `#[repr(C)]`, so it comes back
+// in the same two registers as a pair of float return values.
+
+pub fn register_symbols(builder: &mut JITBuilder) {
+    builder.symbol("calc_complex_sqrt", sqrt as *const u8);
+    builder.symbol("calc_complex_exp", exp as *const u8);
+    builder.symbol("calc_complex_ln", ln as *const u8);
+    builder.symbol("calc_complex_sin", sin as *const u8);
+    builder.symbol("calc_complex_cos", cos as *const u8);
+    builder.symbol("calc_complex_pow", pow as *const u8);
+    builder.symbol("calc_complex_abs", abs as *const u8);
+}
+
+extern "C" fn sqrt(re: f64, im: f64) -> Complex64 {
+    Complex64::new(re, im).sqrt()
+}
+
+extern "C" fn exp(re: f64, im: f64) -> Complex64 {
+    Complex64::new(re, im).exp()
+}
+
+extern "C" fn ln(re: f64, im: f64) -> Complex64 {
+    Complex64::new(re, im).ln()
+}
+
+extern "C" fn sin(re: f64, im: f64) -> Complex64 {
+    Complex64::new(re, im).sin()
+}
+
+extern "C" fn cos(re: f64, im: f64) -> Complex64 {
+    Complex64::new(re, im).cos()
+}
+
+/// Principal value of `base ^ exponent`. Whole exponents multiply out, so
+/// `(1 + 2i) ^ 2` is exactly `-3+4i` rather than going through `ln`, and
+/// zero to any other power is zero where `ln(0)` would give NaN.
+extern "C" fn pow(base_re: f64, base_im: f64, exponent_re: f64, exponent_im: f64) -> Complex64 {
+    let base = Complex64::new(base_re, base_im);
+    let exponent = Complex64::new(exponent_re, exponent_im);
+    let whole = exponent_im == 0.0 && exponent_re.fract() == 0.0;
+    match i32::try_from(exponent_re as i64) {
+        Ok(n) if whole => base.powi(n),
+        _ if base.is_zero() => Complex64::zero(),
+        _ => base.powc(exponent),
+    }
+}
+
+extern "C" fn abs(re: f64, im: f64) -> f64 {
+    Complex64::new(re, im).norm()
+}
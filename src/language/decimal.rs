@@ -0,0 +1,150 @@
+use crate::language::heap::alloc;
+use cranelift_jit::JITBuilder;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::Signed;
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+
+/// Places decimals have unless configured otherwise, as for amounts of money.
+pub const DEFAULT_PLACES: u32 = 2;
+
+/// With at most this many places, any `i64` times `10^places` still fits in
+/// an `i128`, so every integer is also a decimal.
+pub const MAX_PLACES: u32 = 18;
+
+/// Fixed-point number: `scaled / 10^places`, e.g. `0.30` is `30` with two
+/// places. Every decimal in a session has the same number of places, so
+/// compiled code only works with `scaled`, as an `i128`.
+///
+/// `i128::MIN` is left out of the range, so that the helpers below can
+/// return it for results that don't fit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Decimal {
+    pub scaled: i128,
+    pub places: u32,
+}
+
+/// Exactly `places` digits after the point, e.g. `0.30` rather than `0.3`.
+impl Display for Decimal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let scale = 10_u128.pow(self.places);
+        let magnitude = self.scaled.unsigned_abs();
+        let sign = if self.scaled < 0 { "-" } else { "" };
+        match self.places {
+            0 => write!(f, "{}{}", sign, magnitude),
+            places => write!(
+                f,
+                "{}{}.{:0width$}",
+                sign,
+                magnitude / scale,
+                magnitude % scale,
+                width = places as usize
+            ),
+        }
+    }
+}
+
+/// `mantissa / 10^from` with `to` places instead, rounding half to even when
+/// places are dropped. Both are at most `MAX_PLACES`.
+pub fn rescale(mantissa: i128, from: u32, to: u32) -> Option<i128> {
+    let scaled = match to.cmp(&from) {
+        Ordering::Less => round_div(&mantissa, &10_i128.pow(from - to)),
+        _ => mantissa.checked_mul(10_i128.pow(to - from))?,
+    };
+    (scaled != i128::MIN).then_some(scaled)
+}
+
+/// `n / d` rounded to the nearest integer, with ties going to the even one
+/// (as `round` does), so that rounding errors don't pile up in one
+/// direction. For `i128`s, neither may be `i128::MIN`.
+fn round_div<T: Integer + Signed + Clone>(n: &T, d: &T) -> T {
+    let (quotient, remainder) = n.div_rem(d);
+    let remainder = remainder.abs();
+    let rest = d.abs() - remainder.clone();
+    let away = match remainder.cmp(&rest) {
+        Ordering::Less => false,
+        Ordering::Equal => quotient.is_odd(),
+        Ordering::Greater => true,
+    };
+    match (away, n.is_negative() != d.is_negative()) {
+        (false, _) => quotient,
+        (true, true) => quotient - T::one(),
+        (true, false) => quotient + T::one(),
+    }
+}
+
+/// The `i128` a wider result fits in, or `i128::MIN` if it doesn't.
+fn narrow(n: BigInt) -> i128 {
+    i128::try_from(&n).unwrap_or(i128::MIN)
+}
+
+// Compiled code passes decimals as their low and high halves, and gets them
+// back the same way: `Parts` is `#[repr(C)]`, so it comes back in the same
+// two registers as a pair of integer return values.
+
+#[repr(C)]
+pub struct Parts {
+    low: u64,
+    high: i64,
+}
+
+impl Parts {
+    pub fn value(&self) -> i128 {
+        join(self.low, self.high)
+    }
+}
+
+impl From<i128> for Parts {
+    fn from(n: i128) -> Self {
+        Self {
+            low: n as u64,
+            high: (n >> 64) as i64,
+        }
+    }
+}
+
+pub fn join(low: u64, high: i64) -> i128 {
+    (i128::from(high) << 64) | i128::from(low)
+}
+
+pub fn register_symbols(builder: &mut JITBuilder) {
+    builder.symbol("calc_dec_to_f64", to_f64 as *const u8);
+    builder.symbol("calc_dec_trunc", trunc as *const u8);
+    builder.symbol("calc_dec_mul", mul as *const u8);
+    builder.symbol("calc_dec_div", div as *const u8);
+}
+
+extern "C" fn to_f64(low: u64, high: i64, places: i64) -> f64 {
+    join(low, high) as f64 / 10_f64.powi(places as i32)
+}
+
+/// The integer part of a decimal, as a big integer, since with few places
+/// it can be too large for an `i64`.
+extern "C" fn trunc(low: u64, high: i64, places: i64) -> *mut BigInt {
+    alloc(BigInt::from(join(low, high) / 10_i128.pow(places as u32)))
+}
+
+/// `a * b`, rounded back to `places`. The product of the scaled values only
+/// goes through a big integer when it doesn't fit in an `i128`.
+extern "C" fn mul(a_low: u64, a_high: i64, b_low: u64, b_high: i64, places: i64) -> Parts {
+    let (a, b) = (join(a_low, a_high), join(b_low, b_high));
+    let scale = 10_i128.pow(places as u32);
+    let product = match a.checked_mul(b) {
+        Some(n) if n != i128::MIN => round_div(&n, &scale),
+        _ => narrow(round_div(&(BigInt::from(a) * b), &BigInt::from(scale))),
+    };
+    Parts::from(product)
+}
+
+/// `a / b`, rounded to `places`. Compiled code checks for a zero divisor
+/// first.
+extern "C" fn div(a_low: u64, a_high: i64, b_low: u64, b_high: i64, places: i64) -> Parts {
+    let (a, b) = (join(a_low, a_high), join(b_low, b_high));
+    let scale = 10_i128.pow(places as u32);
+    let quotient = match a.checked_mul(scale) {
+        Some(n) if n != i128::MIN && b != i128::MIN => round_div(&n, &b),
+        _ => narrow(round_div(&(BigInt::from(a) * scale), &BigInt::from(b))),
+    };
+    Parts::from(quotient)
+}
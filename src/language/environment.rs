@@ -0,0 +1,544 @@
+use crate::language::decimal::{self, Decimal};
+use crate::language::heap::Heap;
+use crate::language::sized::{IntType, SizedInt};
+use crate::language::units::Unit;
+use crate::language::{bignum, rational, CalcValue, Expr, OverflowMode};
+use cranelift_jit::JITModule;
+use cranelift_module::{DataDescription, DataId, FuncId, Linkage, Module, ModuleError};
+use miette::SourceSpan;
+use num_bigint::BigInt;
+use num_complex::Complex64;
+use num_rational::BigRational;
+use std::collections::HashMap;
+use std::mem::Discriminant;
+
+/// How many calls of user-defined functions can be in progress at once,
+/// well within the stack of a test thread.
+pub const MAX_CALL_DEPTH: i64 = 10_000;
+
+/// A named value living in a data object of the JIT module. Compiled code
+/// loads from (and stores to) the data object, so the value can change
+/// without recompiling anything that references it.
+#[derive(Clone)]
+pub struct Variable {
+    pub data_id: DataId,
+    pub ty: CalcValue,
+}
+
+impl Variable {
+    pub fn value(&self, module: &JITModule) -> CalcValue {
+        let (ptr, _) = module.get_finalized_data(self.data_id);
+        unsafe {
+            match self.ty {
+                CalcValue::Integer(_) => CalcValue::Integer(*(ptr as *const i64)),
+                CalcValue::Float(_) => CalcValue::Float(*(ptr as *const f64)),
+                CalcValue::Bool(_) => CalcValue::Bool(*ptr != 0),
+                CalcValue::BigInt(_) => {
+                    CalcValue::BigInt(bignum::read(*(ptr as *const *const BigInt)))
+                }
+                CalcValue::Rational(_) => {
+                    CalcValue::Rational(rational::read(*(ptr as *const *const BigRational)))
+                }
+                CalcValue::Complex(_) => CalcValue::Complex(*(ptr as *const Complex64)),
+                CalcValue::Decimal(tag) => CalcValue::Decimal(Decimal {
+                    scaled: *(ptr as *const i128),
+                    places: tag.places,
+                }),
+                // Only the low bits of the type are stored
+                CalcValue::Sized(tag) => {
+                    CalcValue::Sized(SizedInt::new(*(ptr as *const u128), tag.ty))
+                }
+                // Stored in SI base units
+                CalcValue::Quantity(_, ref unit) => {
+                    CalcValue::Quantity(*(ptr as *const f64) / unit.scale, unit.clone())
+                }
+                CalcValue::Function(_) => self.ty.clone(),
+            }
+        }
+    }
+}
+
+/// A user-defined function, e.g. `f(x, y) = x * x + y`.
+///
+/// The body is compiled lazily into its own function in the JIT module, once
+/// per combination of argument types it gets called with.
+#[derive(Clone)]
+pub struct Function {
+    pub params: Vec<String>,
+    pub body: Expr,
+    /// Input the function was defined in, which the spans in `body` refer to
+    pub source: String,
+    specializations: HashMap<SignatureKey, Specialization>,
+    /// Sites of the traps in the specializations, which go with them
+    trap_sites: TrapSites,
+}
+
+/// One version of a `Function` for a given list of argument types.
+#[derive(Clone)]
+pub struct Specialization {
+    /// `None` while the return type is still being inferred
+    pub func_id: Option<FuncId>,
+    pub return_type: CalcValue,
+}
+
+/// Something compiled code checks for at runtime instead of letting the
+/// hardware trap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trap {
+    DivisionByZero,
+    Overflow,
+    /// A big integer result beyond what `bignum` is willing to allocate
+    TooLarge,
+    /// A square root, logarithm or power of a negative float, which only
+    /// has a complex result
+    NotReal,
+    /// A decimal result beyond what an `i128` holds at the configured places
+    DecimalOverflow,
+    /// `nan` or an infinity cast to an integer
+    NotFinite,
+    /// An integer power whose exponent turned out to be negative, which
+    /// only has a fractional result
+    NegativeExponent,
+    /// A shift by a negative amount, or (for an integer of that many bits)
+    /// by at least its width
+    ShiftOutOfRange(Option<u32>),
+    /// A call with `MAX_CALL_DEPTH` calls already in progress, which is
+    /// most likely recursion that never stops
+    RecursionLimit,
+    /// A sized integer result (or operand) out of its type's range
+    SizedOverflow(IntType),
+}
+
+/// Wider types an evaluation is retried with, after a trap showed that the
+/// regular ones can't hold its result.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Promotion {
+    /// Integers are compiled as big integers, after an overflow
+    pub wide: bool,
+    /// `sqrt`, `ln`, `pow` and `^` give complex numbers even for real
+    /// arguments, after one of them hit `Trap::NotReal`
+    pub complex: bool,
+}
+
+/// Where a `Trap` was compiled in, for reporting it once it fires. The span
+/// is in the input the code was compiled from, which is only looked up then.
+#[derive(Debug, Clone)]
+pub struct TrapSite {
+    pub trap: Trap,
+    pub span: SourceSpan,
+}
+
+/// Trap sites of some compiled code, by the id compiled code stores in
+/// `fault` when one of them fires.
+pub type TrapSites = HashMap<i64, TrapSite>;
+
+/// The variables (with the bytes of their values) and functions as of the
+/// last `Environment::commit`.
+#[derive(Default)]
+struct Checkpoint {
+    variables: HashMap<String, (Variable, [u8; 16])>,
+    functions: HashMap<String, Function>,
+    generation: u64,
+}
+
+/// Session state shared by every evaluation of a `Calculator`.
+///
+/// `generation` is bumped whenever previously compiled code could be
+/// invalidated (e.g. a variable changing type or a function being
+/// redefined), and is folded into the function cache key.
+///
+/// `promotion` is only set to retry an evaluation that hit a trap, and is
+/// part of the cache key too, as is `overflow`. `exact` makes dividing
+/// integers give rationals instead of floats, and `places` is how many
+/// digits decimals keep after the point.
+pub struct Environment {
+    variables: HashMap<String, Variable>,
+    functions: HashMap<String, Function>,
+    generation: u64,
+    promotion: Promotion,
+    overflow: OverflowMode,
+    exact: bool,
+    places: u32,
+    heap: Heap,
+    /// Data object compiled code writes a `TrapSite` id into before bailing
+    /// out; zero means no trap fired.
+    fault: DataId,
+    /// Data object holding how many calls of user-defined functions are in
+    /// progress. Each call site puts back the count it found once the call
+    /// returns, trap or not.
+    depth: DataId,
+    /// Sites registered since the code being compiled was started, until
+    /// it takes them
+    trap_sites: Vec<(i64, TrapSite)>,
+    last_trap_site: i64,
+    checkpoint: Checkpoint,
+}
+
+impl Environment {
+    pub fn new(module: &mut JITModule) -> Result<Self, Box<ModuleError>> {
+        let mut description = DataDescription::new();
+        description.define_zeroinit(8);
+        description.set_align(8);
+        let fault = module.declare_data("fault", Linkage::Local, true, false)?;
+        module.define_data(fault, &description)?;
+        let depth = module.declare_data("depth", Linkage::Local, true, false)?;
+        module.define_data(depth, &description)?;
+
+        Ok(Self {
+            variables: HashMap::new(),
+            functions: HashMap::new(),
+            generation: 0,
+            promotion: Promotion::default(),
+            overflow: OverflowMode::default(),
+            exact: false,
+            places: decimal::DEFAULT_PLACES,
+            heap: Heap::default(),
+            fault,
+            depth,
+            trap_sites: Vec::new(),
+            last_trap_site: 0,
+            checkpoint: Checkpoint::default(),
+        })
+    }
+
+    pub fn fault(&self) -> DataId {
+        self.fault
+    }
+
+    pub fn depth(&self) -> DataId {
+        self.depth
+    }
+
+    /// Register a runtime check, returning the (non-zero) value compiled code
+    /// stores in `fault` when it fails.
+    pub fn add_trap_site(&mut self, site: TrapSite) -> i64 {
+        self.last_trap_site += 1;
+        self.trap_sites.push((self.last_trap_site, site));
+        self.last_trap_site
+    }
+
+    /// How many sites have been registered but not taken yet, for code
+    /// compiled in the middle of other code to take only its own.
+    pub fn pending_trap_sites(&self) -> usize {
+        self.trap_sites.len()
+    }
+
+    /// Take the sites registered since `pending_trap_sites` returned `from`,
+    /// which then live as long as the code they were compiled into.
+    pub fn take_trap_sites(&mut self, from: usize) -> TrapSites {
+        self.trap_sites.drain(from..).collect()
+    }
+
+    /// Keep trap sites of a specialization of `name` for as long as it is.
+    pub fn add_function_trap_sites(&mut self, name: &str, sites: TrapSites) {
+        if let Some(function) = self.functions.get_mut(name) {
+            function.trap_sites.extend(sites);
+        }
+    }
+
+    /// The site with id `fault` in the body of a function, along with the
+    /// input the function was defined in.
+    pub fn function_trap_site(&self, fault: i64) -> Option<(&TrapSite, &str)> {
+        self.functions.values().find_map(|function| {
+            let site = function.trap_sites.get(&fault)?;
+            Some((site, function.source.as_str()))
+        })
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Variable> {
+        self.variables.get(name)
+    }
+
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.get(name)
+    }
+
+    pub fn function_names(&self) -> impl Iterator<Item = &str> {
+        self.functions.keys().map(String::as_str)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn promotion(&self) -> Promotion {
+        self.promotion
+    }
+
+    pub fn set_promotion(&mut self, promotion: Promotion) {
+        self.promotion = promotion;
+    }
+
+    pub fn overflow(&self) -> OverflowMode {
+        self.overflow
+    }
+
+    pub fn set_overflow(&mut self, overflow: OverflowMode) {
+        self.overflow = overflow;
+    }
+
+    pub fn exact(&self) -> bool {
+        self.exact
+    }
+
+    /// Switch exact mode, which changes the types of already compiled code.
+    pub fn set_exact(&mut self, exact: bool) {
+        if self.exact != exact {
+            self.exact = exact;
+            self.invalidate();
+        }
+    }
+
+    pub fn places(&self) -> u32 {
+        self.places
+    }
+
+    /// Change how many places decimals have, which changes already compiled
+    /// code. Decimal variables are rounded (half to even) to the new number
+    /// of places, and forgotten if they no longer fit.
+    pub fn set_places(&mut self, module: &JITModule, places: u32) {
+        if self.places == places {
+            return;
+        }
+        self.places = places;
+        self.invalidate();
+        self.variables.retain(|_, variable| {
+            let CalcValue::Decimal(tag) = &mut variable.ty else {
+                return true;
+            };
+            let (ptr, _) = module.get_finalized_data(variable.data_id);
+            let ptr = ptr as *mut i128;
+            match decimal::rescale(unsafe { *ptr }, tag.places, places) {
+                Some(scaled) => {
+                    unsafe { *ptr = scaled };
+                    tag.places = places;
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    /// Keep a literal alive for as long as compiled code may refer to it.
+    pub fn constant<T>(&mut self, value: T) -> *const T {
+        self.heap.constant(value)
+    }
+
+    /// Free the values the last run allocated, other than those now held
+    /// by variables (or held by them as of the last commit).
+    pub fn collect_garbage(&mut self, module: &JITModule) {
+        let by_pointer = |variable: &Variable| {
+            matches!(variable.ty, CalcValue::BigInt(_) | CalcValue::Rational(_))
+        };
+        let live: Vec<*mut u8> = self
+            .variables
+            .values()
+            .filter(|variable| by_pointer(variable))
+            .map(|variable| {
+                let (ptr, _) = module.get_finalized_data(variable.data_id);
+                unsafe { *(ptr as *const *mut u8) }
+            })
+            .chain(
+                self.checkpoint
+                    .variables
+                    .values()
+                    .filter(|(variable, _)| by_pointer(variable))
+                    .map(|(_, bytes)| unsafe { bytes.as_ptr().cast::<*mut u8>().read_unaligned() }),
+            )
+            .collect();
+        self.heap.collect(&live);
+    }
+
+    /// Keep the assignments and definitions made so far, which `rollback`
+    /// goes back to.
+    pub fn commit(&mut self, module: &JITModule) {
+        let variables = self
+            .variables
+            .iter()
+            .map(|(name, variable)| {
+                let (ptr, _) = module.get_finalized_data(variable.data_id);
+                let bytes = unsafe { *(ptr as *const [u8; 16]) };
+                (name.clone(), (variable.clone(), bytes))
+            })
+            .collect();
+        self.checkpoint = Checkpoint {
+            variables,
+            functions: self.functions.clone(),
+            generation: self.generation,
+        };
+    }
+
+    /// Undo the assignments and definitions made since the last `commit`.
+    /// Values are written back in place, so compiled code only has to go
+    /// when a variable or function came, went or changed type since.
+    pub fn rollback(&mut self, module: &JITModule) {
+        for (variable, bytes) in self.checkpoint.variables.values() {
+            let (ptr, _) = module.get_finalized_data(variable.data_id);
+            unsafe { *(ptr as *mut [u8; 16]) = *bytes };
+        }
+        let same_variables = self.variables.len() == self.checkpoint.variables.len()
+            && self.variables.iter().all(|(name, variable)| {
+                self.checkpoint
+                    .variables
+                    .get(name)
+                    .is_some_and(|(committed, _)| committed.data_id == variable.data_id)
+            });
+        if same_variables && self.generation == self.checkpoint.generation {
+            return;
+        }
+        self.variables = self
+            .checkpoint
+            .variables
+            .iter()
+            .map(|(name, (variable, _))| (name.clone(), variable.clone()))
+            .collect();
+        self.functions = self.checkpoint.functions.clone();
+        self.invalidate();
+    }
+
+    /// Make sure `name` has a data object able to hold a value of type `ty`.
+    ///
+    /// A variable changing type (or unit) gets a fresh (zeroed) data object,
+    /// so that the old value is never read back as the new type, e.g. an
+    /// integer as a big integer pointer if the assignment doesn't get to run.
+    /// A new variable named like a unit invalidates code that read the unit.
+    pub fn bind_variable(
+        &mut self,
+        module: &mut JITModule,
+        name: &str,
+        ty: &CalcValue,
+    ) -> Result<DataId, Box<ModuleError>> {
+        match self.variables.get(name) {
+            Some(variable) if variable.ty == type_tag(ty) => return Ok(variable.data_id),
+            Some(_) => self.invalidate(),
+            None if Unit::named(name).is_some() => self.invalidate(),
+            None => {}
+        }
+
+        let data_id = module.declare_data(
+            &format!("var_{}_{}", name, self.generation),
+            Linkage::Local,
+            true,
+            false,
+        )?;
+        // Room for any type, complex numbers and decimals being the largest
+        let mut description = DataDescription::new();
+        description.define_zeroinit(16);
+        description.set_align(16);
+        module.define_data(data_id, &description)?;
+
+        self.variables.insert(
+            name.to_string(),
+            Variable {
+                data_id,
+                ty: type_tag(ty),
+            },
+        );
+        Ok(data_id)
+    }
+
+    /// (Re)define a function, returning the definition it replaced.
+    pub fn define_function(
+        &mut self,
+        name: &str,
+        params: Vec<String>,
+        body: Expr,
+        source: String,
+    ) -> Option<Function> {
+        self.invalidate();
+        self.functions.insert(
+            name.to_string(),
+            Function {
+                params,
+                body,
+                source,
+                specializations: HashMap::new(),
+                trap_sites: HashMap::new(),
+            },
+        )
+    }
+
+    /// Put back the definition replaced by a rejected `define_function`.
+    pub fn restore_function(&mut self, name: &str, previous: Option<Function>) {
+        self.invalidate();
+        match previous {
+            Some(function) => self.functions.insert(name.to_string(), function),
+            None => self.functions.remove(name),
+        };
+    }
+
+    pub fn specialization(&self, name: &str, arg_types: &[CalcValue]) -> Option<Specialization> {
+        self.functions
+            .get(name)?
+            .specializations
+            .get(&signature_key(self.promotion, self.overflow, arg_types))
+            .cloned()
+    }
+
+    /// Record (or with `None`, forget) the version of `name` for `arg_types`.
+    pub fn set_specialization(
+        &mut self,
+        name: &str,
+        arg_types: &[CalcValue],
+        specialization: Option<Specialization>,
+    ) {
+        if let Some(function) = self.functions.get_mut(name) {
+            let key = signature_key(self.promotion, self.overflow, arg_types);
+            match specialization {
+                Some(specialization) => function.specializations.insert(key, specialization),
+                None => function.specializations.remove(&key),
+            };
+        }
+    }
+
+    /// Compiled functions bake in the types of the variables and functions
+    /// they reference, so they are dropped along with the cached expressions.
+    fn invalidate(&mut self) {
+        self.generation += 1;
+        for function in self.functions.values_mut() {
+            function.specializations.clear();
+            function.trap_sites.clear();
+        }
+    }
+}
+
+/// An argument type as far as specializations go: quantities in different
+/// units give results in different units, and sized integers of different
+/// types compile to different instructions.
+type TypeKey = (Discriminant<CalcValue>, Option<Unit>, Option<IntType>);
+
+type SignatureKey = (Promotion, OverflowMode, Vec<TypeKey>);
+
+/// Versions compiled under a `Promotion` use other types throughout, so
+/// they are kept apart from the regular ones, as are those compiled for
+/// another overflow mode.
+fn signature_key(
+    promotion: Promotion,
+    overflow: OverflowMode,
+    arg_types: &[CalcValue],
+) -> SignatureKey {
+    let key = |ty: &CalcValue| match ty {
+        CalcValue::Quantity(_, unit) => (std::mem::discriminant(ty), Some(unit.clone()), None),
+        CalcValue::Sized(n) => (std::mem::discriminant(ty), None, Some(n.ty)),
+        _ => (std::mem::discriminant(ty), None, None),
+    };
+    (promotion, overflow, arg_types.iter().map(key).collect())
+}
+
+fn type_tag(ty: &CalcValue) -> CalcValue {
+    match ty {
+        CalcValue::Integer(_) => CalcValue::Integer(0),
+        CalcValue::Float(_) => CalcValue::Float(0.0),
+        CalcValue::Bool(_) => CalcValue::Bool(false),
+        CalcValue::BigInt(_) => CalcValue::BigInt(BigInt::ZERO),
+        CalcValue::Rational(_) => CalcValue::Rational(BigRational::default()),
+        CalcValue::Complex(_) => CalcValue::Complex(Complex64::default()),
+        CalcValue::Decimal(tag) => CalcValue::Decimal(Decimal {
+            scaled: 0,
+            places: tag.places,
+        }),
+        CalcValue::Sized(n) => CalcValue::Sized(SizedInt::zero(n.ty)),
+        CalcValue::Quantity(_, unit) => CalcValue::Quantity(0.0, unit.clone()),
+        CalcValue::Function(_) => ty.clone(),
+    }
+}
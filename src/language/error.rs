@@ -1,3 +1,4 @@
+use crate::language::sized::IntType;
 use miette::{Diagnostic, NamedSource, SourceSpan};
 use thiserror::Error;
 
@@ -22,7 +23,6 @@ pub enum CalcErrorKind {
     #[error("Invalid syntax")]
     ParseError(String),
 
-    #[allow(dead_code)]
     #[error("Type mismatch: {0}")]
     TypeMismatch(String),
 
@@ -38,6 +38,49 @@ pub enum CalcErrorKind {
     #[error("Invalid number: {0}")]
     NumberError(String),
 
+    #[error("Undefined variable: {0}")]
+    UndefinedVariable(String),
+
+    #[error("Division by zero")]
+    DivisionByZero,
+
+    #[error("Integer overflow")]
+    Overflow,
+
+    #[error("Decimal overflow")]
+    DecimalOverflow,
+
+    #[error("`{0}` overflow")]
+    SizedOverflow(IntType),
+
+    #[error("Not a real number")]
+    NotReal,
+
+    #[error("Not a finite number")]
+    NotFinite,
+
+    #[error("Negative exponent")]
+    NegativeExponent,
+
+    #[error("Shift amount out of range")]
+    ShiftOutOfRange,
+
+    #[error("Recursion too deep")]
+    RecursionLimit,
+
+    #[error("Undefined function: {0}")]
+    UndefinedFunction(String),
+
+    #[error("Unknown unit: {0}")]
+    UnknownUnit(String),
+
+    #[error("Wrong number of arguments: `{name}` expects {expected}, got {found}")]
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+
     #[allow(dead_code)]
     #[error("System error: {0}")]
     SystemError(String),
@@ -0,0 +1,78 @@
+use std::cell::RefCell;
+
+/// A value handed to compiled code as a pointer, along with how to free it.
+struct Allocation {
+    ptr: *mut u8,
+    free: unsafe fn(*mut u8),
+}
+
+impl Allocation {
+    fn new<T>(value: T) -> Self {
+        unsafe fn free<T>(ptr: *mut u8) {
+            drop(unsafe { Box::from_raw(ptr as *mut T) });
+        }
+        Self {
+            ptr: Box::into_raw(Box::new(value)) as *mut u8,
+            free: free::<T>,
+        }
+    }
+
+    fn free(self) {
+        unsafe { (self.free)(self.ptr) }
+    }
+}
+
+thread_local! {
+    /// Values allocated by compiled code since the last `Heap::collect`.
+    static ALLOCATIONS: RefCell<Vec<Allocation>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Owner of the values compiled code works with by pointer (big integers
+/// and rationals). Every helper returns a fresh allocation, which lives
+/// until the next collection unless a variable still refers to it.
+#[derive(Default)]
+pub struct Heap {
+    /// Values held by variables as of the last collection
+    retained: Vec<Allocation>,
+    /// Literals baked into compiled code, which may outlive any single run
+    constants: Vec<Allocation>,
+}
+
+impl Heap {
+    pub fn constant<T>(&mut self, value: T) -> *const T {
+        let allocation = Allocation::new(value);
+        let ptr = allocation.ptr as *const T;
+        self.constants.push(allocation);
+        ptr
+    }
+
+    /// Free everything allocated since the last collection, and everything
+    /// retained then, except for `live`.
+    pub fn collect(&mut self, live: &[*mut u8]) {
+        let mut garbage = ALLOCATIONS.with(|allocations| allocations.take());
+        garbage.append(&mut self.retained);
+        for allocation in garbage {
+            if live.contains(&allocation.ptr) {
+                self.retained.push(allocation);
+            } else {
+                allocation.free();
+            }
+        }
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for allocation in self.retained.drain(..).chain(self.constants.drain(..)) {
+            allocation.free();
+        }
+    }
+}
+
+/// Allocate a value for compiled code, to be freed by the next collection.
+pub fn alloc<T>(value: T) -> *mut T {
+    let allocation = Allocation::new(value);
+    let ptr = allocation.ptr as *mut T;
+    ALLOCATIONS.with(|allocations| allocations.borrow_mut().push(allocation));
+    ptr
+}
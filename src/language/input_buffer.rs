@@ -13,6 +13,8 @@ impl InputBuffer {
         }
     }
 
+    /// Everything from `edit_pos` on is copied over, so that text after an
+    /// edit in the middle of the input (e.g. on an earlier line) is kept.
     pub(crate) fn update(
         &mut self,
         new_input: &str,
@@ -25,7 +27,7 @@ impl InputBuffer {
             self.bytes.reserve(new_len - self.bytes.len());
         }
         self.bytes.truncate(edit_pos);
-        let slice = new_input[edit_pos..new_end].as_bytes();
+        let slice = new_input[edit_pos..].as_bytes();
         self.bytes.extend_from_slice(slice);
         self.edit_start = edit_pos;
     }
@@ -57,6 +59,14 @@ mod tests {
         assert_eq!(buffer.as_str(), "1245");
     }
 
+    #[test]
+    fn test_input_buffer_update_middle() {
+        let mut buffer = InputBuffer::new();
+        buffer.update("a = 1\nb = 2\na + b", 0, 0, 17);
+        buffer.update("a = 1\nb = 20\na + b", 10, 11, 12);
+        assert_eq!(buffer.as_str(), "a = 1\nb = 20\na + b");
+    }
+
     #[test]
     fn test_input_buffer_update_spaces() {
         let mut buffer = InputBuffer::new();
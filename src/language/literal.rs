@@ -0,0 +1,166 @@
+use crate::language::sized::{IntType, SizedInt};
+use num_bigint::BigInt;
+
+/// What is wrong with a numeric literal, and where inside it.
+#[derive(Debug)]
+pub struct LiteralError {
+    /// Byte offset into the literal's text
+    pub offset: usize,
+    pub len: usize,
+    pub message: String,
+    pub help: String,
+}
+
+/// Parse an integer literal such as `42`, `1_000_000`, `0xFF`, `0o755` or
+/// `0b1010`, negating it when it followed a unary minus (so that
+/// `-9223372036854775808` still fits in 64 bits). Literals of any size are
+/// accepted.
+pub fn parse_integer(text: &str, negative: bool) -> Result<BigInt, LiteralError> {
+    let (radix, prefix) = match text.get(..2) {
+        Some("0x" | "0X") => (16, 2),
+        Some("0o" | "0O") => (8, 2),
+        Some("0b" | "0B") => (2, 2),
+        _ => (10, 0),
+    };
+    let digits = &text[prefix..];
+    if digits.chars().all(|c| c == '_') {
+        return Err(LiteralError {
+            offset: 0,
+            len: text.len(),
+            message: format!("`{}` has no digits", text),
+            help: format!("Write digits after the prefix, e.g. `{}1`", &text[..prefix]),
+        });
+    }
+
+    let mut magnitude = Vec::with_capacity(digits.len());
+    for (i, c) in digits.char_indices() {
+        if c == '_' {
+            check_separator(text, prefix + i, |c| c.is_digit(radix))?;
+            continue;
+        }
+        let Some(digit) = c.to_digit(radix) else {
+            return Err(LiteralError {
+                offset: prefix + i,
+                len: c.len_utf8(),
+                message: format!("`{}` is not a valid {} digit", c, radix_name(radix)),
+                help: radix_help(radix).into(),
+            });
+        };
+        magnitude.push(digit as u8);
+    }
+
+    let value = BigInt::from_radix_be(num_bigint::Sign::Plus, &magnitude, radix)
+        .expect("digits were checked above");
+    Ok(if negative { -value } else { value })
+}
+
+/// Parse an integer literal with a type suffix, such as `255u8` or
+/// `0xFF_u8`, or return `None` if it has none. The value has to fit in the
+/// type rather than wrapping.
+pub fn parse_sized(text: &str, negative: bool) -> Option<Result<SizedInt, LiteralError>> {
+    let start = text.rfind(['i', 'u'])?;
+    let ty = IntType::from_name(&text[start..])?;
+    let digits = text[..start].strip_suffix('_').unwrap_or(&text[..start]);
+    Some(parse_integer(digits, negative).and_then(|n| {
+        if !ty.contains(&n) {
+            return Err(LiteralError {
+                offset: 0,
+                len: text.len(),
+                message: format!("`{}` is out of range for `{}`", n, ty),
+                help: ty.describe(),
+            });
+        }
+        let raw = (n & BigInt::from(u128::MAX)).try_into().unwrap_or_default();
+        Ok(SizedInt::new(raw, ty))
+    }))
+}
+
+/// Parse a float literal such as `2.5`, `.5`, `1e-9` or `6.022_140e23`.
+pub fn parse_float(text: &str, negative: bool) -> Result<f64, LiteralError> {
+    for (i, c) in text.char_indices() {
+        if c == '_' {
+            check_separator(text, i, |c| c.is_ascii_digit())?;
+        }
+    }
+
+    let value: f64 = text.replace('_', "").parse().map_err(|_| LiteralError {
+        offset: 0,
+        len: text.len(),
+        message: format!("`{}` is not a valid float", text),
+        help: "Floats look like `2.5`, `.5` or `1e-9`".into(),
+    })?;
+    if value.is_infinite() {
+        return Err(LiteralError {
+            offset: 0,
+            len: text.len(),
+            message: format!("`{}` is too large for a float", text),
+            help: "The largest float is about `1.8e308`".into(),
+        });
+    }
+    Ok(if negative { -value } else { value })
+}
+
+/// Parse a decimal literal such as `0.1d` or `19.99d` into its digits (as an
+/// integer) and the number of them after the point, e.g. `(1999, 2)`.
+pub fn parse_decimal(text: &str, negative: bool) -> Result<(i128, u32), LiteralError> {
+    let digits = &text[..text.len() - 1];
+    for (i, c) in digits.char_indices() {
+        if c == '_' {
+            check_separator(digits, i, |c| c.is_ascii_digit())?;
+        }
+    }
+
+    let places = digits
+        .split_once('.')
+        .map_or(0, |(_, fraction)| fraction.replace('_', "").len());
+    let too_long = || LiteralError {
+        offset: 0,
+        len: text.len(),
+        message: format!("`{}` has too many digits for a decimal", text),
+        help: "Decimals hold up to 38 digits".into(),
+    };
+    let mantissa: i128 = digits
+        .replace(['_', '.'], "")
+        .parse()
+        .map_err(|_| too_long())?;
+    let places = u32::try_from(places).map_err(|_| too_long())?;
+    Ok((if negative { -mantissa } else { mantissa }, places))
+}
+
+/// Digit separators have to sit between two digits: `1_000` is fine, while
+/// `1__000`, `1_`, `0x_FF` and `1_.5` are not.
+fn check_separator(
+    text: &str,
+    offset: usize,
+    is_digit: impl Fn(char) -> bool,
+) -> Result<(), LiteralError> {
+    let before = text[..offset].chars().next_back();
+    let after = text[offset + 1..].chars().next();
+    if before.is_some_and(&is_digit) && after.is_some_and(&is_digit) {
+        return Ok(());
+    }
+    Err(LiteralError {
+        offset,
+        len: 1,
+        message: "`_` has to be between two digits".into(),
+        help: "Separators group digits, e.g. `1_000_000`".into(),
+    })
+}
+
+fn radix_name(radix: u32) -> &'static str {
+    match radix {
+        2 => "binary",
+        8 => "octal",
+        16 => "hexadecimal",
+        _ => "decimal",
+    }
+}
+
+fn radix_help(radix: u32) -> &'static str {
+    match radix {
+        2 => "Binary literals only use the digits 0 and 1",
+        8 => "Octal literals only use the digits 0 to 7",
+        16 => "Hexadecimal literals use the digits 0 to 9 and a to f",
+        _ => "Decimal literals only use the digits 0 to 9",
+    }
+}
@@ -1,18 +1,45 @@
+mod bignum;
+mod builtins;
+mod complex;
+mod decimal;
+mod environment;
 mod error;
+mod heap;
 mod input_buffer;
-
+mod literal;
+mod rational;
+mod sized;
+mod suggest;
+mod units;
+
+use crate::language::builtins::{constant, constant_names, Builtin};
+use crate::language::decimal::Decimal;
+use crate::language::environment::{
+    Environment, Promotion, Specialization, Trap, TrapSite, TrapSites, MAX_CALL_DEPTH,
+};
 use crate::language::error::{CalcErrorKind, CalculatorError};
 use crate::language::input_buffer::InputBuffer;
+use crate::language::literal::{parse_decimal, parse_float, parse_integer, parse_sized};
+use crate::language::sized::{IntType, SizedInt};
+use crate::language::suggest::did_you_mean;
+use crate::language::units::Unit;
 use ahash::AHasher;
 use cranelift::prelude::*;
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{Linkage, Module};
+use cranelift_module::{DataId, FuncId, Linkage, Module};
 use dashmap::DashMap;
-use miette::{NamedSource, Result as MietteResult};
+use miette::{NamedSource, Result as MietteResult, SourceSpan};
+use num_bigint::BigInt;
+use num_complex::Complex64;
+use num_rational::BigRational;
+use num_traits::ToPrimitive;
 use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::{
+    cell::RefCell,
     hash::{Hash, Hasher},
+    rc::Rc,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -23,13 +50,90 @@ use tree_sitter::Node;
 #[derive(Debug, Clone)]
 pub enum Expr {
     Integer(i64),
+    /// Integer literal too large for an `Integer`
+    BigInt(BigInt),
+    /// Integer literal with a type suffix, such as `0xFF_u8`
+    Sized(SizedInt),
     Float(f64),
+    /// Imaginary literal such as `4i`, holding its imaginary part
+    Imaginary(f64),
+    /// Decimal literal such as `19.99d`, as its digits and how many of them
+    /// are after the point, since the number of places is only fixed when
+    /// it is compiled
+    Decimal {
+        mantissa: i128,
+        places: u32,
+        span: SourceSpan,
+    },
+    /// Number with a unit, such as `3 km`
+    Quantity {
+        value: f64,
+        unit: Unit,
+    },
+    /// `value in unit`, e.g. `3 km / 20 min in km/h`
+    Convert {
+        value: Box<Expr>,
+        unit: Unit,
+        span: SourceSpan,
+    },
+    Boolean(bool),
+    Variable {
+        name: String,
+        span: SourceSpan,
+    },
+    Assign {
+        name: String,
+        value: Box<Expr>,
+    },
+    Call {
+        name: String,
+        args: Vec<Expr>,
+        span: SourceSpan,
+    },
+    FunctionDef {
+        name: String,
+        params: Vec<String>,
+        body: Box<Expr>,
+    },
     BinaryOp {
         left: Box<Expr>,
         op: BinaryOpKind,
         right: Box<Expr>,
+        spans: BinarySpans,
     },
     Parenthesized(Box<Expr>),
+    Unary {
+        op: UnaryOpKind,
+        operand: Box<Expr>,
+        span: SourceSpan,
+    },
+    Conditional {
+        condition: Box<Expr>,
+        consequence: Box<Expr>,
+        alternative: Box<Expr>,
+        spans: ConditionalSpans,
+    },
+    /// `sum(i = 1..n, body)` or `prod(...)`, compiled to a loop over `i`
+    Aggregate {
+        kind: AggregateKind,
+        variable: String,
+        start: Box<Expr>,
+        end: Box<Expr>,
+        body: Box<Expr>,
+        spans: AggregateSpans,
+    },
+    /// `b%`, `a + b%` or `a - b%`, holding what it means in other operators
+    Percent {
+        meaning: Box<Expr>,
+        form: PercentForm,
+    },
+    /// `value as int` or `value as float`, where `span` covers `as` and the
+    /// type
+    Cast {
+        value: Box<Expr>,
+        target: CastType,
+        span: SourceSpan,
+    },
 }
 
 impl Hash for Expr {
@@ -43,7 +147,12 @@ impl Hash for Expr {
                 1_u8.hash(state);
                 f.to_bits().hash(state);
             }
-            Expr::BinaryOp { left, op, right } => {
+            // Spans are left out throughout, so moving an expression (or
+            // editing a comment in it) keeps its compiled code. Traps in
+            // that code are reported on the current spans, see `trap_span`
+            Expr::BinaryOp {
+                left, op, right, ..
+            } => {
                 2_u8.hash(state);
                 left.hash(state);
                 op.hash(state);
@@ -53,6 +162,100 @@ impl Hash for Expr {
                 3_u8.hash(state);
                 inner.hash(state);
             }
+            Expr::Variable { name, .. } => {
+                4_u8.hash(state);
+                name.hash(state);
+            }
+            Expr::Assign { name, value } => {
+                5_u8.hash(state);
+                name.hash(state);
+                value.hash(state);
+            }
+            Expr::Call { name, args, .. } => {
+                6_u8.hash(state);
+                name.hash(state);
+                args.hash(state);
+            }
+            Expr::FunctionDef { name, params, body } => {
+                7_u8.hash(state);
+                name.hash(state);
+                params.hash(state);
+                body.hash(state);
+            }
+            Expr::Unary { op, operand, .. } => {
+                8_u8.hash(state);
+                op.hash(state);
+                operand.hash(state);
+            }
+            Expr::Boolean(b) => {
+                9_u8.hash(state);
+                b.hash(state);
+            }
+            Expr::Conditional {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                10_u8.hash(state);
+                condition.hash(state);
+                consequence.hash(state);
+                alternative.hash(state);
+            }
+            Expr::BigInt(n) => {
+                11_u8.hash(state);
+                n.hash(state);
+            }
+            Expr::Imaginary(f) => {
+                12_u8.hash(state);
+                f.to_bits().hash(state);
+            }
+            Expr::Quantity { value, unit } => {
+                13_u8.hash(state);
+                value.to_bits().hash(state);
+                unit.hash(state);
+            }
+            Expr::Convert { value, unit, .. } => {
+                14_u8.hash(state);
+                value.hash(state);
+                unit.hash(state);
+            }
+            Expr::Aggregate {
+                kind,
+                variable,
+                start,
+                end,
+                body,
+                ..
+            } => {
+                15_u8.hash(state);
+                kind.hash(state);
+                variable.hash(state);
+                start.hash(state);
+                end.hash(state);
+                body.hash(state);
+            }
+            Expr::Percent { meaning, .. } => {
+                16_u8.hash(state);
+                meaning.hash(state);
+            }
+            Expr::Decimal {
+                mantissa, places, ..
+            } => {
+                17_u8.hash(state);
+                mantissa.hash(state);
+                places.hash(state);
+            }
+            Expr::Cast { value, target, .. } => {
+                18_u8.hash(state);
+                value.hash(state);
+                target.hash(state);
+            }
+            Expr::Sized(n) => {
+                19_u8.hash(state);
+                n.raw.hash(state);
+                n.ty.hash(state);
+            }
         }
     }
 }
@@ -63,14 +266,272 @@ pub enum BinaryOpKind {
     Subtract,
     Multiply,
     Divide,
+    FloorDivide,
+    Power,
+    Modulo,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+}
+
+impl BinaryOpKind {
+    /// Operators that only make sense on integers.
+    fn is_bitwise(self) -> bool {
+        matches!(
+            self,
+            BinaryOpKind::BitAnd
+                | BinaryOpKind::BitOr
+                | BinaryOpKind::BitXor
+                | BinaryOpKind::ShiftLeft
+                | BinaryOpKind::ShiftRight
+        )
+    }
+
+    /// Condition codes for the comparison operators.
+    fn condition(self) -> Option<(IntCC, FloatCC)> {
+        match self {
+            BinaryOpKind::Equal => Some((IntCC::Equal, FloatCC::Equal)),
+            BinaryOpKind::NotEqual => Some((IntCC::NotEqual, FloatCC::NotEqual)),
+            BinaryOpKind::Less => Some((IntCC::SignedLessThan, FloatCC::LessThan)),
+            BinaryOpKind::LessEqual => {
+                Some((IntCC::SignedLessThanOrEqual, FloatCC::LessThanOrEqual))
+            }
+            BinaryOpKind::Greater => Some((IntCC::SignedGreaterThan, FloatCC::GreaterThan)),
+            BinaryOpKind::GreaterEqual => {
+                Some((IntCC::SignedGreaterThanOrEqual, FloatCC::GreaterThanOrEqual))
+            }
+            _ => None,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOpKind::Add => "+",
+            BinaryOpKind::Subtract => "-",
+            BinaryOpKind::Multiply => "*",
+            BinaryOpKind::Divide => "/",
+            BinaryOpKind::FloorDivide => "//",
+            BinaryOpKind::Power => "^",
+            BinaryOpKind::Modulo => "%",
+            BinaryOpKind::BitAnd => "&",
+            BinaryOpKind::BitOr => "|",
+            BinaryOpKind::BitXor => "xor",
+            BinaryOpKind::ShiftLeft => "<<",
+            BinaryOpKind::ShiftRight => ">>",
+            BinaryOpKind::Equal => "==",
+            BinaryOpKind::NotEqual => "!=",
+            BinaryOpKind::Less => "<",
+            BinaryOpKind::LessEqual => "<=",
+            BinaryOpKind::Greater => ">",
+            BinaryOpKind::GreaterEqual => ">=",
+            BinaryOpKind::And => "&&",
+            BinaryOpKind::Or => "||",
+        }
+    }
+}
+
+/// Where the parts of a binary operation are in the source. An implicit
+/// product like `2(3)` has no operator, so its `operator` is the gap
+/// between the operands, where diagnostics about the `*` point.
+#[derive(Debug, Clone, Copy, Hash, PartialEq)]
+pub struct BinarySpans {
+    pub left: SourceSpan,
+    pub operator: SourceSpan,
+    pub right: SourceSpan,
+}
+
+impl Default for BinarySpans {
+    fn default() -> Self {
+        let empty = SourceSpan::from((0, 0));
+        Self {
+            left: empty,
+            operator: empty,
+            right: empty,
+        }
+    }
+}
+
+/// Where the parts of a conditional expression are in the source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConditionalSpans {
+    pub condition: SourceSpan,
+    pub consequence: SourceSpan,
+    pub alternative: SourceSpan,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq)]
+pub enum AggregateKind {
+    Sum,
+    Product,
+}
+
+impl AggregateKind {
+    fn name(self) -> &'static str {
+        match self {
+            AggregateKind::Sum => "sum",
+            AggregateKind::Product => "prod",
+        }
+    }
+
+    /// The operator terms are combined with, and what it starts from.
+    fn operator(self) -> (BinaryOpKind, i64) {
+        match self {
+            AggregateKind::Sum => (BinaryOpKind::Add, 0),
+            AggregateKind::Product => (BinaryOpKind::Multiply, 1),
+        }
+    }
+}
+
+/// How a `%` after a number is read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PercentForm {
+    /// `b%` on its own is `b / 100`
+    Of,
+    /// `a + b%` is `a` increased by `b` percent
+    Increase,
+    /// `a - b%` is `a` decreased by `b` percent
+    Decrease,
+}
+
+impl PercentForm {
+    /// What the percentage means, for diagnostics.
+    fn reading(self) -> &'static str {
+        match self {
+            PercentForm::Of => "`b%` is `b / 100`",
+            PercentForm::Increase => "`a + b%` is `a * (100 + b) / 100`",
+            PercentForm::Decrease => "`a - b%` is `a * (100 - b) / 100`",
+        }
+    }
+}
+
+/// What integer arithmetic does with a result out of its type's range.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum OverflowMode {
+    /// Start over with big integers, which can't overflow (sized integers
+    /// are checked instead)
+    #[default]
+    Promote,
+    /// Report an error on the operator
+    Checked,
+    /// Keep the low bits, like `wrapping_add` in Rust
+    Wrapping,
+    /// Clamp to the type's minimum or maximum, like `saturating_add`
+    Saturating,
+}
+
+impl OverflowMode {
+    pub const ALL: [OverflowMode; 4] = [
+        OverflowMode::Promote,
+        OverflowMode::Checked,
+        OverflowMode::Wrapping,
+        OverflowMode::Saturating,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OverflowMode::Promote => "promote",
+            OverflowMode::Checked => "checked",
+            OverflowMode::Wrapping => "wrapping",
+            OverflowMode::Saturating => "saturating",
+        }
+    }
+}
+
+/// Type a value is cast to with `as`.
+#[derive(Debug, Clone, Copy, Hash, PartialEq)]
+pub enum CastType {
+    /// Truncates toward zero, like `as` in Rust
+    Int,
+    Float,
+    /// Has to be in range, unlike with `as` in Rust
+    Sized(IntType),
+}
+
+impl CastType {
+    fn all() -> impl Iterator<Item = CastType> {
+        [CastType::Int, CastType::Float]
+            .into_iter()
+            .chain(IntType::ALL.map(CastType::Sized))
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::all().find(|target| target.name() == name)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            CastType::Int => "int",
+            CastType::Float => "float",
+            CastType::Sized(ty) => ty.name(),
+        }
+    }
+}
+
+/// Where the parts of an aggregate are in the source.
+#[derive(Debug, Clone, Copy, Hash, PartialEq)]
+pub struct AggregateSpans {
+    /// The `sum` or `prod` keyword
+    pub function: SourceSpan,
+    pub start: SourceSpan,
+    pub end: SourceSpan,
+    pub body: SourceSpan,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq)]
+pub enum UnaryOpKind {
+    Negate,
+    Plus,
+    BitNot,
+    Not,
+}
+
+impl UnaryOpKind {
+    fn symbol(self) -> &'static str {
+        match self {
+            UnaryOpKind::Negate => "-",
+            UnaryOpKind::Plus => "+",
+            UnaryOpKind::BitNot => "~",
+            UnaryOpKind::Not => "!",
+        }
+    }
 }
 
 // ===== Value System =====
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum CalcValue {
     Integer(i64),
     Float(f64),
+    Bool(bool),
+    /// Exact integer, used once a value doesn't fit in an `Integer`
+    BigInt(BigInt),
+    /// Exact fraction in lowest terms, from dividing integers in exact mode
+    Rational(BigRational),
+    /// Complex number, from an imaginary literal or a function of a negative
+    /// number such as `sqrt(-1)`
+    Complex(Complex64),
+    /// Fixed-point number from a decimal literal, e.g. `0.1d`
+    Decimal(Decimal),
+    /// Integer of a given width and signedness, e.g. `255u8`
+    Sized(SizedInt),
+    /// Number with a unit, in that unit (compiled code works in SI base units)
+    Quantity(f64, Unit),
+    /// Result of a function definition, holding its signature (e.g. `f(x, y)`)
+    Function(String),
 }
 
 impl Default for CalcValue {
@@ -84,6 +545,16 @@ impl Display for CalcValue {
         match self {
             CalcValue::Integer(i) => write!(f, "{}", i),
             CalcValue::Float(x) => write!(f, "{}", x),
+            CalcValue::Bool(b) => write!(f, "{}", b),
+            CalcValue::BigInt(n) => write!(f, "{}", n),
+            CalcValue::Rational(r) => write!(f, "{}", r),
+            CalcValue::Complex(z) if z.re == 0.0 => write!(f, "{}i", z.im),
+            CalcValue::Complex(z) if z.im < 0.0 => write!(f, "{}-{}i", z.re, -z.im),
+            CalcValue::Complex(z) => write!(f, "{}+{}i", z.re, z.im),
+            CalcValue::Decimal(d) => write!(f, "{}", d),
+            CalcValue::Sized(n) => write!(f, "{}", n),
+            CalcValue::Quantity(x, unit) => write!(f, "{} {}", x, unit.name),
+            CalcValue::Function(signature) => write!(f, "{}", signature),
         }
     }
 }
@@ -93,6 +564,17 @@ impl PartialEq for CalcValue {
         match (self, other) {
             (CalcValue::Integer(a), CalcValue::Integer(b)) => a == b,
             (CalcValue::Float(a), CalcValue::Float(b)) => (a - b).abs() < f64::EPSILON,
+            (CalcValue::Bool(a), CalcValue::Bool(b)) => a == b,
+            (CalcValue::BigInt(a), CalcValue::BigInt(b)) => a == b,
+            (CalcValue::Rational(a), CalcValue::Rational(b)) => a == b,
+            (CalcValue::Complex(a), CalcValue::Complex(b)) => (a - b).norm() < f64::EPSILON,
+            (CalcValue::Decimal(a), CalcValue::Decimal(b)) => a == b,
+            (CalcValue::Sized(a), CalcValue::Sized(b)) => a == b,
+            // Converting between units rarely comes out exact
+            (CalcValue::Quantity(a, a_unit), CalcValue::Quantity(b, b_unit)) => {
+                a_unit == b_unit && (a - b).abs() <= f64::EPSILON * a.abs().max(b.abs()).max(1.0)
+            }
+            (CalcValue::Function(a), CalcValue::Function(b)) => a == b,
             _ => false,
         }
     }
@@ -100,6 +582,26 @@ impl PartialEq for CalcValue {
 
 // ===== JIT Compilation =====
 
+/// Function parameters visible while typing (`CalcValue`) or compiling
+/// (`(CalcValue, Value)`) a function body.
+type Scope<T> = HashMap<String, T>;
+
+fn ir_type(ty: &CalcValue) -> Type {
+    match ty {
+        CalcValue::Float(_) | CalcValue::Quantity(..) => types::F64,
+        CalcValue::Bool(_) => types::I8,
+        // Real and imaginary parts, in that order
+        CalcValue::Complex(_) => types::F64X2,
+        CalcValue::Decimal(_) => types::I128,
+        CalcValue::Sized(n) => n.ty.ir_type(),
+        // Big integers and rationals are passed around as pointers
+        CalcValue::Integer(_)
+        | CalcValue::BigInt(_)
+        | CalcValue::Rational(_)
+        | CalcValue::Function(_) => types::I64,
+    }
+}
+
 pub struct CompilationCache {
     last_tree: Option<tree_sitter::Tree>,
     function_cache: DashMap<u64, Arc<CompiledFunction>>,
@@ -108,25 +610,68 @@ pub struct CompilationCache {
 pub enum CompiledFnPtr {
     Integer(unsafe fn() -> i64),
     Float(unsafe fn() -> f64),
+    Bool(unsafe fn() -> bool),
+    BigInt(unsafe fn() -> *const BigInt),
+    Rational(unsafe fn() -> *const BigRational),
+    Complex(unsafe extern "C" fn() -> Complex64),
+    /// Returns the scaled value, to be shown with this many places
+    Decimal(unsafe extern "C" fn() -> decimal::Parts, u32),
+    /// Returns the value widened to 128 bits
+    Sized(unsafe extern "C" fn() -> decimal::Parts, IntType),
+    /// Returns the value in SI base units, to be shown in the unit
+    Quantity(unsafe fn() -> f64, Unit),
 }
 
 pub struct CompiledFunction {
     code_ptr: CompiledFnPtr,
     last_used: Arc<Mutex<Instant>>,
+    /// Spans of the expression this was compiled from, see `trap_span`
+    spans: Vec<SourceSpan>,
+    /// Sites of the traps compiled into it, other than those in the bodies
+    /// of functions it calls
+    trap_sites: TrapSites,
 }
 
-impl CompiledFunction {
-    fn new_int(code_ptr: unsafe fn() -> i64) -> Self {
-        Self {
-            code_ptr: CompiledFnPtr::Integer(code_ptr),
-            last_used: Arc::new(Mutex::new(Instant::now())),
+impl CompiledFnPtr {
+    /// The finalized code at `fn_ptr` as the variant for `return_type`.
+    ///
+    /// # Safety
+    ///
+    /// The code has to have been compiled to return a `return_type`.
+    unsafe fn new(fn_ptr: *const u8, return_type: CalcValue) -> Self {
+        /// `fn_ptr` as the function pointer type `F`, which the caller
+        /// picks to match the compiled signature.
+        unsafe fn code<F: Copy>(fn_ptr: *const u8) -> F {
+            assert_eq!(std::mem::size_of::<F>(), std::mem::size_of::<*const u8>());
+            unsafe { std::mem::transmute_copy::<*const u8, F>(&fn_ptr) }
+        }
+
+        unsafe {
+            match return_type {
+                CalcValue::Integer(_) => Self::Integer(code(fn_ptr)),
+                CalcValue::Float(_) => Self::Float(code(fn_ptr)),
+                CalcValue::Bool(_) => Self::Bool(code(fn_ptr)),
+                CalcValue::BigInt(_) => Self::BigInt(code(fn_ptr)),
+                CalcValue::Rational(_) => Self::Rational(code(fn_ptr)),
+                CalcValue::Complex(_) => Self::Complex(code(fn_ptr)),
+                CalcValue::Decimal(d) => Self::Decimal(code(fn_ptr), d.places),
+                CalcValue::Sized(n) => Self::Sized(code(fn_ptr), n.ty),
+                CalcValue::Quantity(_, unit) => Self::Quantity(code(fn_ptr), unit),
+                CalcValue::Function(_) => {
+                    unreachable!("definitions are rejected by determine_type")
+                }
+            }
         }
     }
+}
 
-    fn new_float(code_ptr: unsafe fn() -> f64) -> Self {
+impl CompiledFunction {
+    fn new(code_ptr: CompiledFnPtr) -> Self {
         Self {
-            code_ptr: CompiledFnPtr::Float(code_ptr),
+            code_ptr,
             last_used: Arc::new(Mutex::new(Instant::now())),
+            spans: Vec::new(),
+            trap_sites: TrapSites::new(),
         }
     }
 
@@ -135,13 +680,354 @@ impl CompiledFunction {
     }
 
     unsafe fn call(&self) -> CalcValue {
-        match self.code_ptr {
+        match &self.code_ptr {
             CompiledFnPtr::Integer(ptr) => CalcValue::Integer(ptr()),
             CompiledFnPtr::Float(ptr) => CalcValue::Float(ptr()),
+            CompiledFnPtr::Bool(ptr) => CalcValue::Bool(ptr()),
+            // Big integer arithmetic can end up back in range, e.g. `2^64 - 2^64`
+            CompiledFnPtr::BigInt(ptr) => integer(bignum::read(ptr())),
+            // Likewise, fractions can cancel out, e.g. `(1 / 3) * 3`
+            CompiledFnPtr::Rational(ptr) => {
+                let r = rational::read(ptr());
+                match r.is_integer() {
+                    true => integer(r.to_integer()),
+                    false => CalcValue::Rational(r),
+                }
+            }
+            // And imaginary parts, e.g. `(1 + 2i) * (1 - 2i)`
+            CompiledFnPtr::Complex(ptr) => match ptr() {
+                z if z.im == 0.0 => CalcValue::Float(z.re),
+                z => CalcValue::Complex(z),
+            },
+            CompiledFnPtr::Decimal(ptr, places) => CalcValue::Decimal(Decimal {
+                scaled: ptr().value(),
+                places: *places,
+            }),
+            CompiledFnPtr::Sized(ptr, ty) => {
+                CalcValue::Sized(SizedInt::new(ptr().value() as u128, *ty))
+            }
+            CompiledFnPtr::Quantity(ptr, unit) => {
+                CalcValue::Quantity(ptr() / unit.scale, unit.clone())
+            }
+        }
+    }
+}
+
+/// The smallest type holding `n`.
+fn integer(n: BigInt) -> CalcValue {
+    match i64::try_from(&n) {
+        Ok(n) => CalcValue::Integer(n),
+        Err(_) => CalcValue::BigInt(n),
+    }
+}
+
+/// How a type is described in diagnostics.
+fn type_name(ty: &CalcValue) -> &'static str {
+    match ty {
+        CalcValue::Integer(_) => "an integer",
+        CalcValue::Float(_) => "a float",
+        CalcValue::Bool(_) => "a boolean",
+        CalcValue::BigInt(_) => "a big integer",
+        CalcValue::Rational(_) => "a rational",
+        CalcValue::Complex(_) => "a complex number",
+        CalcValue::Decimal(_) => "a decimal",
+        CalcValue::Sized(_) => "a sized integer",
+        CalcValue::Quantity(..) => "a quantity",
+        CalcValue::Function(_) => "a function",
+    }
+}
+
+/// Type of a value in `unit`, where units that cancel out (e.g. `km / m`)
+/// leave a plain float.
+fn in_unit(unit: Unit) -> CalcValue {
+    match unit.is_dimensionless() {
+        true => CalcValue::Float(0.0),
+        false => CalcValue::Quantity(0.0, unit),
+    }
+}
+
+/// What a call expression refers to.
+enum Callee {
+    User { params: Vec<String>, body: Expr },
+    Builtin(Builtin),
+}
+
+/// Every span in `expr`, in the same order for expressions that only differ
+/// in where they are.
+fn collect_spans(expr: &Expr, spans: &mut Vec<SourceSpan>) {
+    match expr {
+        Expr::Integer(_)
+        | Expr::BigInt(_)
+        | Expr::Sized(_)
+        | Expr::Float(_)
+        | Expr::Imaginary(_)
+        | Expr::Quantity { .. }
+        | Expr::Boolean(_) => {}
+        Expr::Variable { span, .. } | Expr::Decimal { span, .. } => spans.push(*span),
+        Expr::Convert { value, span, .. } => {
+            spans.push(*span);
+            collect_spans(value, spans);
+        }
+        Expr::Assign { value, .. } => collect_spans(value, spans),
+        Expr::Call { args, span, .. } => {
+            spans.push(*span);
+            for arg in args {
+                collect_spans(arg, spans);
+            }
+        }
+        Expr::FunctionDef { body, .. } => collect_spans(body, spans),
+        Expr::BinaryOp {
+            left,
+            right,
+            spans: parts,
+            ..
+        } => {
+            spans.extend([parts.left, parts.operator, parts.right]);
+            collect_spans(left, spans);
+            collect_spans(right, spans);
+        }
+        Expr::Parenthesized(inner) => collect_spans(inner, spans),
+        Expr::Unary { operand, span, .. } => {
+            spans.push(*span);
+            collect_spans(operand, spans);
+        }
+        Expr::Conditional {
+            condition,
+            consequence,
+            alternative,
+            spans: parts,
+        } => {
+            spans.extend([parts.condition, parts.consequence, parts.alternative]);
+            collect_spans(condition, spans);
+            collect_spans(consequence, spans);
+            collect_spans(alternative, spans);
+        }
+        Expr::Aggregate {
+            start,
+            end,
+            body,
+            spans: parts,
+            ..
+        } => {
+            spans.extend([parts.function, parts.start, parts.end, parts.body]);
+            collect_spans(start, spans);
+            collect_spans(end, spans);
+            collect_spans(body, spans);
+        }
+        Expr::Percent { meaning, .. } => collect_spans(meaning, spans),
+        Expr::Cast { value, span, .. } => {
+            spans.push(*span);
+            collect_spans(value, spans);
+        }
+    }
+}
+
+/// Value of `expr` if it is an integer constant, e.g. the `-2` in `x ^ -2`.
+fn integer_constant(expr: &Expr) -> Option<BigInt> {
+    match expr {
+        Expr::Integer(n) => Some(BigInt::from(*n)),
+        Expr::BigInt(n) => Some(n.clone()),
+        Expr::Parenthesized(inner) => integer_constant(inner),
+        Expr::Unary { op, operand, .. } => match op {
+            UnaryOpKind::Negate => integer_constant(operand).map(|n| -n),
+            UnaryOpKind::Plus => integer_constant(operand),
+            UnaryOpKind::BitNot => integer_constant(operand).map(|n| !n),
+            UnaryOpKind::Not => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `expr` is an integer constant below zero. Such powers are
+/// computed as floats.
+fn is_negative_constant(expr: &Expr) -> bool {
+    integer_constant(expr).is_some_and(|n| n.sign() == num_bigint::Sign::Minus)
+}
+
+/// Integer power by square-and-multiply, along with whether the result
+/// overflowed. Compiled code checks for a negative exponent first.
+fn int_pow(builder: &mut FunctionBuilder, base: Value, exponent: Value) -> (Value, Value) {
+    let header = builder.create_block();
+    let body = builder.create_block();
+    let exit = builder.create_block();
+    for block in [header, body] {
+        for _ in 0..3 {
+            builder.append_block_param(block, types::I64);
+        }
+        builder.append_block_param(block, types::I8);
+    }
+    builder.append_block_param(exit, types::I64);
+    builder.append_block_param(exit, types::I8);
+
+    let one = builder.ins().iconst(types::I64, 1);
+    let no = builder.ins().iconst(types::I8, 0);
+    builder.ins().jump(header, &[one, base, exponent, no]);
+
+    // header(result, base, exponent, overflowed): keep going while bits are left
+    builder.switch_to_block(header);
+    let [result, factor, remaining, overflowed] =
+        [0, 1, 2, 3].map(|i| builder.block_params(header)[i]);
+    builder.ins().brif(
+        remaining,
+        body,
+        &[result, factor, remaining, overflowed],
+        exit,
+        &[result, overflowed],
+    );
+
+    builder.switch_to_block(body);
+    builder.seal_block(body);
+    let [result, factor, remaining, overflowed] =
+        [0, 1, 2, 3].map(|i| builder.block_params(body)[i]);
+    let odd = builder.ins().band_imm(remaining, 1);
+    let (multiplied, multiply_overflowed) = builder.ins().smul_overflow(result, factor);
+    let result = builder.ins().select(odd, multiplied, result);
+    let odd = builder.ins().icmp_imm(IntCC::NotEqual, odd, 0);
+    let multiply_overflowed = builder.ins().band(multiply_overflowed, odd);
+    let remaining = builder.ins().ushr_imm(remaining, 1);
+    // Squaring the factor one last time doesn't matter
+    let (factor, square_overflowed) = builder.ins().smul_overflow(factor, factor);
+    let more = builder.ins().icmp_imm(IntCC::NotEqual, remaining, 0);
+    let square_overflowed = builder.ins().band(square_overflowed, more);
+    let overflowed = builder.ins().bor(overflowed, multiply_overflowed);
+    let overflowed = builder.ins().bor(overflowed, square_overflowed);
+    builder
+        .ins()
+        .jump(header, &[result, factor, remaining, overflowed]);
+    builder.seal_block(header);
+
+    builder.switch_to_block(exit);
+    builder.seal_block(exit);
+    let [result, overflowed] = [0, 1].map(|i| builder.block_params(exit)[i]);
+    (result, overflowed)
+}
+
+/// Integer division rounding toward negative infinity, e.g. `-7 // 2 == -4`.
+/// `sdiv` truncates, so the quotient is one too big whenever there is a
+/// remainder whose sign differs from the divisor's.
+fn floor_div(builder: &mut FunctionBuilder, dividend: Value, divisor: Value) -> Value {
+    let quotient = builder.ins().sdiv(dividend, divisor);
+    let remainder = builder.ins().srem(dividend, divisor);
+    let inexact = builder.ins().icmp_imm(IntCC::NotEqual, remainder, 0);
+    let signs = builder.ins().bxor(remainder, divisor);
+    let signs_differ = builder.ins().icmp_imm(IntCC::SignedLessThan, signs, 0);
+    let adjust = builder.ins().band(inexact, signs_differ);
+    let ty = builder.func.dfg.value_type(quotient);
+    let adjust = match ty {
+        types::I8 => adjust,
+        _ => builder.ins().uextend(ty, adjust),
+    };
+    builder.ins().isub(quotient, adjust)
+}
+
+/// Whether a signed sum, difference or negation that overflowed went below
+/// the minimum rather than above the maximum: wrapping flipped its sign.
+fn wrapped_below(builder: &mut FunctionBuilder, wrapped: Value) -> Value {
+    builder
+        .ins()
+        .icmp_imm(IntCC::SignedGreaterThanOrEqual, wrapped, 0)
+}
+
+/// `value << amount`, along with whether it lost bits: whether shifting it
+/// back doesn't give `value` again.
+fn shift_left(
+    builder: &mut FunctionBuilder,
+    (value, amount): (Value, Value),
+    signed: bool,
+) -> (Value, Value) {
+    let shifted = builder.ins().ishl(value, amount);
+    let back = match signed {
+        true => builder.ins().sshr(shifted, amount),
+        false => builder.ins().ushr(shifted, amount),
+    };
+    let overflowed = builder.ins().icmp(IntCC::NotEqual, back, value);
+    (shifted, overflowed)
+}
+
+/// Whether a signed left shift that overflowed went below the minimum,
+/// which it does for a negative value.
+fn shift_below(builder: &mut FunctionBuilder, value: Value) -> Value {
+    builder.ins().icmp_imm(IntCC::SignedLessThan, value, 0)
+}
+
+/// Whether a signed product that overflowed went below the minimum, which
+/// it does when the signs of its factors differ.
+fn product_below(builder: &mut FunctionBuilder, a: Value, b: Value) -> Value {
+    let signs = builder.ins().bxor(a, b);
+    builder.ins().icmp_imm(IntCC::SignedLessThan, signs, 0)
+}
+
+/// Whether a signed power that overflowed went below the minimum, which it
+/// does for a negative base and an odd exponent.
+fn power_below(builder: &mut FunctionBuilder, base: Value, exponent: Value) -> Value {
+    let negative = builder.ins().icmp_imm(IntCC::SignedLessThan, base, 0);
+    let exponent = match builder.func.dfg.value_type(exponent) {
+        types::I128 => builder.ins().ireduce(types::I64, exponent),
+        _ => exponent,
+    };
+    let odd = builder.ins().band_imm(exponent, 1);
+    let odd = builder.ins().icmp_imm(IntCC::NotEqual, odd, 0);
+    builder.ins().band(negative, odd)
+}
+
+/// For overflows that always go the same way, e.g. above the maximum for
+/// an unsigned sum.
+fn constant_below(below: bool) -> impl FnOnce(&mut FunctionBuilder) -> Value {
+    move |builder| builder.ins().iconst(types::I8, i64::from(below))
+}
+
+/// Whether `base ^ exponent` (on floats) takes a negative number to a
+/// fractional power, which only has complex results.
+fn is_complex_power(builder: &mut FunctionBuilder, base: Value, exponent: Value) -> Value {
+    let zero = builder.ins().f64const(0.0);
+    let negative = builder.ins().fcmp(FloatCC::LessThan, base, zero);
+    let whole = builder.ins().floor(exponent);
+    let fractional = builder
+        .ins()
+        .fcmp(FloatCC::OrderedNotEqual, exponent, whole);
+    builder.ins().band(negative, fractional)
+}
+
+/// The real and imaginary parts of a complex number.
+fn split(builder: &mut FunctionBuilder, z: Value) -> [Value; 2] {
+    [0, 1].map(|lane| builder.ins().extractlane(z, lane))
+}
+
+/// A complex number from its real and imaginary parts.
+fn join(builder: &mut FunctionBuilder, re: Value, im: Value) -> Value {
+    let z = builder.ins().scalar_to_vector(types::F64X2, re);
+    builder.ins().insertlane(z, im, 1)
+}
+
+/// An `i128` constant, which `iconst` can't hold more than 64 bits of.
+fn i128_constant(builder: &mut FunctionBuilder, n: i128) -> Value {
+    let low = builder.ins().iconst(types::I64, n as u64 as i64);
+    let high = builder.ins().iconst(types::I64, (n >> 64) as i64);
+    builder.ins().iconcat(low, high)
+}
+
+/// `n` as an integer of type `ty`, of which it keeps the low bits.
+fn int_constant(builder: &mut FunctionBuilder, ty: Type, n: i128) -> Value {
+    match ty {
+        types::I128 => i128_constant(builder, n),
+        _ => {
+            let bits = ty.bits();
+            builder
+                .ins()
+                .iconst(ty, (n as i64) << (64 - bits) >> (64 - bits))
         }
     }
 }
 
+/// A sized integer extended to 128 bits, by its sign if it has one.
+fn widen(builder: &mut FunctionBuilder, ty: IntType, value: Value) -> Value {
+    match (ty.bits, ty.signed) {
+        (128, _) => value,
+        (_, true) => builder.ins().sextend(types::I128, value),
+        (_, false) => builder.ins().uextend(types::I128, value),
+    }
+}
+
 // ===== Parser Implementation =====
 
 fn collect_error_nodes<'a>(node: Node<'a>, errors: &mut Vec<Node<'a>>) {
@@ -155,6 +1041,91 @@ fn collect_error_nodes<'a>(node: Node<'a>, errors: &mut Vec<Node<'a>>) {
     }
 }
 
+/// Row and (byte) column of `byte` in `text`, as tree-sitter counts them.
+fn point_at(text: &str, byte: usize) -> tree_sitter::Point {
+    let before = &text.as_bytes()[..byte.min(text.len())];
+    match before.iter().rposition(|&b| b == b'\n') {
+        Some(newline) => tree_sitter::Point::new(
+            before.iter().filter(|&&b| b == b'\n').count(),
+            before.len() - newline - 1,
+        ),
+        None => tree_sitter::Point::new(0, before.len()),
+    }
+}
+
+/// `value / 100` for the `value` node in a `percent`, with the `/` on the
+/// `%`.
+fn per_hundred(value: Expr, value_node: Node, percent: Node) -> Expr {
+    let sign = percent.child_by_field_name("operator").unwrap_or(percent);
+    let sign_span: SourceSpan = (sign.start_byte(), sign.end_byte() - sign.start_byte()).into();
+    Expr::BinaryOp {
+        left: Box::new(value),
+        op: BinaryOpKind::Divide,
+        right: Box::new(Expr::Integer(100)),
+        spans: BinarySpans {
+            left: (
+                value_node.start_byte(),
+                value_node.end_byte() - value_node.start_byte(),
+            )
+                .into(),
+            operator: sign_span,
+            right: sign_span,
+        },
+    }
+}
+
+/// Add what a percentage means to a type error in it, since the operators
+/// the error names aren't written out, e.g. the `*` in `true + 5%`.
+fn explain_percent(error: miette::Report, form: PercentForm) -> miette::Report {
+    match error.downcast::<CalculatorError>() {
+        Ok(mut error) if matches!(error.kind, CalcErrorKind::TypeMismatch(_)) => {
+            let reading = format!(
+                "{}, which is a float even for integers (a fraction in exact mode)",
+                form.reading()
+            );
+            error.help = Some(match error.help {
+                Some(help) => format!("{}. Here {}", help, reading),
+                None => format!("Here {}", reading),
+            });
+            error.into()
+        }
+        Ok(error) => error.into(),
+        Err(error) => error,
+    }
+}
+
+/// What a name stands for in an expression: the value of a constant such as
+/// `pi`, or else a variable.
+fn name_expr(name: &str, span: SourceSpan) -> Expr {
+    match constant(name) {
+        Some(value) => Expr::Float(value),
+        None => Expr::Variable {
+            name: name.to_string(),
+            span,
+        },
+    }
+}
+
+/// `left * right` for operands written side by side, with the `*` placed
+/// in the gap between them.
+fn implicit_product(left: Expr, right: Expr, left_node: Node, right_node: Node) -> Expr {
+    let node_span = |n: Node| (n.start_byte(), n.end_byte() - n.start_byte()).into();
+    Expr::BinaryOp {
+        left: Box::new(left),
+        op: BinaryOpKind::Multiply,
+        right: Box::new(right),
+        spans: BinarySpans {
+            left: node_span(left_node),
+            operator: (
+                left_node.end_byte(),
+                right_node.start_byte() - left_node.end_byte(),
+            )
+                .into(),
+            right: node_span(right_node),
+        },
+    }
+}
+
 // ===== Calculator Implementation =====
 
 pub struct Calculator {
@@ -164,6 +1135,7 @@ pub struct Calculator {
     jit_module: Arc<RwLock<JITModule>>,
     builder_context: Arc<Mutex<FunctionBuilderContext>>,
     input_buffer: InputBuffer,
+    environment: Rc<RefCell<Environment>>,
 }
 
 impl Calculator {
@@ -199,6 +1171,16 @@ impl Calculator {
                 help: None,
             })?;
 
+        // Functions taking or returning decimals pass `i128`s
+        flag_builder
+            .set("enable_llvm_abi_extensions", "true")
+            .map_err(|e| CalculatorError {
+                src: source.clone(),
+                span: (0, 0).into(),
+                kind: CalcErrorKind::JitError(e.to_string()),
+                help: None,
+            })?;
+
         let isa_builder = cranelift_native::builder().map_err(|e| CalculatorError {
             src: source.clone(),
             span: (0, 0).into(),
@@ -215,8 +1197,20 @@ impl Calculator {
                 help: None,
             })?;
 
-        let builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
-        let jit_module = JITModule::new(builder);
+        let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        builtins::register_symbols(&mut builder);
+        bignum::register_symbols(&mut builder);
+        rational::register_symbols(&mut builder);
+        complex::register_symbols(&mut builder);
+        decimal::register_symbols(&mut builder);
+        sized::register_symbols(&mut builder);
+        let mut jit_module = JITModule::new(builder);
+        let environment = Environment::new(&mut jit_module).map_err(|e| CalculatorError {
+            src: source.clone(),
+            span: (0, 0).into(),
+            kind: CalcErrorKind::JitError(e.to_string()),
+            help: None,
+        })?;
 
         Ok(Self {
             parser,
@@ -228,9 +1222,57 @@ impl Calculator {
             jit_module: Arc::new(RwLock::new(jit_module)),
             builder_context: Arc::new(Mutex::new(FunctionBuilderContext::new())),
             input_buffer: InputBuffer::new(),
+            environment: Rc::new(RefCell::new(environment)),
         })
     }
 
+    /// Make `/` on integers exact, giving rationals instead of floats.
+    pub fn set_exact(&mut self, exact: bool) {
+        self.environment.borrow_mut().set_exact(exact);
+    }
+
+    /// Choose what integer arithmetic does with a result out of range.
+    /// Compiled code is kept for each mode, so switching back is free.
+    pub fn set_overflow(&mut self, mode: OverflowMode) {
+        self.environment.borrow_mut().set_overflow(mode);
+    }
+
+    /// Keep this many digits after the point in decimals such as `0.1d`, up
+    /// to 18 (more are capped). Decimal variables are rounded to match.
+    pub fn set_decimal_places(&mut self, places: u32) {
+        let jit_module = self.jit_module.read();
+        self.environment
+            .borrow_mut()
+            .set_places(&jit_module, places.min(decimal::MAX_PLACES));
+    }
+
+    /// Keep the effects of everything evaluated so far, as the repl does
+    /// when a line is submitted.
+    pub fn commit(&mut self) {
+        let jit_module = self.jit_module.read();
+        self.environment.borrow_mut().commit(&jit_module);
+    }
+
+    /// Undo the assignments and function definitions evaluated since the
+    /// last `commit`, so that evaluating an edited line again doesn't
+    /// apply them twice.
+    pub fn rollback(&mut self) {
+        let jit_module = self.jit_module.read();
+        self.environment.borrow_mut().rollback(&jit_module);
+    }
+
+    /// Current value of a session variable, if it has been assigned.
+    pub fn variable(&self, name: &str) -> Option<CalcValue> {
+        let jit_module = self.jit_module.read();
+        self.environment
+            .borrow()
+            .variable(name)
+            .map(|variable| variable.value(&jit_module))
+    }
+
+    /// Evaluate a single-statement input, e.g. `1 + 2`. With several
+    /// statements, this is the last one's result, unless an earlier one
+    /// failed.
     pub fn update_input(
         &mut self,
         new_input: &str,
@@ -238,37 +1280,83 @@ impl Calculator {
         old_end: usize,
         new_end: usize,
     ) -> MietteResult<CalcValue> {
-        self.input_buffer
-            .update(new_input, edit_pos, old_end, new_end);
-        self.source = NamedSource::new("calculator", new_input.to_string());
+        self.update_program(new_input, edit_pos, old_end, new_end)
+            .into_iter()
+            .reduce(|result, next| result.and(next))
+            .unwrap_or_else(|| {
+                Err(CalculatorError {
+                    src: self.source.clone(),
+                    span: (0, new_input.len()).into(),
+                    kind: CalcErrorKind::ParseError("Empty expression".into()),
+                    help: Some("Expression cannot be empty".into()),
+                })?
+            })
+    }
 
+    /// Reparse the input after an edit replacing `edit_pos..old_end` with
+    /// `edit_pos..new_end` of `new_input`, then evaluate its statements
+    /// (separated by `;` or newlines) in order, giving one result each.
+    /// Errors point into the whole input.
+    pub fn update_program(
+        &mut self,
+        new_input: &str,
+        edit_pos: usize,
+        old_end: usize,
+        new_end: usize,
+    ) -> Vec<MietteResult<CalcValue>> {
+        let old_input = self.input_buffer.as_str();
         let edit = tree_sitter::InputEdit {
             start_byte: edit_pos,
             old_end_byte: old_end,
             new_end_byte: new_end,
-            start_position: tree_sitter::Point::new(0, edit_pos),
-            old_end_position: tree_sitter::Point::new(0, old_end),
-            new_end_position: tree_sitter::Point::new(0, new_end),
+            start_position: point_at(old_input, edit_pos),
+            old_end_position: point_at(old_input, old_end),
+            new_end_position: point_at(new_input, new_end),
         };
 
+        self.input_buffer
+            .update(new_input, edit_pos, old_end, new_end);
+        self.source = NamedSource::new("calculator", new_input.to_string());
+
         let tree = if let Some(old_tree) = &mut self.cache.last_tree {
             old_tree.edit(&edit);
             self.parser
                 .parse(self.input_buffer.as_str(), Some(old_tree))
         } else {
             self.parser.parse(self.input_buffer.as_str(), None)
-        }
-        .ok_or_else(|| CalculatorError {
-            src: self.source.clone(),
-            span: (0, new_input.len()).into(),
-            kind: CalcErrorKind::ParseError("Failed to parse input".to_string()),
-            help: Some("Make sure your expression is syntactically valid".to_string()),
-        })?;
-
-        self.cache.last_tree = Some(tree.clone());
-
+        };
+        let Some(tree) = tree else {
+            return vec![Err(CalculatorError {
+                src: self.source.clone(),
+                span: (0, new_input.len()).into(),
+                kind: CalcErrorKind::ParseError("Failed to parse input".to_string()),
+                help: Some("Make sure your expression is syntactically valid".to_string()),
+            }
+            .into())];
+        };
+
+        self.cache.last_tree = Some(tree.clone());
+
+        // Input the parser couldn't make sense of at all is one statement
+        let root = tree.root_node();
+        let statements: Vec<Node> = match root.kind() {
+            "source" => root
+                .named_children(&mut root.walk())
+                .filter(|statement| statement.kind() != "comment")
+                .collect(),
+            _ => vec![root],
+        };
+        statements
+            .into_iter()
+            .map(|statement| self.run_statement(new_input, statement))
+            .collect()
+    }
+
+    /// Evaluate one statement of the input, or define the function it
+    /// defines.
+    fn run_statement(&self, input: &str, statement: Node) -> MietteResult<CalcValue> {
         let mut errors = Vec::new();
-        collect_error_nodes(tree.root_node(), &mut errors);
+        collect_error_nodes(statement, &mut errors);
 
         if !errors.is_empty() {
             let error_node = &errors[0];
@@ -277,35 +1365,100 @@ impl Calculator {
                 error_node.end_byte() - error_node.start_byte(),
             );
             let error_message = error_node
-                .utf8_text(self.input_buffer.as_str().as_bytes())
+                .utf8_text(input.as_bytes())
                 .unwrap_or("Syntax error")
                 .to_string();
 
+            // Error recovery fills in a missing operand (as in `1 +`) with a
+            // zero-width number
+            let kind = match error_node.kind() {
+                "number" | "float" if error_node.is_missing() => {
+                    CalcErrorKind::NumberError("Expected a number".into())
+                }
+                _ => CalcErrorKind::ParseError(format!("Syntax error near '{}'", error_message)),
+            };
+
             return Err(CalculatorError {
                 src: self.source.clone(),
                 span: span.into(),
-                kind: CalcErrorKind::ParseError(format!("Syntax error near '{}'", error_message)),
+                kind,
                 help: Some("Ensure that your expression follows the correct syntax.".into()),
             }
             .into());
         }
 
-        let ast = self.node_to_expr(new_input, tree.root_node())?;
-        let ast_hash = self.hash_ast(&ast);
+        let ast = self.node_to_expr(input, statement)?;
+        if let Expr::FunctionDef { name, params, body } = ast {
+            return self.define_function(name, params, *body, input);
+        }
+
+        // Start over with big integers throughout when something overflowed
+        // (they can't), and with complex results when a function of a
+        // negative number needs one
+        let mut result = self.evaluate(input, &ast);
+        let mut promotion = Promotion::default();
+        let promote = self.environment.borrow().overflow() == OverflowMode::Promote;
+        loop {
+            let kind = match &result {
+                Err(report) => report.downcast_ref::<CalculatorError>().map(|e| &e.kind),
+                Ok(_) => None,
+            };
+            match kind {
+                Some(CalcErrorKind::Overflow) if !promotion.wide && promote => {
+                    promotion.wide = true
+                }
+                Some(CalcErrorKind::NotReal) if !promotion.complex => promotion.complex = true,
+                _ => break,
+            }
+            self.environment.borrow_mut().set_promotion(promotion);
+            result = self.evaluate(input, &ast);
+        }
+        self.environment
+            .borrow_mut()
+            .set_promotion(Promotion::default());
+        result
+    }
+
+    /// Compile `ast` (unless it is cached) and run it.
+    fn evaluate(&self, input: &str, ast: &Expr) -> MietteResult<CalcValue> {
+        let ast_hash = self.hash_ast(ast);
 
         if let Some(cached_fn) = self.cache.function_cache.get(&ast_hash) {
             let entry = cached_fn.value().clone();
             entry.update();
-            return Ok(unsafe { entry.call() });
+            return self.run(&entry, ast);
         }
 
-        let compiled_fn = self.compile_expr(new_input, ast)?;
-        let result = unsafe { compiled_fn.call() };
+        let compiled_fn = Arc::new(self.compile_expr(input, ast.clone())?);
         self.cache
             .function_cache
-            .insert(ast_hash, Arc::new(compiled_fn));
+            .insert(ast_hash, compiled_fn.clone());
         self.cleanup_cache();
 
+        self.run(&compiled_fn, ast)
+    }
+
+    /// Call a compiled expression, turning a trap it hit into an error on
+    /// `ast`, the expression being evaluated.
+    fn run(&self, compiled_fn: &CompiledFunction, ast: &Expr) -> MietteResult<CalcValue> {
+        let fault = {
+            let jit_module = self.jit_module.read();
+            let (ptr, _) = jit_module.get_finalized_data(self.environment.borrow().fault());
+            ptr as *mut i64
+        };
+
+        let (result, fired) = unsafe {
+            *fault = 0;
+            let result = compiled_fn.call();
+            (result, *fault)
+        };
+        {
+            let jit_module = self.jit_module.read();
+            self.environment.borrow_mut().collect_garbage(&jit_module);
+        }
+        if fired != 0 {
+            Err(self.trap_error(fired, compiled_fn, ast))?
+        }
         Ok(result)
     }
 
@@ -326,15 +1479,183 @@ impl Calculator {
                     help: Some("Check the expression syntax.".into()),
                 })?
             }
+            // Programs are evaluated a statement at a time by `update_program`
             "source" => {
-                let child = node.child(0).ok_or_else(|| CalculatorError {
+                let mut cursor = node.walk();
+                let mut statements = node
+                    .named_children(&mut cursor)
+                    .filter(|statement| statement.kind() != "comment");
+                let child = statements.next().ok_or_else(|| CalculatorError {
                     src: self.source.clone(),
                     span: (span.start, span.end - span.start).into(),
                     kind: CalcErrorKind::ParseError("Empty expression".into()),
                     help: Some("Expression cannot be empty".into()),
                 })?;
+                if let Some(next) = statements.next() {
+                    Err(CalculatorError {
+                        src: self.source.clone(),
+                        span: (next.start_byte(), next.end_byte() - next.start_byte()).into(),
+                        kind: CalcErrorKind::ParseError("Expected a single statement".into()),
+                        help: Some("Evaluate programs with `update_program`".into()),
+                    })?
+                }
                 self.node_to_expr(input, child)
             }
+            "assignment" => {
+                let name_node = node.child_by_field_name("name").ok_or_else(|| CalculatorError {
+                    src: self.source.clone(),
+                    span: (span.start, span.end - span.start).into(),
+                    kind: CalcErrorKind::ParseError("Missing assignment target".into()),
+                    help: Some("Assignments look like `x = 1`".into()),
+                })?;
+                let name = name_node.utf8_text(input.as_bytes()).unwrap_or_default();
+                self.check_not_constant(
+                    name,
+                    (name_node.start_byte(), name_node.end_byte() - name_node.start_byte()).into(),
+                )?;
+                let value = node
+                    .child_by_field_name("value")
+                    .ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError("Missing assigned value".into()),
+                        help: Some("Assignments look like `x = 1`".into()),
+                    })?;
+                let value_expr = self.node_to_expr(input, value)?;
+
+                Ok(Expr::Assign {
+                    name: name.to_string(),
+                    value: Box::new(value_expr),
+                })
+            }
+            "function_definition" => {
+                let signature = node
+                    .child_by_field_name("signature")
+                    .ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError("Missing function signature".into()),
+                        help: Some("Definitions look like `f(x, y) = x * y`".into()),
+                    })?;
+                let name = signature
+                    .child_by_field_name("function")
+                    .and_then(|n| n.utf8_text(input.as_bytes()).ok())
+                    .ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError("Missing function name".into()),
+                        help: Some("Definitions look like `f(x, y) = x * y`".into()),
+                    })?;
+
+                let mut params: Vec<String> = Vec::new();
+                let mut cursor = signature.walk();
+                for argument in signature.children_by_field_name("argument", &mut cursor) {
+                    let arg_span = (argument.start_byte(), argument.end_byte() - argument.start_byte());
+                    let param = argument
+                        .named_child(0)
+                        .filter(|n| n.kind() == "identifier" && argument.named_child_count() == 1)
+                        .and_then(|n| n.utf8_text(input.as_bytes()).ok())
+                        .ok_or_else(|| CalculatorError {
+                            src: self.source.clone(),
+                            span: arg_span.into(),
+                            kind: CalcErrorKind::ParseError("Function parameters must be names".into()),
+                            help: Some("Definitions look like `f(x, y) = x * y`".into()),
+                        })?;
+                    self.check_not_constant(param, arg_span.into())?;
+                    if params.iter().any(|p| p == param) {
+                        Err(CalculatorError {
+                            src: self.source.clone(),
+                            span: arg_span.into(),
+                            kind: CalcErrorKind::ParseError(format!("Duplicate parameter '{}'", param)),
+                            help: Some("Give each parameter a different name".into()),
+                        })?
+                    }
+                    params.push(param.to_string());
+                }
+
+                let body = node
+                    .child_by_field_name("body")
+                    .ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError("Missing function body".into()),
+                        help: Some("Definitions look like `f(x, y) = x * y`".into()),
+                    })?;
+                let body_expr = self.node_to_expr(input, body)?;
+
+                Ok(Expr::FunctionDef {
+                    name: name.to_string(),
+                    params,
+                    body: Box::new(body_expr),
+                })
+            }
+            "call_expression" => {
+                let name = node
+                    .child_by_field_name("function")
+                    .and_then(|n| n.utf8_text(input.as_bytes()).ok())
+                    .ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError("Missing function name".into()),
+                        help: Some("Calls look like `f(1, 2)`".into()),
+                    })?;
+                let mut cursor = node.walk();
+                let args = node
+                    .children_by_field_name("argument", &mut cursor)
+                    .map(|argument| self.node_to_expr(input, argument))
+                    .collect::<MietteResult<Vec<_>>>()?;
+
+                Ok(Expr::Call {
+                    name: name.to_string(),
+                    args,
+                    span: (span.start, span.end - span.start).into(),
+                })
+            }
+            "aggregate" => {
+                let part = |field: &str| -> MietteResult<Node> {
+                    Ok(node.child_by_field_name(field).ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError(format!("Missing {}", field)),
+                        help: Some("Sums look like `sum(i = 1..10, i * i)`".into()),
+                    })?)
+                };
+                let function = part("function")?;
+                let kind = match function.utf8_text(input.as_bytes()).unwrap_or_default() {
+                    "prod" => AggregateKind::Product,
+                    _ => AggregateKind::Sum,
+                };
+                let variable_node = part("variable")?;
+                let variable = variable_node
+                    .utf8_text(input.as_bytes())
+                    .unwrap_or_default()
+                    .to_string();
+                self.check_not_constant(
+                    &variable,
+                    (
+                        variable_node.start_byte(),
+                        variable_node.end_byte() - variable_node.start_byte(),
+                    )
+                        .into(),
+                )?;
+                let (start, end, body) = (part("start")?, part("end")?, part("body")?);
+
+                let node_span = |n: Node| (n.start_byte(), n.end_byte() - n.start_byte()).into();
+                Ok(Expr::Aggregate {
+                    kind,
+                    variable,
+                    start: Box::new(self.node_to_expr(input, start)?),
+                    end: Box::new(self.node_to_expr(input, end)?),
+                    body: Box::new(self.node_to_expr(input, body)?),
+                    spans: AggregateSpans {
+                        function: node_span(function),
+                        start: node_span(start),
+                        end: node_span(end),
+                        body: node_span(body),
+                    },
+                })
+            }
+            "identifier" => Ok(name_expr(node_text, (span.start, span.end - span.start).into())),
             "expression" => {
                 let child = node.child(0).ok_or_else(|| CalculatorError {
                     src: self.source.clone(),
@@ -357,29 +1678,230 @@ impl Calculator {
                 let inner_expr = self.node_to_expr(input, inner)?;
                 Ok(Expr::Parenthesized(Box::new(inner_expr)))
             }
-            "number" => {
-                if let Ok(expr) = node_text.parse().map(Expr::Integer) {
-                    Ok(expr)
-                } else {
-                    Err(CalculatorError {
+            "number" | "float" | "imaginary" | "decimal" => self.parse_literal(input, node, false),
+            "quantity" => {
+                let part = |field: &str| -> MietteResult<Node> {
+                    Ok(node.child_by_field_name(field).ok_or_else(|| CalculatorError {
                         src: self.source.clone(),
                         span: (span.start, span.end - span.start).into(),
-                        kind: CalcErrorKind::NumberError("Failed to parse integer".into()),
-                        help: Some("Make sure the number is a valid integer".into()),
-                    })?
+                        kind: CalcErrorKind::ParseError(format!("Missing {}", field)),
+                        help: Some("Quantities look like `3 km` or `4 m^2`".into()),
+                    })?)
+                };
+                let (value, unit) = (part("value")?, part("unit")?);
+                let literal = self.parse_literal(input, value, false)?;
+                let text = unit.utf8_text(input.as_bytes()).unwrap_or_default();
+                let (name, exponent) = match text.split_once('^') {
+                    Some((name, exponent)) => (name, Some(exponent)),
+                    None => (text, None),
+                };
+                // Any other name is a variable being multiplied, as in `2x`
+                if Unit::named(name).is_none() {
+                    let variable = name_expr(name, (unit.start_byte(), name.len()).into());
+                    let exponent = match exponent.map(|exponent| (exponent, exponent.parse())) {
+                        Some((_, Ok(exponent))) => Some(exponent),
+                        Some((exponent, Err(_))) => Err(CalculatorError {
+                            src: self.source.clone(),
+                            span: (unit.start_byte(), text.len()).into(),
+                            kind: CalcErrorKind::NumberError(format!(
+                                "exponent `{}` is too large",
+                                exponent
+                            )),
+                            help: Some("Write a larger power as `x ^ (...)`".into()),
+                        })?,
+                        None => None,
+                    };
+                    let right = match exponent {
+                        Some(exponent) => Expr::BinaryOp {
+                            left: Box::new(variable),
+                            op: BinaryOpKind::Power,
+                            right: Box::new(Expr::Integer(exponent)),
+                            spans: BinarySpans {
+                                left: (unit.start_byte(), name.len()).into(),
+                                operator: (unit.start_byte() + name.len(), 1).into(),
+                                right: (
+                                    unit.start_byte() + name.len() + 1,
+                                    text.len() - name.len() - 1,
+                                )
+                                    .into(),
+                            },
+                        },
+                        None => variable,
+                    };
+                    return Ok(implicit_product(literal, right, value, unit));
                 }
+                let value = match literal {
+                    Expr::Integer(n) => n as f64,
+                    Expr::BigInt(n) => n.to_f64().unwrap_or(f64::INFINITY),
+                    Expr::Float(x) => x,
+                    Expr::Sized(n) => Err(CalculatorError {
+                        src: self.source.clone(),
+                        span: (value.start_byte(), value.end_byte() - value.start_byte()).into(),
+                        kind: CalcErrorKind::TypeMismatch(format!(
+                            "a `{}` can't have a unit",
+                            n.ty
+                        )),
+                        help: Some("Quantities are floats, so leave out the suffix".into()),
+                    })?,
+                    _ => unreachable!("the grammar only puts numbers before units"),
+                };
+                let unit = self.node_to_unit(input, unit)?;
+                Ok(Expr::Quantity { value, unit })
             }
-            "float" => {
-                if let Ok(expr) = node_text.parse().map(Expr::Float) {
-                    Ok(expr)
-                } else {
-                    Err(CalculatorError {
+            "implicit_multiplication" => {
+                let part = |field: &str| -> MietteResult<Node> {
+                    Ok(node.child_by_field_name(field).ok_or_else(|| CalculatorError {
                         src: self.source.clone(),
                         span: (span.start, span.end - span.start).into(),
-                        kind: CalcErrorKind::NumberError("Failed to parse float".into()),
-                        help: Some("Make sure the number is a valid floating point number".into()),
-                    })?
+                        kind: CalcErrorKind::ParseError(format!("Missing {} operand", field)),
+                        help: Some("Products can be written `2(3 + 4)` or `(a)(b)`".into()),
+                    })?)
+                };
+                let (left, right) = (part("left")?, part("right")?);
+                let left_expr = self.node_to_expr(input, left)?;
+                let right_expr = self.node_to_expr(input, right)?;
+                Ok(implicit_product(left_expr, right_expr, left, right))
+            }
+            "conversion" => {
+                let part = |field: &str| -> MietteResult<Node> {
+                    Ok(node.child_by_field_name(field).ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError(format!("Missing {}", field)),
+                        help: Some("Conversions look like `3 km / 20 min in km/h`".into()),
+                    })?)
+                };
+                let operator = part("operator")?;
+                Ok(Expr::Convert {
+                    value: Box::new(self.node_to_expr(input, part("value")?)?),
+                    unit: self.node_to_unit(input, part("unit")?)?,
+                    span: (operator.start_byte(), operator.end_byte() - operator.start_byte())
+                        .into(),
+                })
+            }
+            "cast" => {
+                let part = |field: &str| -> MietteResult<Node> {
+                    Ok(node.child_by_field_name(field).ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError(format!("Missing {}", field)),
+                        help: Some("Casts look like `x as int` or `x as float`".into()),
+                    })?)
+                };
+                let operator = part("operator")?;
+                let type_node = part("type")?;
+                let name = type_node.utf8_text(input.as_bytes()).unwrap_or_default();
+                let target = CastType::from_name(name).ok_or_else(|| CalculatorError {
+                    src: self.source.clone(),
+                    span: (
+                        type_node.start_byte(),
+                        type_node.end_byte() - type_node.start_byte(),
+                    )
+                        .into(),
+                    kind: CalcErrorKind::ParseError(format!("`{}` is not a type", name)),
+                    help: Some(match did_you_mean(name, CastType::all().map(CastType::name)) {
+                        Some(suggestion) => format!("Did you mean `{}`?", suggestion),
+                        None => "Values can be cast to `int`, `float` or a sized integer type such as `u8`".into(),
+                    }),
+                })?;
+                Ok(Expr::Cast {
+                    value: Box::new(self.node_to_expr(input, part("value")?)?),
+                    target,
+                    span: (
+                        operator.start_byte(),
+                        type_node.end_byte() - operator.start_byte(),
+                    )
+                        .into(),
+                })
+            }
+            "percent" => {
+                let value = node.child_by_field_name("value").ok_or_else(|| CalculatorError {
+                    src: self.source.clone(),
+                    span: (span.start, span.end - span.start).into(),
+                    kind: CalcErrorKind::ParseError("Missing percentage".into()),
+                    help: Some("Percentages look like `50%`".into()),
+                })?;
+                let value_expr = self.node_to_expr(input, value)?;
+                Ok(Expr::Percent {
+                    meaning: Box::new(per_hundred(value_expr, value, node)),
+                    form: PercentForm::Of,
+                })
+            }
+            "boolean" => Ok(Expr::Boolean(node_text == "true")),
+            "unary_expression" => {
+                let op_node = node
+                    .child_by_field_name("operator")
+                    .ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError("Missing operator".into()),
+                        help: Some("Unary expression must have an operator".into()),
+                    })?;
+                let op = match op_node.utf8_text(input.as_bytes()).unwrap_or_default() {
+                    "-" => UnaryOpKind::Negate,
+                    "+" => UnaryOpKind::Plus,
+                    "~" => UnaryOpKind::BitNot,
+                    "!" => UnaryOpKind::Not,
+                    op_text => Err(CalculatorError {
+                        src: self.source.clone(),
+                        span: (op_node.start_byte(), op_node.end_byte() - op_node.start_byte())
+                            .into(),
+                        kind: CalcErrorKind::InvalidOperator(op_text.to_string()),
+                        help: Some("Only -, +, ~ and ! can be used as prefix operators".into()),
+                    })?,
+                };
+
+                let operand = node
+                    .child_by_field_name("operand")
+                    .ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError("Missing operand".into()),
+                        help: Some("Unary expression must have an operand".into()),
+                    })?;
+
+                // Negative literals stay literals, which keeps `-9223372036854775808` in range
+                if let Some(literal) = operand
+                    .named_child(0)
+                    .filter(|n| {
+                        op == UnaryOpKind::Negate
+                            && matches!(n.kind(), "number" | "float" | "imaginary" | "decimal")
+                    })
+                {
+                    return self.parse_literal(input, literal, true);
                 }
+
+                let operand_expr = self.node_to_expr(input, operand)?;
+                Ok(Expr::Unary {
+                    op,
+                    operand: Box::new(operand_expr),
+                    span: (op_node.start_byte(), op_node.end_byte() - op_node.start_byte()).into(),
+                })
+            }
+            "conditional_expression" => {
+                let part = |field: &str| -> MietteResult<Node> {
+                    Ok(node.child_by_field_name(field).ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.start, span.end - span.start).into(),
+                        kind: CalcErrorKind::ParseError(format!("Missing {}", field)),
+                        help: Some("Conditionals look like `if x > 0 then x else -x`".into()),
+                    })?)
+                };
+                let condition = part("condition")?;
+                let consequence = part("consequence")?;
+                let alternative = part("alternative")?;
+
+                let node_span = |n: Node| (n.start_byte(), n.end_byte() - n.start_byte()).into();
+                Ok(Expr::Conditional {
+                    condition: Box::new(self.node_to_expr(input, condition)?),
+                    consequence: Box::new(self.node_to_expr(input, consequence)?),
+                    alternative: Box::new(self.node_to_expr(input, alternative)?),
+                    spans: ConditionalSpans {
+                        condition: node_span(condition),
+                        consequence: node_span(consequence),
+                        alternative: node_span(alternative),
+                    },
+                })
             }
             "binary_expression" => {
                 let left = node
@@ -401,6 +1923,22 @@ impl Calculator {
                         "-" => Ok(BinaryOpKind::Subtract),
                         "*" => Ok(BinaryOpKind::Multiply),
                         "/" => Ok(BinaryOpKind::Divide),
+                        "//" => Ok(BinaryOpKind::FloorDivide),
+                        "^" | "**" => Ok(BinaryOpKind::Power),
+                        "%" => Ok(BinaryOpKind::Modulo),
+                        "&" => Ok(BinaryOpKind::BitAnd),
+                        "|" => Ok(BinaryOpKind::BitOr),
+                        "xor" => Ok(BinaryOpKind::BitXor),
+                        "<<" => Ok(BinaryOpKind::ShiftLeft),
+                        ">>" => Ok(BinaryOpKind::ShiftRight),
+                        "==" => Ok(BinaryOpKind::Equal),
+                        "!=" => Ok(BinaryOpKind::NotEqual),
+                        "<" => Ok(BinaryOpKind::Less),
+                        "<=" => Ok(BinaryOpKind::LessEqual),
+                        ">" => Ok(BinaryOpKind::Greater),
+                        ">=" => Ok(BinaryOpKind::GreaterEqual),
+                        "&&" => Ok(BinaryOpKind::And),
+                        "||" => Ok(BinaryOpKind::Or),
                         _ => {
                             let op_node = node.child_by_field_name("operator")
                                 .expect("Operator could not be found.");
@@ -409,7 +1947,7 @@ impl Calculator {
                                 src: self.source.clone(),
                                 span: (op_span.start, op_span.end - op_span.start).into(),
                                 kind: CalcErrorKind::InvalidOperator(op_text.to_string()),
-                                help: Some("Only +, -, *, and / operators are supported".into()),
+                                help: Some("Only arithmetic (+ - * / // % ^), bitwise (& | xor << >>), comparison (== != < <= > >=) and logical (&& ||) operators are supported".into()),
                             })
                         },
                     }
@@ -430,34 +1968,199 @@ impl Calculator {
                         kind: CalcErrorKind::ParseError("Missing right operand".into()),
                         help: Some("Binary expression must have a right operand".into()),
                     })?;
+
+                let node_span = |n: Node| (n.start_byte(), n.end_byte() - n.start_byte()).into();
+                let spans = BinarySpans {
+                    left: node_span(left),
+                    operator: node
+                        .child_by_field_name("operator")
+                        .map(node_span)
+                        .unwrap_or_else(|| node_span(node)),
+                    right: node_span(right),
+                };
+
+                // `a + b%` and `a - b%` change `a` by `b` percent, as on a
+                // handheld calculator
+                let percent = right.child(0).filter(|child| child.kind() == "percent");
+                let form = match op {
+                    BinaryOpKind::Add => Some(PercentForm::Increase),
+                    BinaryOpKind::Subtract => Some(PercentForm::Decrease),
+                    _ => None,
+                };
+                if let (Some(percent), Some(form)) = (percent, form) {
+                    let value = percent.child_by_field_name("value").ok_or_else(|| CalculatorError {
+                        src: self.source.clone(),
+                        span: node_span(percent),
+                        kind: CalcErrorKind::ParseError("Missing percentage".into()),
+                        help: Some("Percentages look like `50%`".into()),
+                    })?;
+                    // Dividing last keeps `200 + 10%` exactly 220
+                    let factor = Expr::BinaryOp {
+                        left: Box::new(Expr::Integer(100)),
+                        op,
+                        right: Box::new(self.node_to_expr(input, value)?),
+                        spans: BinarySpans {
+                            left: spans.operator,
+                            right: node_span(value),
+                            ..spans
+                        },
+                    };
+                    let product = Expr::BinaryOp {
+                        left: Box::new(left_expr),
+                        op: BinaryOpKind::Multiply,
+                        right: Box::new(factor),
+                        spans,
+                    };
+                    return Ok(Expr::Percent {
+                        meaning: Box::new(per_hundred(product, node, percent)),
+                        form,
+                    });
+                }
                 let right_expr = self.node_to_expr(input, right)?;
 
                 Ok(Expr::BinaryOp {
                     left: Box::new(left_expr),
                     op,
                     right: Box::new(right_expr),
+                    spans,
                 })
             }
             _ => Err(CalculatorError {
                 src: self.source.clone(),
                 span: (span.start, span.end - span.start).into(),
                 kind: CalcErrorKind::ParseError(format!("Unexpected node type '{}'", node.kind())),
-                help: Some("Expression must be a number, float, or binary operation".into()),
+                help: Some("Expression must be a number, float, boolean, variable, function call, conditional, or binary operation".into()),
+            })?,
+        }
+    }
+
+    /// Parse a `number` (with or without a type suffix), `float`, `imaginary`
+    /// or `decimal` node, pointing errors at the offending part of the
+    /// literal.
+    fn parse_literal(&self, input: &str, literal: Node, negative: bool) -> MietteResult<Expr> {
+        let text = literal.utf8_text(input.as_bytes()).unwrap_or_default();
+        let parsed = match literal.kind() {
+            "number" => match parse_sized(text, negative) {
+                Some(sized) => sized.map(Expr::Sized),
+                None => parse_integer(text, negative).map(|n| match i64::try_from(&n) {
+                    Ok(n) => Expr::Integer(n),
+                    Err(_) => Expr::BigInt(n),
+                }),
+            },
+            // The digits before the `i` read like any float
+            "imaginary" => parse_float(&text[..text.len() - 1], negative).map(Expr::Imaginary),
+            "decimal" => parse_decimal(text, negative).map(|(mantissa, places)| Expr::Decimal {
+                mantissa,
+                places,
+                span: (literal.start_byte(), text.len()).into(),
+            }),
+            _ => parse_float(text, negative).map(Expr::Float),
+        };
+        match parsed {
+            Ok(expr) => Ok(expr),
+            Err(error) => Err(CalculatorError {
+                src: self.source.clone(),
+                span: (literal.start_byte() + error.offset, error.len).into(),
+                kind: CalcErrorKind::NumberError(error.message),
+                help: Some(error.help),
             })?,
         }
     }
 
+    /// Read the unit after a number or `in`, e.g. `km`, `m^2` or `m/s^2`.
+    fn node_to_unit(&self, input: &str, node: Node) -> MietteResult<Unit> {
+        let span: SourceSpan = (node.start_byte(), node.end_byte() - node.start_byte()).into();
+        let text = node.utf8_text(input.as_bytes()).unwrap_or_default();
+        let not_a_unit = || CalculatorError {
+            src: self.source.clone(),
+            span,
+            kind: CalcErrorKind::UnknownUnit(text.to_string()),
+            help: Some("Units look like `km/h` or `m/s^2`".into()),
+        };
+
+        let unit = match node.kind() {
+            "expression" => self.node_to_unit(input, node.child(0).ok_or_else(not_a_unit)?)?,
+            "parenthesized_expression" => {
+                let inner = node.child_by_field_name("inner").ok_or_else(not_a_unit)?;
+                self.node_to_unit(input, inner)?
+            }
+            "unit" | "identifier" => {
+                let (name, exponent) = match text.split_once('^') {
+                    Some((name, exponent)) => (name, Some(exponent)),
+                    None => (text, None),
+                };
+                let unit = Unit::named(name).ok_or_else(|| CalculatorError {
+                    src: self.source.clone(),
+                    span: (span.offset(), name.len()).into(),
+                    kind: CalcErrorKind::UnknownUnit(name.to_string()),
+                    help: Some(match did_you_mean(name, Unit::names()) {
+                        Some(suggestion) => format!("Did you mean `{}`?", suggestion),
+                        None => format!(
+                            "Known units are {}",
+                            Unit::names().collect::<Vec<_>>().join(", ")
+                        ),
+                    }),
+                })?;
+                match exponent.map(|exponent| (exponent, exponent.parse())) {
+                    Some((_, Ok(exponent))) => unit.pow(exponent).ok_or_else(not_a_unit)?,
+                    Some((exponent, Err(_))) => Err(CalculatorError {
+                        src: self.source.clone(),
+                        span,
+                        kind: CalcErrorKind::NumberError(format!(
+                            "exponent `{}` is too large",
+                            exponent
+                        )),
+                        help: Some("Units take small whole number powers, as in `m^2`".into()),
+                    })?,
+                    None => unit,
+                }
+            }
+            "binary_expression" => {
+                let field = |name: &str| -> MietteResult<Node> {
+                    Ok(node.child_by_field_name(name).ok_or_else(not_a_unit)?)
+                };
+                let left = self.node_to_unit(input, field("left")?)?;
+                let right = field("right")?;
+                let unit = match field("operator")?.utf8_text(input.as_bytes()) {
+                    Ok("*") => left.mul(&self.node_to_unit(input, right)?),
+                    Ok("/") => left.div(&self.node_to_unit(input, right)?),
+                    Ok("^" | "**") => right
+                        .utf8_text(input.as_bytes())
+                        .ok()
+                        .and_then(|exponent| exponent.replace(' ', "").parse().ok())
+                        .and_then(|exponent| left.pow(exponent)),
+                    _ => None,
+                };
+                unit.ok_or_else(not_a_unit)?
+            }
+            _ => Err(not_a_unit())?,
+        };
+        if unit.is_dimensionless() {
+            Err(not_a_unit())?
+        }
+        Ok(unit)
+    }
+
     pub fn compile_expr(&self, input: &str, expr: Expr) -> MietteResult<CompiledFunction> {
         let mut jit_module = self.jit_module.write();
         let mut ctx = jit_module.make_context();
 
-        let (return_type, is_float) = self.determine_type(&expr)?;
-        let signature_type = if is_float { types::F64 } else { types::I64 };
-
+        let (return_type, _) = self.determine_type(&expr)?;
+        // Sites left behind by code that failed to compile
+        self.environment.borrow_mut().take_trap_sites(0);
+
+        // Complex results come back as two floats, laid out like `Complex64`,
+        // and decimals and sized integers (widened to 128 bits) as two
+        // integers, laid out like `decimal::Parts`
+        let returns = match return_type {
+            CalcValue::Complex(_) => vec![types::F64; 2],
+            CalcValue::Decimal(_) | CalcValue::Sized(_) => vec![types::I64; 2],
+            _ => vec![ir_type(&return_type)],
+        };
         ctx.func
             .signature
             .returns
-            .push(AbiParam::new(signature_type));
+            .extend(returns.into_iter().map(AbiParam::new));
 
         let mut builder_context = self.builder_context.lock();
         let mut func_builder = FunctionBuilder::new(&mut ctx.func, &mut *builder_context);
@@ -467,8 +2170,29 @@ impl Calculator {
         func_builder.switch_to_block(entry_block);
         func_builder.seal_block(entry_block);
 
-        let (_, result) = self.compile_node(input, &mut func_builder, &expr)?;
-        func_builder.ins().return_(&[result]);
+        let (_, result) = self.compile_node(
+            input,
+            &mut jit_module,
+            &mut func_builder,
+            &Scope::new(),
+            &expr,
+        )?;
+        match return_type {
+            CalcValue::Complex(_) => {
+                let parts = split(&mut func_builder, result);
+                func_builder.ins().return_(&parts)
+            }
+            CalcValue::Decimal(_) => {
+                let (low, high) = func_builder.ins().isplit(result);
+                func_builder.ins().return_(&[low, high])
+            }
+            CalcValue::Sized(n) => {
+                let result = widen(&mut func_builder, n.ty, result);
+                let (low, high) = func_builder.ins().isplit(result);
+                func_builder.ins().return_(&[low, high])
+            }
+            _ => func_builder.ins().return_(&[result]),
+        };
         func_builder.finalize();
 
         let id = jit_module
@@ -504,91 +2228,3018 @@ impl Calculator {
 
         let fn_ptr = jit_module.get_finalized_function(id);
 
-        Ok(match return_type {
-            CalcValue::Integer(_) => {
-                CompiledFunction::new_int(unsafe { std::mem::transmute(fn_ptr) })
-            }
-            CalcValue::Float(_) => {
-                CompiledFunction::new_float(unsafe { std::mem::transmute(fn_ptr) })
-            }
-        })
+        let mut compiled =
+            CompiledFunction::new(unsafe { CompiledFnPtr::new(fn_ptr, return_type) });
+        collect_spans(&expr, &mut compiled.spans);
+        compiled.trap_sites = self.environment.borrow_mut().take_trap_sites(0);
+        Ok(compiled)
     }
 
-    fn determine_type(&self, expr: &Expr) -> MietteResult<(CalcValue, bool)> {
-        Ok(match expr {
-            Expr::Integer(n) => (CalcValue::Integer(*n), false),
-            Expr::Float(x) => (CalcValue::Float(*x), true),
-            Expr::BinaryOp { left, op, right } => {
-                let (_left_type, left_float) = self.determine_type(left)?;
-                let (_right_type, right_float) = self.determine_type(right)?;
-                if left_float || right_float || *op == BinaryOpKind::Divide {
-                    (CalcValue::Float(0.0), true)
-                } else {
-                    (CalcValue::Integer(0), false)
+    /// Store a function definition once its body type-checks (with integer
+    /// arguments), keeping the previous definition otherwise.
+    fn define_function(
+        &self,
+        name: String,
+        params: Vec<String>,
+        body: Expr,
+        input: &str,
+    ) -> MietteResult<CalcValue> {
+        let signature = format!("{}({})", name, params.join(", "));
+        // Defining it again as it was, e.g. when only a comment changed,
+        // keeps the code compiled for it and everything calling it
+        let structure = |expr: &Expr| {
+            let mut hasher = AHasher::default();
+            expr.hash(&mut hasher);
+            hasher.finish()
+        };
+        let unchanged = self
+            .environment
+            .borrow()
+            .function(&name)
+            .is_some_and(|function| {
+                function.params == params && structure(&function.body) == structure(&body)
+            });
+        if unchanged {
+            return Ok(CalcValue::Function(signature));
+        }
+        let arg_types = vec![CalcValue::Integer(0); params.len()];
+        let previous = self.environment.borrow_mut().define_function(
+            &name,
+            params.clone(),
+            body.clone(),
+            input.to_string(),
+        );
+
+        if let Err(error) = self.return_type(&name, &params, &body, &arg_types) {
+            self.environment
+                .borrow_mut()
+                .restore_function(&name, previous);
+            return Err(error);
+        }
+        Ok(CalcValue::Function(signature))
+    }
+
+    /// Look up the function a call refers to, checking the argument count.
+    fn callee(&self, name: &str, arg_count: usize, span: SourceSpan) -> MietteResult<Callee> {
+        let environment = self.environment.borrow();
+        let (callee, params) = match environment.function(name) {
+            Some(function) => (
+                Callee::User {
+                    params: function.params.clone(),
+                    body: function.body.clone(),
+                },
+                function.params.clone(),
+            ),
+            None => match Builtin::from_name(name) {
+                Some(builtin) => (
+                    Callee::Builtin(builtin),
+                    builtin.params().iter().map(|p| p.to_string()).collect(),
+                ),
+                None => {
+                    let candidates = Builtin::ALL
+                        .iter()
+                        .map(|builtin| builtin.name())
+                        .chain(environment.function_names());
+                    let help = match did_you_mean(name, candidates) {
+                        Some(suggestion) => format!("Did you mean `{}`?", suggestion),
+                        None => format!("Define it first, e.g. `{}(x) = x * 2`", name),
+                    };
+                    Err(CalculatorError {
+                        src: self.source.clone(),
+                        span: (span.offset(), name.len()).into(),
+                        kind: CalcErrorKind::UndefinedFunction(name.to_string()),
+                        help: Some(help),
+                    })?
                 }
-            }
-            Expr::Parenthesized(inner) => self.determine_type(inner)?,
-        })
+            },
+        };
+
+        if params.len() != arg_count {
+            Err(CalculatorError {
+                src: self.source.clone(),
+                span,
+                kind: CalcErrorKind::ArityMismatch {
+                    name: name.to_string(),
+                    expected: params.len(),
+                    found: arg_count,
+                },
+                help: Some(format!("Call it as `{}({})`", name, params.join(", "))),
+            })?
+        }
+        Ok(callee)
     }
 
-    fn compile_node(
+    /// Infer what `name` returns for `arg_types`. Recursive calls see the
+    /// type assumed so far, which starts as integer (or failing that, boolean)
+    /// and is widened until the body agrees with it.
+    fn return_type(
         &self,
-        input: &str,
-        builder: &mut FunctionBuilder,
-        expr: &Expr,
-    ) -> MietteResult<(CalcValue, cranelift::prelude::Value)> {
-        match expr {
-            Expr::Integer(n) => {
-                let v = builder.ins().iconst(types::I64, *n);
-                Ok((CalcValue::Integer(*n), v))
-            }
-            Expr::Float(x) => {
-                let v = builder.ins().f64const(*x);
-                Ok((CalcValue::Float(*x), v))
+        name: &str,
+        params: &[String],
+        body: &Expr,
+        arg_types: &[CalcValue],
+    ) -> MietteResult<CalcValue> {
+        let known = self.environment.borrow().specialization(name, arg_types);
+        if let Some(specialization) = known {
+            return Ok(specialization.return_type);
+        }
+
+        let scope: Scope<CalcValue> = params
+            .iter()
+            .cloned()
+            .zip(arg_types.iter().cloned())
+            .collect();
+        let infer = |mut assumed: CalcValue| loop {
+            self.environment.borrow_mut().set_specialization(
+                name,
+                arg_types,
+                Some(Specialization {
+                    func_id: None,
+                    return_type: assumed.clone(),
+                }),
+            );
+            let result = self.determine_type_in(body, &scope);
+            self.environment
+                .borrow_mut()
+                .set_specialization(name, arg_types, None);
+
+            let (body_type, _) = result?;
+            if std::mem::discriminant(&body_type) == std::mem::discriminant(&assumed) {
+                return Ok(body_type);
             }
-            Expr::BinaryOp { left, op, right } => {
-                let (left_val, left_ir) = self.compile_node(input, builder, left)?;
-                let (right_val, right_ir) = self.compile_node(input, builder, right)?;
+            assumed = body_type;
+        };
 
-                let needs_float = matches!(op, BinaryOpKind::Divide)
-                    || matches!(&left_val, CalcValue::Float(_))
-                    || matches!(&right_val, CalcValue::Float(_));
+        // Recursion that only type-checks as a boolean, e.g. `even(n) = n == 0
+        // || !even(n - 1)`, needs to start from that assumption instead
+        infer(CalcValue::Integer(0))
+            .or_else(|error| infer(CalcValue::Bool(false)).map_err(|_| error))
+    }
 
-                let (final_left, final_right) = if needs_float {
-                    let float_left = match &left_val {
-                        CalcValue::Integer(_) => builder.ins().fcvt_from_sint(types::F64, left_ir),
-                        CalcValue::Float(_) => left_ir,
-                    };
-                    let float_right = match &right_val {
-                        CalcValue::Integer(_) => builder.ins().fcvt_from_sint(types::F64, right_ir),
-                        CalcValue::Float(_) => right_ir,
-                    };
-                    (float_left, float_right)
-                } else {
-                    (left_ir, right_ir)
-                };
+    /// Get the version of `name` compiled for `arg_types`, compiling it into
+    /// its own function in the JIT module on first use.
+    fn specialize(
+        &self,
+        module: &mut JITModule,
+        name: &str,
+        params: &[String],
+        body: &Expr,
+        arg_types: &[CalcValue],
+    ) -> MietteResult<(FuncId, CalcValue)> {
+        let known = self.environment.borrow().specialization(name, arg_types);
+        if let Some(Specialization {
+            func_id: Some(func_id),
+            return_type,
+        }) = known
+        {
+            return Ok((func_id, return_type));
+        }
 
-                let result = match (op, needs_float) {
-                    (BinaryOpKind::Add, false) => builder.ins().iadd(final_left, final_right),
-                    (BinaryOpKind::Subtract, false) => builder.ins().isub(final_left, final_right),
-                    (BinaryOpKind::Multiply, false) => builder.ins().imul(final_left, final_right),
-                    (BinaryOpKind::Add, true) => builder.ins().fadd(final_left, final_right),
-                    (BinaryOpKind::Subtract, true) => builder.ins().fsub(final_left, final_right),
-                    (BinaryOpKind::Multiply, true) => builder.ins().fmul(final_left, final_right),
-                    (BinaryOpKind::Divide, _) => builder.ins().fdiv(final_left, final_right),
-                };
+        let return_type = self.return_type(name, params, body, arg_types)?;
+        let input = self
+            .environment
+            .borrow()
+            .function(name)
+            .map(|function| function.source.clone())
+            .unwrap_or_default();
+        let mut signature = module.make_signature();
+        signature
+            .params
+            .extend(arg_types.iter().map(|ty| AbiParam::new(ir_type(ty))));
+        signature.returns.push(AbiParam::new(ir_type(&return_type)));
+
+        let func_id = module
+            .declare_function(
+                &format!("fn_{}_{}", name, fastrand::u64(..)),
+                Linkage::Local,
+                &signature,
+            )
+            .map_err(|e| CalculatorError {
+                src: self.source.clone(),
+                span: (0, 0).into(),
+                kind: CalcErrorKind::JitError(e.to_string()),
+                help: None,
+            })?;
 
-                Ok((
-                    if needs_float {
-                        CalcValue::Float(0.0)
-                    } else {
-                        CalcValue::Integer(0)
-                    },
-                    result,
-                ))
-            }
-            Expr::Parenthesized(inner) => self.compile_node(input, builder, inner),
+        // Registered before the body is compiled so recursive calls find it
+        self.environment.borrow_mut().set_specialization(
+            name,
+            arg_types,
+            Some(Specialization {
+                func_id: Some(func_id),
+                return_type: return_type.clone(),
+            }),
+        );
+
+        let pending = self.environment.borrow().pending_trap_sites();
+        let result = (|| -> MietteResult<()> {
+            let mut ctx = module.make_context();
+            ctx.func.signature = signature;
+
+            // The shared builder context is still in use by the caller
+            let mut builder_context = FunctionBuilderContext::new();
+            let mut func_builder = FunctionBuilder::new(&mut ctx.func, &mut builder_context);
+
+            let entry_block = func_builder.create_block();
+            func_builder.append_block_params_for_function_params(entry_block);
+            func_builder.switch_to_block(entry_block);
+            func_builder.seal_block(entry_block);
+
+            let scope: Scope<(CalcValue, Value)> = params
+                .iter()
+                .cloned()
+                .zip(
+                    arg_types
+                        .iter()
+                        .cloned()
+                        .zip(func_builder.block_params(entry_block).iter().copied()),
+                )
+                .collect();
+
+            let (_, result) = self.compile_node(&input, module, &mut func_builder, &scope, body)?;
+            func_builder.ins().return_(&[result]);
+            func_builder.finalize();
+
+            module
+                .define_function(func_id, &mut ctx)
+                .map_err(|e| CalculatorError {
+                    src: self.source.clone(),
+                    span: (0, 0).into(),
+                    kind: CalcErrorKind::JitError(e.to_string()),
+                    help: None,
+                })?;
+            Ok(())
+        })();
+
+        let mut environment = self.environment.borrow_mut();
+        let trap_sites = environment.take_trap_sites(pending);
+        match result {
+            Ok(()) => environment.add_function_trap_sites(name, trap_sites),
+            Err(_) => environment.set_specialization(name, arg_types, None),
         }
+        result.map(|_| (func_id, return_type))
+    }
+
+    /// Errors from inside a function body point into the input it was
+    /// defined in, so they are reported on the call being compiled instead.
+    fn at_call_site(&self, name: &str, span: SourceSpan, report: miette::Report) -> miette::Report {
+        match report.downcast::<CalculatorError>() {
+            Ok(error) => CalculatorError {
+                src: self.source.clone(),
+                span,
+                help: Some(format!(
+                    "Raised while compiling `{}` for these arguments",
+                    name
+                )),
+                ..error
+            }
+            .into(),
+            Err(report) => report,
+        }
+    }
+
+    fn nested_definition(&self) -> CalculatorError {
+        CalculatorError {
+            src: self.source.clone(),
+            span: (0, self.source.inner().len()).into(),
+            kind: CalcErrorKind::CompilationError("Unexpected function definition".into()),
+            help: Some("Define functions on their own, e.g. `f(x) = x * 2`".into()),
+        }
+    }
+
+    fn undefined_variable(&self, name: &str, span: SourceSpan) -> CalculatorError {
+        CalculatorError {
+            src: self.source.clone(),
+            span,
+            kind: CalcErrorKind::UndefinedVariable(name.to_string()),
+            // Possibly a misspelt constant or unit, as in `2 pu` or `3 kmh`.
+            // Any one letter name is close to some unit or `e`, so short
+            // names aren't suggested one
+            help: Some(match did_you_mean(name, constant_names()) {
+                Some(constant) if name.len() > 1 => format!("Did you mean `{}`?", constant),
+                _ => match did_you_mean(name, Unit::names()) {
+                    Some(unit) if name.len() > 2 => format!("Did you mean `{}`?", unit),
+                    _ => format!("Assign a value first, e.g. `{} = 1`", name),
+                },
+            }),
+        }
+    }
+
+    /// Constants are replaced by their value while parsing, so they can't
+    /// be assigned or used as parameter names.
+    fn check_not_constant(&self, name: &str, span: SourceSpan) -> MietteResult<()> {
+        if constant(name).is_some() {
+            Err(CalculatorError {
+                src: self.source.clone(),
+                span,
+                kind: CalcErrorKind::ParseError(format!("`{}` is a constant", name)),
+                help: Some(format!("`{}` is a constant, so pick another name", name)),
+            })?
+        }
+        Ok(())
+    }
+
+    /// Value of a decimal literal with the configured number of places.
+    /// Literals with more digits after the point are rejected rather than
+    /// rounded.
+    fn decimal_literal(
+        &self,
+        mantissa: i128,
+        written: u32,
+        span: SourceSpan,
+    ) -> MietteResult<Decimal> {
+        let places = self.environment.borrow().places();
+        if written > places {
+            Err(CalculatorError {
+                src: self.source.clone(),
+                span,
+                kind: CalcErrorKind::NumberError(format!(
+                    "decimals have {} places, this one has {}",
+                    places, written
+                )),
+                help: Some("Round it, or start the calculator with more `--places`".into()),
+            })?
+        }
+        match decimal::rescale(mantissa, written, places) {
+            Some(scaled) => Ok(Decimal { scaled, places }),
+            None => Err(CalculatorError {
+                src: self.source.clone(),
+                span,
+                kind: CalcErrorKind::NumberError(format!(
+                    "too large for a decimal with {} places",
+                    places
+                )),
+                help: Some("Decimals hold up to 38 digits, places included".into()),
+            })?,
+        }
+    }
+
+    /// Type of `left op right`, or a `TypeMismatch` on the operator when
+    /// the operands don't suit it. Mixing integers and floats gives a float,
+    /// and mixing integers and big integers a big integer. In exact mode,
+    /// `/` on integers gives a rational, which `+ - * /` keep exact (other
+    /// operators work on it as a float). Decimals and integers give a
+    /// decimal under `+ - * /`, and a float otherwise (as do decimals mixed
+    /// with anything else). Anything mixed with a complex number gives a
+    /// complex number, which has no order, so `< <= > >=`, `//` and `%`
+    /// reject it. Quantities are checked by `quantity_type`.
+    fn binary_type(
+        &self,
+        op: BinaryOpKind,
+        left: &CalcValue,
+        right: &CalcValue,
+        right_expr: &Expr,
+        spans: &BinarySpans,
+    ) -> MietteResult<CalcValue> {
+        let both = |check: fn(&CalcValue) -> bool| check(left) && check(right);
+        let is_bool = |ty: &CalcValue| matches!(ty, CalcValue::Bool(_));
+        let is_integer =
+            |ty: &CalcValue| matches!(ty, CalcValue::Integer(_) | CalcValue::BigInt(_));
+        let is_number = |ty: &CalcValue| !matches!(ty, CalcValue::Bool(_) | CalcValue::Function(_));
+        let either = |check: fn(&CalcValue) -> bool| check(left) || check(right);
+        let is_float = |ty: &CalcValue| matches!(ty, CalcValue::Float(_));
+        let is_rational = |ty: &CalcValue| matches!(ty, CalcValue::Rational(_));
+        let either_decimal = either(|ty| matches!(ty, CalcValue::Decimal(_)));
+        let either_complex = either(|ty| matches!(ty, CalcValue::Complex(_)));
+        let either_big = either(|ty| matches!(ty, CalcValue::BigInt(_)));
+        let either_quantity = either(|ty| matches!(ty, CalcValue::Quantity(..)));
+        let either_sized = either(|ty| matches!(ty, CalcValue::Sized(_)));
+        let mismatch = |expected: &str, help: &str| {
+            self.operand_mismatch(op.symbol(), expected, help, spans.operator)
+        };
+
+        Ok(match op {
+            BinaryOpKind::And | BinaryOpKind::Or => {
+                if !both(is_bool) {
+                    Err(mismatch(
+                        "boolean",
+                        "Compare numbers first, e.g. `x > 0 && x < 10`",
+                    ))?
+                }
+                CalcValue::Bool(false)
+            }
+            BinaryOpKind::Equal | BinaryOpKind::NotEqual if both(is_bool) => CalcValue::Bool(false),
+            // Mixed with a float, a sized integer is just a number
+            _ if either_sized && !either(is_float) => {
+                let ty = self.common_int_type(left, right, spans.operator)?;
+                match op {
+                    _ if op.condition().is_some() => CalcValue::Bool(false),
+                    BinaryOpKind::Divide => CalcValue::Float(0.0),
+                    _ => CalcValue::Sized(SizedInt::zero(ty)),
+                }
+            }
+            _ if either_quantity && !op.is_bitwise() => {
+                self.quantity_type(op, left, right, right_expr, spans)?
+            }
+            _ if op.condition().is_some() => {
+                if !both(is_number) {
+                    Err(mismatch(
+                        "numeric",
+                        "Booleans can only be compared with `==` and `!=`",
+                    ))?
+                }
+                if either_complex && !matches!(op, BinaryOpKind::Equal | BinaryOpKind::NotEqual) {
+                    Err(mismatch(
+                        "real",
+                        "Complex numbers can only be compared with `==` and `!=`",
+                    ))?
+                }
+                CalcValue::Bool(false)
+            }
+            _ if op.is_bitwise() => {
+                if !both(is_integer) {
+                    Err(mismatch(
+                        "integer",
+                        "Bitwise operators only work on whole numbers",
+                    ))?
+                }
+                if either_big {
+                    CalcValue::BigInt(BigInt::ZERO)
+                } else {
+                    CalcValue::Integer(0)
+                }
+            }
+            _ => {
+                if !both(is_number) {
+                    Err(mismatch(
+                        "numeric",
+                        "Booleans only work with `&&`, `||`, `!`, `==` and `!=`",
+                    ))?
+                }
+                if either_complex && matches!(op, BinaryOpKind::FloorDivide | BinaryOpKind::Modulo)
+                {
+                    Err(mismatch(
+                        "real",
+                        "Complex numbers can't be rounded, so `//` and `%` don't apply to them",
+                    ))?
+                }
+                let exact = match op {
+                    BinaryOpKind::Divide => self.environment.borrow().exact(),
+                    BinaryOpKind::Add | BinaryOpKind::Subtract | BinaryOpKind::Multiply => true,
+                    _ => false,
+                };
+                let decimal = either_decimal
+                    && matches!(
+                        op,
+                        BinaryOpKind::Add
+                            | BinaryOpKind::Subtract
+                            | BinaryOpKind::Multiply
+                            | BinaryOpKind::Divide
+                    )
+                    && both(|ty| matches!(ty, CalcValue::Integer(_) | CalcValue::Decimal(_)));
+                let float = either(is_float)
+                    || either_decimal
+                    || (op == BinaryOpKind::Power && is_negative_constant(right_expr))
+                    || (!exact && (op == BinaryOpKind::Divide || either(is_rational)));
+                // Powers of negative floats can be complex, e.g. `(-8) ^ (1 / 3)`
+                let complex = float
+                    && op == BinaryOpKind::Power
+                    && self.environment.borrow().promotion().complex;
+                if either_complex || complex {
+                    CalcValue::Complex(Complex64::default())
+                } else if decimal {
+                    CalcValue::Decimal(Decimal {
+                        scaled: 0,
+                        places: self.environment.borrow().places(),
+                    })
+                } else if float {
+                    CalcValue::Float(0.0)
+                } else if op == BinaryOpKind::Divide || either(is_rational) {
+                    CalcValue::Rational(BigRational::default())
+                } else if either_big {
+                    CalcValue::BigInt(BigInt::ZERO)
+                } else {
+                    CalcValue::Integer(0)
+                }
+            }
+        })
+    }
+
+    /// Type a sized integer and another integer are both brought to: the
+    /// wider of two sized types (see `IntType::widen`), or the sized one for
+    /// a plain integer, whose value then has to fit. Anything else, such as
+    /// `u64` and `i64`, needs a cast.
+    fn common_int_type(
+        &self,
+        left: &CalcValue,
+        right: &CalcValue,
+        span: SourceSpan,
+    ) -> MietteResult<IntType> {
+        let mismatch = |message: String, help: String| CalculatorError {
+            src: self.source.clone(),
+            span,
+            kind: CalcErrorKind::TypeMismatch(message),
+            help: Some(help),
+        };
+        Ok(match (left, right) {
+            (CalcValue::Sized(a), CalcValue::Sized(b)) => match a.ty.widen(b.ty) {
+                Some(ty) => ty,
+                None => {
+                    let wider = IntType::ALL
+                        .into_iter()
+                        .find(|ty| a.ty.fits_in(*ty) && b.ty.fits_in(*ty));
+                    Err(mismatch(
+                        format!("`{}` and `{}` have no common type", a.ty, b.ty),
+                        match wider {
+                            Some(ty) => format!("Cast one side first, e.g. with `as {}`", ty),
+                            None => "Cast one side first".into(),
+                        },
+                    ))?
+                }
+            },
+            (CalcValue::Sized(n), CalcValue::Integer(_) | CalcValue::BigInt(_))
+            | (CalcValue::Integer(_) | CalcValue::BigInt(_), CalcValue::Sized(n)) => n.ty,
+            (CalcValue::Sized(n), other) | (other, CalcValue::Sized(n)) => Err(mismatch(
+                format!("a `{}` can't be mixed with {}", n.ty, type_name(other)),
+                match other {
+                    CalcValue::Complex(_) | CalcValue::Quantity(..) | CalcValue::Function(_) => {
+                        "Sized integers only mix with other integers and floats".into()
+                    }
+                    _ => format!("Cast it first, e.g. with `as {}`", n.ty),
+                },
+            ))?,
+            _ => unreachable!("one side is a sized integer"),
+        })
+    }
+
+    /// Type of `left op right` when either side is a quantity, with plain
+    /// numbers being dimensionless. Comparisons, `+`, `-`, `%` and `//` need
+    /// both sides to have the same dimension, and the first three give the
+    /// left side's unit (both are in SI base units by then). `*` and `/`
+    /// combine the units, and `^` takes an integer constant as exponent.
+    fn quantity_type(
+        &self,
+        op: BinaryOpKind,
+        left: &CalcValue,
+        right: &CalcValue,
+        right_expr: &Expr,
+        spans: &BinarySpans,
+    ) -> MietteResult<CalcValue> {
+        let unit_of = |ty: &CalcValue| -> MietteResult<Unit> {
+            Ok(match ty {
+                CalcValue::Quantity(_, unit) => unit.clone(),
+                CalcValue::Complex(_) => Err(self.operand_mismatch(
+                    op.symbol(),
+                    "real",
+                    "Quantities can't be complex",
+                    spans.operator,
+                ))?,
+                CalcValue::Bool(_) | CalcValue::Function(_) => Err(self.operand_mismatch(
+                    op.symbol(),
+                    "numeric",
+                    "Booleans only work with `&&`, `||`, `!`, `==` and `!=`",
+                    spans.operator,
+                ))?,
+                _ => Unit::dimensionless(),
+            })
+        };
+        let left_unit = unit_of(left)?;
+        let right_unit = unit_of(right)?;
+        let unit_mismatch = |message: String, help: String| CalculatorError {
+            src: self.source.clone(),
+            span: spans.operator,
+            kind: CalcErrorKind::TypeMismatch(message),
+            help: Some(help),
+        };
+
+        let unit = match op {
+            BinaryOpKind::Multiply => left_unit.mul(&right_unit),
+            BinaryOpKind::Divide => left_unit.div(&right_unit),
+            BinaryOpKind::Power => {
+                let exponent = integer_constant(right_expr).and_then(|n| i64::try_from(n).ok());
+                match exponent {
+                    Some(exponent) if right_unit.is_dimensionless() => left_unit.pow(exponent),
+                    _ => Err(unit_mismatch(
+                        "`^` needs a whole number exponent for a quantity".into(),
+                        "Units can only be raised to fixed powers, e.g. `(3 m) ^ 2`".into(),
+                    ))?,
+                }
+            }
+            _ if left_unit.dimension != right_unit.dimension => Err(unit_mismatch(
+                format!("`{}` needs operands of the same dimension", op.symbol()),
+                format!(
+                    "The left side is {}, the right side {}",
+                    left_unit.describe(),
+                    right_unit.describe()
+                ),
+            ))?,
+            _ if op.condition().is_some() => return Ok(CalcValue::Bool(false)),
+            BinaryOpKind::FloorDivide => return Ok(CalcValue::Float(0.0)),
+            _ => Some(left_unit),
+        };
+        match unit {
+            Some(unit) => Ok(in_unit(unit)),
+            None => Err(unit_mismatch(
+                format!("`{}` gives a unit with too large a power", op.symbol()),
+                "Units can be raised to powers up to 127".into(),
+            ))?,
+        }
+    }
+
+    fn unary_type(
+        &self,
+        op: UnaryOpKind,
+        operand: &CalcValue,
+        span: SourceSpan,
+    ) -> MietteResult<CalcValue> {
+        let (expected, help) = match (op, operand) {
+            (
+                UnaryOpKind::Negate | UnaryOpKind::Plus,
+                CalcValue::Integer(_)
+                | CalcValue::BigInt(_)
+                | CalcValue::Rational(_)
+                | CalcValue::Decimal(_)
+                | CalcValue::Float(_)
+                | CalcValue::Complex(_)
+                | CalcValue::Quantity(..)
+                | CalcValue::Sized(_),
+            )
+            | (
+                UnaryOpKind::BitNot,
+                CalcValue::Integer(_) | CalcValue::BigInt(_) | CalcValue::Sized(_),
+            )
+            | (UnaryOpKind::Not, CalcValue::Bool(_)) => return Ok(operand.clone()),
+            (UnaryOpKind::Negate | UnaryOpKind::Plus, _) => {
+                ("numeric", "Use `!` to negate a boolean")
+            }
+            (UnaryOpKind::BitNot, _) => ("integer", "Bitwise operators only work on whole numbers"),
+            (UnaryOpKind::Not, _) => ("boolean", "Compare it first, e.g. `!(x > 0)`"),
+        };
+        Err(self.operand_mismatch(op.symbol(), expected, help, span))?
+    }
+
+    /// Type both branches of a conditional can be brought to: integers are
+    /// promoted to big integers, rationals, decimals, floats or complex
+    /// numbers (and decimals to floats or complex numbers), quantities need the same dimension (and take the consequence's unit),
+    /// sized integers go by `common_int_type` (or become floats alongside
+    /// one), anything else has to match exactly.
+    fn unify_branches(
+        &self,
+        consequence: &CalcValue,
+        alternative: &CalcValue,
+        spans: &ConditionalSpans,
+    ) -> MietteResult<CalcValue> {
+        Ok(match (consequence, alternative) {
+            (CalcValue::Sized(_), CalcValue::Float(_))
+            | (CalcValue::Float(_), CalcValue::Sized(_)) => CalcValue::Float(0.0),
+            (CalcValue::Sized(_), _) | (_, CalcValue::Sized(_)) => {
+                CalcValue::Sized(SizedInt::zero(self.common_int_type(
+                    consequence,
+                    alternative,
+                    spans.alternative,
+                )?))
+            }
+            (CalcValue::Integer(_), CalcValue::Integer(_)) => CalcValue::Integer(0),
+            (
+                CalcValue::Integer(_) | CalcValue::Decimal(_),
+                CalcValue::Integer(_) | CalcValue::Decimal(_),
+            ) => CalcValue::Decimal(Decimal {
+                scaled: 0,
+                places: self.environment.borrow().places(),
+            }),
+            (
+                CalcValue::Integer(_) | CalcValue::BigInt(_),
+                CalcValue::Integer(_) | CalcValue::BigInt(_),
+            ) => CalcValue::BigInt(BigInt::ZERO),
+            (
+                CalcValue::Integer(_) | CalcValue::BigInt(_) | CalcValue::Rational(_),
+                CalcValue::Integer(_) | CalcValue::BigInt(_) | CalcValue::Rational(_),
+            ) => CalcValue::Rational(BigRational::default()),
+            (
+                CalcValue::Integer(_)
+                | CalcValue::BigInt(_)
+                | CalcValue::Rational(_)
+                | CalcValue::Decimal(_)
+                | CalcValue::Float(_),
+                CalcValue::Integer(_)
+                | CalcValue::BigInt(_)
+                | CalcValue::Rational(_)
+                | CalcValue::Decimal(_)
+                | CalcValue::Float(_),
+            ) => CalcValue::Float(0.0),
+            (
+                CalcValue::Integer(_)
+                | CalcValue::BigInt(_)
+                | CalcValue::Rational(_)
+                | CalcValue::Decimal(_)
+                | CalcValue::Float(_)
+                | CalcValue::Complex(_),
+                CalcValue::Integer(_)
+                | CalcValue::BigInt(_)
+                | CalcValue::Rational(_)
+                | CalcValue::Decimal(_)
+                | CalcValue::Float(_)
+                | CalcValue::Complex(_),
+            ) => CalcValue::Complex(Complex64::default()),
+            (CalcValue::Bool(_), CalcValue::Bool(_)) => CalcValue::Bool(false),
+            (CalcValue::Quantity(_, unit), CalcValue::Quantity(_, other))
+                if unit.dimension == other.dimension =>
+            {
+                consequence.clone()
+            }
+            (CalcValue::Quantity(_, unit), CalcValue::Quantity(_, other)) => {
+                Err(CalculatorError {
+                    src: self.source.clone(),
+                    span: spans.alternative,
+                    kind: CalcErrorKind::TypeMismatch(format!(
+                        "branches give {} and {}",
+                        unit.describe(),
+                        other.describe()
+                    )),
+                    help: Some("Both branches need the same dimension".into()),
+                })?
+            }
+            _ => Err(CalculatorError {
+                src: self.source.clone(),
+                span: spans.alternative,
+                kind: CalcErrorKind::TypeMismatch(format!(
+                    "branches give {} and {}",
+                    type_name(consequence),
+                    type_name(alternative)
+                )),
+                help: Some("Both branches need to be numbers, or both booleans".into()),
+            })?,
+        })
+    }
+
+    /// Type `sum` or `prod` gives, which is the type of its terms. The
+    /// bounds have to be integers, and a product of quantities would have a
+    /// unit depending on how many terms there are.
+    fn aggregate_type(
+        &self,
+        kind: AggregateKind,
+        [start, end]: [&CalcValue; 2],
+        body: &CalcValue,
+        spans: &AggregateSpans,
+    ) -> MietteResult<CalcValue> {
+        for (bound, span) in [(start, spans.start), (end, spans.end)] {
+            if !matches!(bound, CalcValue::Integer(_) | CalcValue::BigInt(_)) {
+                Err(CalculatorError {
+                    src: self.source.clone(),
+                    span,
+                    kind: CalcErrorKind::TypeMismatch(format!(
+                        "`{}` needs integer bounds, not {}",
+                        kind.name(),
+                        type_name(bound)
+                    )),
+                    help: Some(format!(
+                        "Ranges look like `{}(i = 1..10, i)`, including both ends",
+                        kind.name()
+                    )),
+                })?
+            }
+        }
+        let help = match body {
+            CalcValue::Bool(_) | CalcValue::Function(_) => {
+                "Terms are added or multiplied, so they have to be numbers"
+            }
+            CalcValue::Quantity(..) if kind == AggregateKind::Product => {
+                "The unit would depend on the number of terms"
+            }
+            _ => return Ok(body.clone()),
+        };
+        Err(CalculatorError {
+            src: self.source.clone(),
+            span: spans.body,
+            kind: CalcErrorKind::TypeMismatch(format!(
+                "`{}` terms can't be {}",
+                kind.name(),
+                type_name(body)
+            )),
+            help: Some(help.into()),
+        })?
+    }
+
+    fn condition_mismatch(&self, condition: &CalcValue, span: SourceSpan) -> CalculatorError {
+        CalculatorError {
+            src: self.source.clone(),
+            span,
+            kind: CalcErrorKind::TypeMismatch(format!(
+                "condition is {}, not a boolean",
+                type_name(condition)
+            )),
+            help: Some("Compare it first, e.g. `if x != 0 then ...`".into()),
+        }
+    }
+
+    fn operand_mismatch(
+        &self,
+        operator: &str,
+        expected: &str,
+        help: &str,
+        span: SourceSpan,
+    ) -> CalculatorError {
+        CalculatorError {
+            src: self.source.clone(),
+            span,
+            kind: CalcErrorKind::TypeMismatch(format!(
+                "`{}` needs {} operands",
+                operator, expected
+            )),
+            help: Some(help.into()),
+        }
+    }
+
+    /// Type `builtin` returns for `arg_types`. Quantities keep their unit
+    /// through `abs`, `min` and `max`, `sqrt` halves its powers, and the
+    /// other builtins only take plain numbers.
+    fn builtin_type(
+        &self,
+        builtin: Builtin,
+        arg_types: &[CalcValue],
+        span: SourceSpan,
+    ) -> MietteResult<CalcValue> {
+        let name = builtin.name();
+        let argument_mismatch = |expected: &str, help: &str| CalculatorError {
+            src: self.source.clone(),
+            span,
+            kind: CalcErrorKind::TypeMismatch(format!("`{}` needs {} arguments", name, expected)),
+            help: Some(help.into()),
+        };
+        if arg_types.iter().any(|ty| matches!(ty, CalcValue::Bool(_))) {
+            Err(argument_mismatch(
+                "numeric",
+                "Booleans can't be passed to math functions",
+            ))?
+        }
+        let complex = |ty: &CalcValue| matches!(ty, CalcValue::Complex(_));
+        if builtin.complex_symbol().is_none() && arg_types.iter().any(complex) {
+            Err(argument_mismatch(
+                "real",
+                "Complex numbers can't be rounded or ordered",
+            ))?
+        }
+
+        // Sized integers keep their type through the functions that keep
+        // integers as integers, unless mixed with a float
+        let integral = matches!(
+            builtin,
+            Builtin::Abs
+                | Builtin::Floor
+                | Builtin::Ceil
+                | Builtin::Round
+                | Builtin::Min
+                | Builtin::Max
+        );
+        let sized = |ty: &CalcValue| matches!(ty, CalcValue::Sized(_));
+        let float = |ty: &CalcValue| matches!(ty, CalcValue::Float(_));
+        if integral && arg_types.iter().any(sized) && !arg_types.iter().any(float) {
+            let ty = match arg_types {
+                [left, right] => self.common_int_type(left, right, span)?,
+                [CalcValue::Sized(n)] => n.ty,
+                _ => unreachable!("arity was checked against the builtin"),
+            };
+            return Ok(CalcValue::Sized(SizedInt::zero(ty)));
+        }
+
+        let units: Vec<Unit> = arg_types
+            .iter()
+            .map(|ty| match ty {
+                CalcValue::Quantity(_, unit) => unit.clone(),
+                _ => Unit::dimensionless(),
+            })
+            .collect();
+        if units.iter().all(Unit::is_dimensionless) {
+            let complex = self.environment.borrow().promotion().complex;
+            return Ok(builtin.return_type(arg_types, complex));
+        }
+        Ok(match (builtin, units.as_slice()) {
+            (Builtin::Abs, [unit]) => in_unit(unit.clone()),
+            (Builtin::Min | Builtin::Max, [unit, other]) if unit.dimension == other.dimension => {
+                in_unit(unit.clone())
+            }
+            (Builtin::Min | Builtin::Max, _) => Err(argument_mismatch(
+                "same-dimension",
+                &format!(
+                    "The first argument is {}, the second {}",
+                    units[0].describe(),
+                    units[1].describe()
+                ),
+            ))?,
+            (Builtin::Sqrt, [unit]) => match unit.sqrt() {
+                Some(root) => in_unit(root),
+                None => Err(argument_mismatch(
+                    "square",
+                    &format!(
+                        "{} has no square root, unlike e.g. `4 m^2`",
+                        unit.describe()
+                    ),
+                ))?,
+            },
+            _ => Err(argument_mismatch(
+                "dimensionless",
+                &format!("Divide by a unit first, e.g. `{}(x / 1 m)`", name),
+            ))?,
+        })
+    }
+
+    /// Type of `value as target`. Integers of any size are `int`s, so big
+    /// integers stay big, as does everything once integers are wide (and
+    /// sized integers too large for 64 bits).
+    fn cast_type(
+        &self,
+        value: &CalcValue,
+        target: CastType,
+        span: SourceSpan,
+    ) -> MietteResult<CalcValue> {
+        let help = match value {
+            CalcValue::Integer(_)
+            | CalcValue::BigInt(_)
+            | CalcValue::Rational(_)
+            | CalcValue::Decimal(_)
+            | CalcValue::Float(_)
+            | CalcValue::Sized(_)
+            | CalcValue::Bool(_) => {
+                let wide = self.environment.borrow().promotion().wide;
+                let big = match value {
+                    CalcValue::BigInt(_) => true,
+                    CalcValue::Sized(n) => !n.ty.fits_in(IntType::I64),
+                    _ => false,
+                };
+                return Ok(match target {
+                    CastType::Float => CalcValue::Float(0.0),
+                    CastType::Int if wide || big => CalcValue::BigInt(BigInt::ZERO),
+                    CastType::Int => CalcValue::Integer(0),
+                    CastType::Sized(ty) => CalcValue::Sized(SizedInt::zero(ty)),
+                });
+            }
+            CalcValue::Complex(_) => "Take its magnitude first, e.g. `abs(z)`",
+            CalcValue::Quantity(..) => "Divide it by a unit first, e.g. `x / m`",
+            CalcValue::Function(_) => "Call it first",
+        };
+        Err(CalculatorError {
+            src: self.source.clone(),
+            span,
+            kind: CalcErrorKind::TypeMismatch(format!(
+                "{} can't be cast to `{}`",
+                type_name(value),
+                target.name()
+            )),
+            help: Some(help.into()),
+        })?
+    }
+
+    /// Type of `value in unit`, which only changes how a quantity is shown.
+    fn conversion_type(
+        &self,
+        value: &CalcValue,
+        unit: &Unit,
+        span: SourceSpan,
+    ) -> MietteResult<CalcValue> {
+        let from = match value {
+            CalcValue::Quantity(_, from) => from.clone(),
+            _ => Unit::dimensionless(),
+        };
+        if from.dimension != unit.dimension {
+            Err(CalculatorError {
+                src: self.source.clone(),
+                span,
+                kind: CalcErrorKind::TypeMismatch(
+                    "`in` needs a quantity of the same dimension".into(),
+                ),
+                help: Some(format!(
+                    "The value is {}, the unit {}",
+                    from.describe(),
+                    unit.describe()
+                )),
+            })?
+        }
+        Ok(CalcValue::Quantity(0.0, unit.clone()))
+    }
+
+    fn determine_type(&self, expr: &Expr) -> MietteResult<(CalcValue, bool)> {
+        self.determine_type_in(expr, &Scope::new())
+    }
+
+    fn determine_type_in(
+        &self,
+        expr: &Expr,
+        scope: &Scope<CalcValue>,
+    ) -> MietteResult<(CalcValue, bool)> {
+        let promotion = self.environment.borrow().promotion();
+        let wide = promotion.wide;
+        Ok(match expr {
+            Expr::Integer(n) if wide => (CalcValue::BigInt(BigInt::from(*n)), false),
+            Expr::Integer(n) => (CalcValue::Integer(*n), false),
+            Expr::BigInt(n) => (CalcValue::BigInt(n.clone()), false),
+            Expr::Sized(n) => (CalcValue::Sized(*n), false),
+            Expr::Float(x) => (CalcValue::Float(*x), true),
+            Expr::Imaginary(x) => (CalcValue::Complex(Complex64::new(0.0, *x)), false),
+            Expr::Decimal {
+                mantissa,
+                places,
+                span,
+            } => (
+                CalcValue::Decimal(self.decimal_literal(*mantissa, *places, *span)?),
+                false,
+            ),
+            Expr::Quantity { value, unit } => (CalcValue::Quantity(*value, unit.clone()), false),
+            Expr::Convert { value, unit, span } => {
+                let (value_type, _) = self.determine_type_in(value, scope)?;
+                (self.conversion_type(&value_type, unit, *span)?, false)
+            }
+            Expr::Boolean(b) => (CalcValue::Bool(*b), false),
+            Expr::BinaryOp {
+                left,
+                op,
+                right,
+                spans,
+            } => {
+                let (left_type, _) = self.determine_type_in(left, scope)?;
+                let (right_type, _) = self.determine_type_in(right, scope)?;
+                let percent = [left, right]
+                    .into_iter()
+                    .find_map(|operand| match **operand {
+                        Expr::Percent { form, .. } => Some(form),
+                        _ => None,
+                    });
+                let ty = self
+                    .binary_type(*op, &left_type, &right_type, right, spans)
+                    .map_err(|error| match percent {
+                        Some(form) => explain_percent(error, form),
+                        None => error,
+                    })?;
+                let is_float = matches!(ty, CalcValue::Float(_));
+                (ty, is_float)
+            }
+            Expr::Parenthesized(inner) => self.determine_type_in(inner, scope)?,
+            Expr::Percent { meaning, form } => self
+                .determine_type_in(meaning, scope)
+                .map_err(|error| explain_percent(error, *form))?,
+            Expr::Cast {
+                value,
+                target,
+                span,
+            } => {
+                let (value_type, _) = self.determine_type_in(value, scope)?;
+                let ty = self.cast_type(&value_type, *target, *span)?;
+                let is_float = matches!(ty, CalcValue::Float(_));
+                (ty, is_float)
+            }
+            Expr::Unary { op, operand, span } => {
+                let (operand_type, is_float) = self.determine_type_in(operand, scope)?;
+                (self.unary_type(*op, &operand_type, *span)?, is_float)
+            }
+            Expr::Variable { name, span } => {
+                let ty = match scope.get(name) {
+                    Some(ty) => ty.clone(),
+                    None => match self.environment.borrow().variable(name) {
+                        Some(variable) => match variable.ty {
+                            CalcValue::Integer(_) if wide => CalcValue::BigInt(BigInt::ZERO),
+                            _ => variable.ty.clone(),
+                        },
+                        None => match Unit::named(name) {
+                            Some(unit) => CalcValue::Quantity(1.0, unit),
+                            None => Err(self.undefined_variable(name, *span))?,
+                        },
+                    },
+                };
+                let is_float = matches!(ty, CalcValue::Float(_));
+                (ty, is_float)
+            }
+            Expr::Assign { value, .. } => self.determine_type_in(value, scope)?,
+            Expr::Call { name, args, span } => {
+                let callee = self.callee(name, args.len(), *span)?;
+                let arg_types = args
+                    .iter()
+                    .map(|arg| self.determine_type_in(arg, scope).map(|(ty, _)| ty))
+                    .collect::<MietteResult<Vec<_>>>()?;
+                let return_type = match callee {
+                    Callee::User { params, body } => self
+                        .return_type(name, &params, &body, &arg_types)
+                        .map_err(|e| self.at_call_site(name, *span, e))?,
+                    Callee::Builtin(builtin) => self.builtin_type(builtin, &arg_types, *span)?,
+                };
+                let is_float = matches!(return_type, CalcValue::Float(_));
+                (return_type, is_float)
+            }
+            Expr::Conditional {
+                condition,
+                consequence,
+                alternative,
+                spans,
+            } => {
+                let (condition_type, _) = self.determine_type_in(condition, scope)?;
+                if !matches!(condition_type, CalcValue::Bool(_)) {
+                    Err(self.condition_mismatch(&condition_type, spans.condition))?
+                }
+                let (consequence_type, _) = self.determine_type_in(consequence, scope)?;
+                let (alternative_type, _) = self.determine_type_in(alternative, scope)?;
+                let ty = self.unify_branches(&consequence_type, &alternative_type, spans)?;
+                let is_float = matches!(ty, CalcValue::Float(_));
+                (ty, is_float)
+            }
+            Expr::Aggregate {
+                kind,
+                variable,
+                start,
+                end,
+                body,
+                spans,
+            } => {
+                let (start_type, _) = self.determine_type_in(start, scope)?;
+                let (end_type, _) = self.determine_type_in(end, scope)?;
+                let mut scope = scope.clone();
+                let counter = match wide {
+                    true => CalcValue::BigInt(BigInt::ZERO),
+                    false => CalcValue::Integer(0),
+                };
+                scope.insert(variable.clone(), counter);
+                let (body_type, _) = self.determine_type_in(body, &scope)?;
+                let ty = self.aggregate_type(*kind, [&start_type, &end_type], &body_type, spans)?;
+                let is_float = matches!(ty, CalcValue::Float(_));
+                (ty, is_float)
+            }
+            Expr::FunctionDef { .. } => Err(self.nested_definition())?,
+        })
+    }
+
+    fn compile_node(
+        &self,
+        input: &str,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        scope: &Scope<(CalcValue, Value)>,
+        expr: &Expr,
+    ) -> MietteResult<(CalcValue, cranelift::prelude::Value)> {
+        match expr {
+            Expr::Integer(n) if self.environment.borrow().promotion().wide => {
+                let n = BigInt::from(*n);
+                let v = self.big_constant(builder, n.clone());
+                Ok((CalcValue::BigInt(n), v))
+            }
+            Expr::Integer(n) => {
+                let v = builder.ins().iconst(types::I64, *n);
+                Ok((CalcValue::Integer(*n), v))
+            }
+            Expr::BigInt(n) => {
+                let v = self.big_constant(builder, n.clone());
+                Ok((CalcValue::BigInt(n.clone()), v))
+            }
+            Expr::Sized(n) => {
+                let v = int_constant(builder, n.ty.ir_type(), n.extended());
+                Ok((CalcValue::Sized(*n), v))
+            }
+            Expr::Float(x) => {
+                let v = builder.ins().f64const(*x);
+                Ok((CalcValue::Float(*x), v))
+            }
+            Expr::Imaginary(x) => {
+                let re = builder.ins().f64const(0.0);
+                let im = builder.ins().f64const(*x);
+                Ok((
+                    CalcValue::Complex(Complex64::new(0.0, *x)),
+                    join(builder, re, im),
+                ))
+            }
+            Expr::Decimal {
+                mantissa,
+                places,
+                span,
+            } => {
+                let value = self.decimal_literal(*mantissa, *places, *span)?;
+                let v = i128_constant(builder, value.scaled);
+                Ok((CalcValue::Decimal(value), v))
+            }
+            Expr::Quantity { value, unit } => {
+                let v = builder.ins().f64const(value * unit.scale);
+                Ok((CalcValue::Quantity(*value, unit.clone()), v))
+            }
+            // Values are in SI base units either way
+            Expr::Convert { value, unit, span } => {
+                let (value_type, v) = self.compile_node(input, module, builder, scope, value)?;
+                Ok((self.conversion_type(&value_type, unit, *span)?, v))
+            }
+            Expr::Boolean(b) => {
+                let v = builder.ins().iconst(types::I8, i64::from(*b));
+                Ok((CalcValue::Bool(*b), v))
+            }
+            // Short-circuits: the right operand only runs when it decides the result
+            Expr::BinaryOp {
+                left,
+                op: op @ (BinaryOpKind::And | BinaryOpKind::Or),
+                right,
+                spans,
+            } => {
+                let (left_val, left_ir) = self.compile_node(input, module, builder, scope, left)?;
+                let right_block = builder.create_block();
+                let merge_block = builder.create_block();
+                builder.append_block_param(merge_block, types::I8);
+                match op {
+                    BinaryOpKind::And => {
+                        builder
+                            .ins()
+                            .brif(left_ir, right_block, &[], merge_block, &[left_ir])
+                    }
+                    _ => builder
+                        .ins()
+                        .brif(left_ir, merge_block, &[left_ir], right_block, &[]),
+                };
+
+                builder.switch_to_block(right_block);
+                builder.seal_block(right_block);
+                let (right_val, right_ir) =
+                    self.compile_node(input, module, builder, scope, right)?;
+                let result_type = self.binary_type(*op, &left_val, &right_val, right, spans)?;
+                builder.ins().jump(merge_block, &[right_ir]);
+
+                builder.switch_to_block(merge_block);
+                builder.seal_block(merge_block);
+                Ok((result_type, builder.block_params(merge_block)[0]))
+            }
+            Expr::BinaryOp {
+                left,
+                op,
+                right,
+                spans,
+            } => {
+                let (left_val, left_ir) = self.compile_node(input, module, builder, scope, left)?;
+                let (right_val, right_ir) =
+                    self.compile_node(input, module, builder, scope, right)?;
+
+                let result_type = self.binary_type(*op, &left_val, &right_val, right, spans)?;
+                // Comparisons bring their operands to a common type of their own
+                let is = |check: fn(&CalcValue) -> bool| check(&left_val) || check(&right_val);
+                let operand_type = match &result_type {
+                    CalcValue::Bool(_) if is(|ty| matches!(ty, CalcValue::Complex(_))) => {
+                        CalcValue::Complex(Complex64::default())
+                    }
+                    CalcValue::Bool(_)
+                        if is(|ty| matches!(ty, CalcValue::Float(_) | CalcValue::Quantity(..))) =>
+                    {
+                        CalcValue::Float(0.0)
+                    }
+                    CalcValue::Bool(_) if is(|ty| matches!(ty, CalcValue::Sized(_))) => {
+                        let ty = self.common_int_type(&left_val, &right_val, spans.operator)?;
+                        CalcValue::Sized(SizedInt::zero(ty))
+                    }
+                    // Decimals only have exact comparisons with integers
+                    CalcValue::Bool(_) if is(|ty| matches!(ty, CalcValue::Decimal(_))) => {
+                        match is(|ty| matches!(ty, CalcValue::BigInt(_) | CalcValue::Rational(_))) {
+                            true => CalcValue::Float(0.0),
+                            false => CalcValue::Decimal(Decimal {
+                                scaled: 0,
+                                places: self.environment.borrow().places(),
+                            }),
+                        }
+                    }
+                    CalcValue::Bool(_) if is(|ty| matches!(ty, CalcValue::Rational(_))) => {
+                        CalcValue::Rational(BigRational::default())
+                    }
+                    CalcValue::Bool(_) if is(|ty| matches!(ty, CalcValue::BigInt(_))) => {
+                        CalcValue::BigInt(BigInt::ZERO)
+                    }
+                    CalcValue::Bool(_) => left_val.clone(),
+                    CalcValue::Quantity(..) => CalcValue::Float(0.0),
+                    ty => ty.clone(),
+                };
+                // A plain integer mixed with a sized one has to fit its type
+                let (final_left, final_right) = match operand_type {
+                    CalcValue::Sized(n) => (
+                        self.fit(module, builder, (&left_val, left_ir), n.ty, spans.left)?,
+                        self.fit(module, builder, (&right_val, right_ir), n.ty, spans.right)?,
+                    ),
+                    _ => (
+                        self.convert(module, builder, &left_val, &operand_type, left_ir)?,
+                        self.convert(module, builder, &right_val, &operand_type, right_ir)?,
+                    ),
+                };
+
+                if let Some((int_cc, float_cc)) = op.condition() {
+                    let result = match operand_type {
+                        CalcValue::Float(_) => {
+                            builder.ins().fcmp(float_cc, final_left, final_right)
+                        }
+                        // Only `==` and `!=` get here, comparing both parts
+                        CalcValue::Complex(_) => {
+                            let [left_re, left_im] = split(builder, final_left);
+                            let [right_re, right_im] = split(builder, final_right);
+                            let re = builder.ins().fcmp(float_cc, left_re, right_re);
+                            let im = builder.ins().fcmp(float_cc, left_im, right_im);
+                            match op {
+                                BinaryOpKind::Equal => builder.ins().band(re, im),
+                                _ => builder.ins().bor(re, im),
+                            }
+                        }
+                        CalcValue::BigInt(_) | CalcValue::Rational(_) => {
+                            let symbol = match operand_type {
+                                CalcValue::BigInt(_) => "calc_big_cmp",
+                                _ => "calc_rat_cmp",
+                            };
+                            let ordering = self.call_symbol(
+                                module,
+                                builder,
+                                symbol,
+                                &[final_left, final_right],
+                                types::I64,
+                            )?;
+                            builder.ins().icmp_imm(int_cc, ordering, 0)
+                        }
+                        CalcValue::Sized(n) if !n.ty.signed => {
+                            builder
+                                .ins()
+                                .icmp(int_cc.unsigned(), final_left, final_right)
+                        }
+                        _ => builder.ins().icmp(int_cc, final_left, final_right),
+                    };
+                    return Ok((result_type, result));
+                }
+
+                if let CalcValue::BigInt(_) = operand_type {
+                    let result = self.compile_big_binary(
+                        module,
+                        builder,
+                        *op,
+                        [final_left, final_right],
+                        spans,
+                    )?;
+                    return Ok((result_type, result));
+                }
+                if let CalcValue::Rational(_) = operand_type {
+                    let result = self.compile_rational_binary(
+                        module,
+                        builder,
+                        *op,
+                        [final_left, final_right],
+                        spans,
+                    )?;
+                    return Ok((result_type, result));
+                }
+                if let CalcValue::Decimal(_) = operand_type {
+                    let result = self.compile_decimal_binary(
+                        module,
+                        builder,
+                        *op,
+                        [final_left, final_right],
+                        spans,
+                    )?;
+                    return Ok((result_type, result));
+                }
+                if let CalcValue::Sized(n) = operand_type {
+                    let result = self.compile_sized_binary(
+                        module,
+                        builder,
+                        *op,
+                        (n.ty, [final_left, final_right]),
+                        spans,
+                    )?;
+                    return Ok((result_type, result));
+                }
+                if let CalcValue::Complex(_) = operand_type {
+                    let result = self.compile_complex_binary(
+                        module,
+                        builder,
+                        *op,
+                        [final_left, final_right],
+                    )?;
+                    return Ok((result_type, result));
+                }
+
+                let needs_float = matches!(operand_type, CalcValue::Float(_));
+                let result = match (op, needs_float) {
+                    (BinaryOpKind::Add | BinaryOpKind::Subtract, false) => {
+                        let (result, overflowed) = match op {
+                            BinaryOpKind::Add => {
+                                builder.ins().sadd_overflow(final_left, final_right)
+                            }
+                            _ => builder.ins().ssub_overflow(final_left, final_right),
+                        };
+                        let below =
+                            move |builder: &mut FunctionBuilder| wrapped_below(builder, result);
+                        self.check_overflow(
+                            module,
+                            builder,
+                            (result, overflowed),
+                            (Trap::Overflow, below),
+                            spans.operator,
+                        )
+                    }
+                    (BinaryOpKind::Multiply, false) => {
+                        let product = builder.ins().smul_overflow(final_left, final_right);
+                        let below = move |builder: &mut FunctionBuilder| {
+                            product_below(builder, final_left, final_right)
+                        };
+                        self.check_overflow(
+                            module,
+                            builder,
+                            product,
+                            (Trap::Overflow, below),
+                            spans.operator,
+                        )
+                    }
+                    (BinaryOpKind::Add, true) => builder.ins().fadd(final_left, final_right),
+                    (BinaryOpKind::Subtract, true) => builder.ins().fsub(final_left, final_right),
+                    (BinaryOpKind::Multiply, true) => builder.ins().fmul(final_left, final_right),
+                    (BinaryOpKind::Divide, _) => builder.ins().fdiv(final_left, final_right),
+                    (BinaryOpKind::FloorDivide, false) => {
+                        let is_zero = builder.ins().icmp_imm(IntCC::Equal, final_right, 0);
+                        self.trap_if(module, builder, is_zero, Trap::DivisionByZero, spans.right);
+                        // The one quotient that doesn't fit: i64::MIN // -1
+                        let min_dividend =
+                            builder.ins().icmp_imm(IntCC::Equal, final_left, i64::MIN);
+                        let minus_one = builder.ins().icmp_imm(IntCC::Equal, final_right, -1);
+                        let overflows = builder.ins().band(min_dividend, minus_one);
+                        // `sdiv` would fault on it, so that one is divided by 1
+                        // instead, which gives the wrapped quotient
+                        let one = builder.ins().iconst(types::I64, 1);
+                        let divisor = builder.ins().select(overflows, one, final_right);
+                        let quotient = floor_div(builder, final_left, divisor);
+                        self.check_overflow(
+                            module,
+                            builder,
+                            (quotient, overflows),
+                            (Trap::Overflow, constant_below(false)),
+                            spans.operator,
+                        )
+                    }
+                    (BinaryOpKind::FloorDivide, true) => {
+                        let quotient = builder.ins().fdiv(final_left, final_right);
+                        builder.ins().floor(quotient)
+                    }
+                    (BinaryOpKind::Power, false) => {
+                        let negative =
+                            builder
+                                .ins()
+                                .icmp_imm(IntCC::SignedLessThan, final_right, 0);
+                        self.trap_if(
+                            module,
+                            builder,
+                            negative,
+                            Trap::NegativeExponent,
+                            spans.right,
+                        );
+                        let power = int_pow(builder, final_left, final_right);
+                        let below = move |builder: &mut FunctionBuilder| {
+                            power_below(builder, final_left, final_right)
+                        };
+                        self.check_overflow(
+                            module,
+                            builder,
+                            power,
+                            (Trap::Overflow, below),
+                            spans.operator,
+                        )
+                    }
+                    (BinaryOpKind::Power, true) => {
+                        // Start over with complex powers if this one is
+                        let not_real = is_complex_power(builder, final_left, final_right);
+                        self.trap_if(module, builder, not_real, Trap::NotReal, spans.operator);
+                        self.call_symbol(
+                            module,
+                            builder,
+                            "calc_pow",
+                            &[final_left, final_right],
+                            types::F64,
+                        )?
+                    }
+                    (BinaryOpKind::Modulo, false) => {
+                        let is_zero = builder.ins().icmp_imm(IntCC::Equal, final_right, 0);
+                        self.trap_if(module, builder, is_zero, Trap::DivisionByZero, spans.right);
+                        builder.ins().srem(final_left, final_right)
+                    }
+                    (BinaryOpKind::Modulo, true) => self.call_symbol(
+                        module,
+                        builder,
+                        "calc_fmod",
+                        &[final_left, final_right],
+                        types::F64,
+                    )?,
+                    (BinaryOpKind::BitAnd, false) => builder.ins().band(final_left, final_right),
+                    (BinaryOpKind::BitOr, false) => builder.ins().bor(final_left, final_right),
+                    (BinaryOpKind::BitXor, false) => builder.ins().bxor(final_left, final_right),
+                    (BinaryOpKind::ShiftLeft, false) => {
+                        self.check_shift(module, builder, (64, final_right), spans);
+                        let shifted = shift_left(builder, (final_left, final_right), true);
+                        let below =
+                            move |builder: &mut FunctionBuilder| shift_below(builder, final_left);
+                        self.check_overflow(
+                            module,
+                            builder,
+                            shifted,
+                            (Trap::Overflow, below),
+                            spans.operator,
+                        )
+                    }
+                    (BinaryOpKind::ShiftRight, false) => {
+                        self.check_shift(module, builder, (64, final_right), spans);
+                        builder.ins().sshr(final_left, final_right)
+                    }
+                    (
+                        BinaryOpKind::BitAnd
+                        | BinaryOpKind::BitOr
+                        | BinaryOpKind::BitXor
+                        | BinaryOpKind::ShiftLeft
+                        | BinaryOpKind::ShiftRight,
+                        true,
+                    ) => unreachable!("rejected by binary_type"),
+                    (
+                        BinaryOpKind::Equal
+                        | BinaryOpKind::NotEqual
+                        | BinaryOpKind::Less
+                        | BinaryOpKind::LessEqual
+                        | BinaryOpKind::Greater
+                        | BinaryOpKind::GreaterEqual
+                        | BinaryOpKind::And
+                        | BinaryOpKind::Or,
+                        _,
+                    ) => unreachable!("lowered above"),
+                };
+
+                Ok((result_type, result))
+            }
+            Expr::Parenthesized(inner) => self.compile_node(input, module, builder, scope, inner),
+            Expr::Percent { meaning, .. } => {
+                self.compile_node(input, module, builder, scope, meaning)
+            }
+            Expr::Cast {
+                value,
+                target,
+                span,
+            } => {
+                let (value_type, v) = self.compile_node(input, module, builder, scope, value)?;
+                let ty = self.cast_type(&value_type, *target, *span)?;
+                let result = self.compile_cast(module, builder, (&value_type, v), &ty, *span)?;
+                Ok((ty, result))
+            }
+            Expr::Unary { op, operand, span } => {
+                let (operand_val, operand_ir) =
+                    self.compile_node(input, module, builder, scope, operand)?;
+                let ty = self.unary_type(*op, &operand_val, *span)?;
+                let result = match (op, &operand_val) {
+                    (UnaryOpKind::Plus, _) => operand_ir,
+                    (
+                        UnaryOpKind::Negate,
+                        CalcValue::Float(_) | CalcValue::Complex(_) | CalcValue::Quantity(..),
+                    ) => builder.ins().fneg(operand_ir),
+                    (UnaryOpKind::Negate, CalcValue::BigInt(_)) => self.call_symbol(
+                        module,
+                        builder,
+                        "calc_big_neg",
+                        &[operand_ir],
+                        types::I64,
+                    )?,
+                    (UnaryOpKind::Negate, CalcValue::Rational(_)) => self.call_symbol(
+                        module,
+                        builder,
+                        "calc_rat_neg",
+                        &[operand_ir],
+                        types::I64,
+                    )?,
+                    // Only `i128::MIN` is negative both before and after
+                    (UnaryOpKind::Negate, CalcValue::Decimal(_)) => {
+                        let negated = builder.ins().ineg(operand_ir);
+                        let both = builder.ins().band(operand_ir, negated);
+                        let overflowed = builder.ins().icmp_imm(IntCC::SignedLessThan, both, 0);
+                        self.trap_if(module, builder, overflowed, Trap::DecimalOverflow, *span);
+                        negated
+                    }
+                    (UnaryOpKind::Negate, CalcValue::Sized(n)) => {
+                        let zero = int_constant(builder, n.ty.ir_type(), 0);
+                        let (negated, overflowed) = match n.ty.signed {
+                            true => builder.ins().ssub_overflow(zero, operand_ir),
+                            false => builder.ins().usub_overflow(zero, operand_ir),
+                        };
+                        let signed = n.ty.signed;
+                        let below = move |builder: &mut FunctionBuilder| match signed {
+                            true => wrapped_below(builder, negated),
+                            false => constant_below(true)(builder),
+                        };
+                        self.check_overflow(
+                            module,
+                            builder,
+                            (negated, overflowed),
+                            (Trap::SizedOverflow(n.ty), below),
+                            *span,
+                        )
+                    }
+                    (UnaryOpKind::Negate, _) => {
+                        let zero = builder.ins().iconst(types::I64, 0);
+                        let (negated, overflowed) = builder.ins().ssub_overflow(zero, operand_ir);
+                        let below =
+                            move |builder: &mut FunctionBuilder| wrapped_below(builder, negated);
+                        self.check_overflow(
+                            module,
+                            builder,
+                            (negated, overflowed),
+                            (Trap::Overflow, below),
+                            *span,
+                        )
+                    }
+                    (UnaryOpKind::BitNot, CalcValue::BigInt(_)) => self.call_symbol(
+                        module,
+                        builder,
+                        "calc_big_not",
+                        &[operand_ir],
+                        types::I64,
+                    )?,
+                    (UnaryOpKind::BitNot, _) => builder.ins().bnot(operand_ir),
+                    (UnaryOpKind::Not, _) => builder.ins().icmp_imm(IntCC::Equal, operand_ir, 0),
+                };
+                Ok((ty, result))
+            }
+            Expr::Variable { name, span } => {
+                if let Some((ty, v)) = scope.get(name) {
+                    return Ok((ty.clone(), *v));
+                }
+                let (data_id, ty) = match self.environment.borrow().variable(name) {
+                    Some(variable) => (variable.data_id, variable.ty.clone()),
+                    None => match Unit::named(name) {
+                        Some(unit) => {
+                            let v = builder.ins().f64const(unit.scale);
+                            return Ok((CalcValue::Quantity(1.0, unit), v));
+                        }
+                        None => Err(self.undefined_variable(name, *span))?,
+                    },
+                };
+                let address = self.data_address(module, builder, data_id);
+                let v = builder
+                    .ins()
+                    .load(ir_type(&ty), MemFlags::trusted(), address, 0);
+                if matches!(ty, CalcValue::Integer(_)) && self.environment.borrow().promotion().wide
+                {
+                    let big = CalcValue::BigInt(BigInt::ZERO);
+                    let v = self.convert(module, builder, &ty, &big, v)?;
+                    return Ok((big, v));
+                }
+                Ok((ty, v))
+            }
+            Expr::Assign { name, value } => {
+                let (value_type, value_ir) =
+                    self.compile_node(input, module, builder, scope, value)?;
+                let data_id = self
+                    .environment
+                    .borrow_mut()
+                    .bind_variable(module, name, &value_type)
+                    .map_err(|e| CalculatorError {
+                        src: self.source.clone(),
+                        span: (0, 0).into(),
+                        kind: CalcErrorKind::JitError(e.to_string()),
+                        help: None,
+                    })?;
+                let address = self.data_address(module, builder, data_id);
+                builder
+                    .ins()
+                    .store(MemFlags::trusted(), value_ir, address, 0);
+                Ok((value_type, value_ir))
+            }
+            Expr::Call { name, args, span } => {
+                let callee = self.callee(name, args.len(), *span)?;
+                let args = args
+                    .iter()
+                    .map(|arg| self.compile_node(input, module, builder, scope, arg))
+                    .collect::<MietteResult<Vec<_>>>()?;
+
+                let (params, body) = match callee {
+                    Callee::User { params, body } => (params, body),
+                    Callee::Builtin(builtin) => {
+                        return self.compile_builtin(module, builder, *span, builtin, args)
+                    }
+                };
+                let (arg_types, arg_values): (Vec<_>, Vec<_>) = args.into_iter().unzip();
+
+                let (func_id, return_type) = self
+                    .specialize(module, name, &params, &body, &arg_types)
+                    .map_err(|e| self.at_call_site(name, *span, e))?;
+                // Count the call in progress, so that recursion that never
+                // stops traps here rather than overflowing the stack
+                let depth_id = self.environment.borrow().depth();
+                let depth_address = self.data_address(module, builder, depth_id);
+                let depth = builder
+                    .ins()
+                    .load(types::I64, MemFlags::trusted(), depth_address, 0);
+                let too_deep =
+                    builder
+                        .ins()
+                        .icmp_imm(IntCC::SignedGreaterThanOrEqual, depth, MAX_CALL_DEPTH);
+                self.trap_if(module, builder, too_deep, Trap::RecursionLimit, *span);
+                let deeper = builder.ins().iadd_imm(depth, 1);
+                builder
+                    .ins()
+                    .store(MemFlags::trusted(), deeper, depth_address, 0);
+
+                let func_ref = module.declare_func_in_func(func_id, builder.func);
+                let call = builder.ins().call(func_ref, &arg_values);
+                let result = builder.inst_results(call)[0];
+                builder
+                    .ins()
+                    .store(MemFlags::trusted(), depth, depth_address, 0);
+
+                // Bail out as well if the callee hit a trap
+                let fault = self.fault_address(module, builder);
+                let fired = builder
+                    .ins()
+                    .load(types::I64, MemFlags::trusted(), fault, 0);
+                self.bail_out_if(builder, fired);
+                Ok((return_type, result))
+            }
+            Expr::Conditional {
+                condition,
+                consequence,
+                alternative,
+                spans,
+            } => {
+                // Branches are promoted before jumping to the merge block, so
+                // their common type has to be known up front
+                let types: Scope<CalcValue> = scope
+                    .iter()
+                    .map(|(name, (ty, _))| (name.clone(), ty.clone()))
+                    .collect();
+                let (consequence_type, _) = self.determine_type_in(consequence, &types)?;
+                let (alternative_type, _) = self.determine_type_in(alternative, &types)?;
+                let result_type =
+                    self.unify_branches(&consequence_type, &alternative_type, spans)?;
+
+                let (condition_type, condition_ir) =
+                    self.compile_node(input, module, builder, scope, condition)?;
+                if !matches!(condition_type, CalcValue::Bool(_)) {
+                    Err(self.condition_mismatch(&condition_type, spans.condition))?
+                }
+
+                let then_block = builder.create_block();
+                let else_block = builder.create_block();
+                let merge_block = builder.create_block();
+                builder.append_block_param(merge_block, ir_type(&result_type));
+                builder
+                    .ins()
+                    .brif(condition_ir, then_block, &[], else_block, &[]);
+
+                for (block, branch, span) in [
+                    (then_block, consequence, spans.consequence),
+                    (else_block, alternative, spans.alternative),
+                ] {
+                    builder.switch_to_block(block);
+                    builder.seal_block(block);
+                    let (branch_type, branch_ir) =
+                        self.compile_node(input, module, builder, scope, branch)?;
+                    let branch_ir = match result_type {
+                        CalcValue::Sized(n) => {
+                            self.fit(module, builder, (&branch_type, branch_ir), n.ty, span)?
+                        }
+                        _ => {
+                            self.convert(module, builder, &branch_type, &result_type, branch_ir)?
+                        }
+                    };
+                    builder.ins().jump(merge_block, &[branch_ir]);
+                }
+
+                builder.switch_to_block(merge_block);
+                builder.seal_block(merge_block);
+                Ok((result_type, builder.block_params(merge_block)[0]))
+            }
+            // A loop whose block takes the counter and the running total:
+            //   entry: start > end ? exit(identity) : body(start, identity)
+            //   body(i, total): total = total op term(i);
+            //                   i == end ? exit(total) : body(i + 1, total)
+            // Comparing before incrementing keeps `end == i64::MAX` finite
+            Expr::Aggregate {
+                kind,
+                variable,
+                start,
+                end,
+                body,
+                spans,
+            } => {
+                let (start_type, start_ir) =
+                    self.compile_node(input, module, builder, scope, start)?;
+                let (end_type, end_ir) = self.compile_node(input, module, builder, scope, end)?;
+                let wide = self.environment.borrow().promotion().wide;
+                let counter = match wide {
+                    true => CalcValue::BigInt(BigInt::ZERO),
+                    false => CalcValue::Integer(0),
+                };
+                // The total's type has to be known before the loop, like a
+                // conditional's
+                let mut types: Scope<CalcValue> = scope
+                    .iter()
+                    .map(|(name, (ty, _))| (name.clone(), ty.clone()))
+                    .collect();
+                types.insert(variable.clone(), counter.clone());
+                let (body_type, _) = self.determine_type_in(body, &types)?;
+                let total_type =
+                    self.aggregate_type(*kind, [&start_type, &end_type], &body_type, spans)?;
+
+                let start_ir = self.narrow(module, builder, &start_type, start_ir, spans.start)?;
+                let end_ir = self.narrow(module, builder, &end_type, end_ir, spans.end)?;
+                let (op, identity) = kind.operator();
+                let identity = match &total_type {
+                    CalcValue::Sized(n) => int_constant(builder, n.ty.ir_type(), identity.into()),
+                    _ => {
+                        let identity = builder.ins().iconst(types::I64, identity);
+                        self.convert(
+                            module,
+                            builder,
+                            &CalcValue::Integer(0),
+                            &total_type,
+                            identity,
+                        )?
+                    }
+                };
+
+                let body_block = builder.create_block();
+                let exit_block = builder.create_block();
+                builder.append_block_param(body_block, types::I64);
+                builder.append_block_param(body_block, ir_type(&total_type));
+                builder.append_block_param(exit_block, ir_type(&total_type));
+                let empty = builder
+                    .ins()
+                    .icmp(IntCC::SignedGreaterThan, start_ir, end_ir);
+                builder.ins().brif(
+                    empty,
+                    exit_block,
+                    &[identity],
+                    body_block,
+                    &[start_ir, identity],
+                );
+
+                builder.switch_to_block(body_block);
+                let [i, total] = [0, 1].map(|n| builder.block_params(body_block)[n]);
+                let i_ir = self.convert(module, builder, &CalcValue::Integer(0), &counter, i)?;
+                let mut scope = scope.clone();
+                scope.insert(variable.clone(), (counter, i_ir));
+                let (term_type, term) = self.compile_node(input, module, builder, &scope, body)?;
+                let term = self.convert(module, builder, &term_type, &total_type, term)?;
+                let spans = BinarySpans {
+                    left: spans.function,
+                    operator: spans.function,
+                    right: spans.body,
+                };
+                // Combined like the binary operator would
+                let total = match (&total_type, op) {
+                    (CalcValue::Integer(_), BinaryOpKind::Add) => {
+                        let (sum, overflowed) = builder.ins().sadd_overflow(total, term);
+                        let below =
+                            move |builder: &mut FunctionBuilder| wrapped_below(builder, sum);
+                        self.check_overflow(
+                            module,
+                            builder,
+                            (sum, overflowed),
+                            (Trap::Overflow, below),
+                            spans.operator,
+                        )
+                    }
+                    (CalcValue::Integer(_), _) => {
+                        let product = builder.ins().smul_overflow(total, term);
+                        let below = move |builder: &mut FunctionBuilder| {
+                            product_below(builder, total, term)
+                        };
+                        self.check_overflow(
+                            module,
+                            builder,
+                            product,
+                            (Trap::Overflow, below),
+                            spans.operator,
+                        )
+                    }
+                    (CalcValue::BigInt(_), _) => {
+                        self.compile_big_binary(module, builder, op, [total, term], &spans)?
+                    }
+                    (CalcValue::Rational(_), _) => {
+                        self.compile_rational_binary(module, builder, op, [total, term], &spans)?
+                    }
+                    (CalcValue::Decimal(_), _) => {
+                        self.compile_decimal_binary(module, builder, op, [total, term], &spans)?
+                    }
+                    (CalcValue::Complex(_), _) => {
+                        self.compile_complex_binary(module, builder, op, [total, term])?
+                    }
+                    (CalcValue::Sized(n), _) => self.compile_sized_binary(
+                        module,
+                        builder,
+                        op,
+                        (n.ty, [total, term]),
+                        &spans,
+                    )?,
+                    (_, BinaryOpKind::Add) => builder.ins().fadd(total, term),
+                    _ => builder.ins().fmul(total, term),
+                };
+                let done = builder.ins().icmp(IntCC::Equal, i, end_ir);
+                let next = builder.ins().iadd_imm(i, 1);
+                builder
+                    .ins()
+                    .brif(done, exit_block, &[total], body_block, &[next, total]);
+                builder.seal_block(body_block);
+
+                builder.switch_to_block(exit_block);
+                builder.seal_block(exit_block);
+                Ok((total_type, builder.block_params(exit_block)[0]))
+            }
+            Expr::FunctionDef { .. } => Err(self.nested_definition())?,
+        }
+    }
+
+    /// A loop bound or cast integer as an `i64`, trapping with
+    /// `Trap::Overflow` when a big integer one doesn't fit.
+    fn narrow(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        ty: &CalcValue,
+        value: Value,
+        span: SourceSpan,
+    ) -> MietteResult<Value> {
+        if !matches!(ty, CalcValue::BigInt(_)) {
+            return Ok(value);
+        }
+        let fits = self.call_symbol(module, builder, "calc_big_fits_i64", &[value], types::I64)?;
+        let too_large = builder.ins().icmp_imm(IntCC::Equal, fits, 0);
+        self.trap_if(module, builder, too_large, Trap::Overflow, span);
+        self.call_symbol(module, builder, "calc_big_to_i64", &[value], types::I64)
+    }
+
+    /// `value` of type `from` (an integer, boolean or sized integer) as a
+    /// sized integer of type `ty`, trapping with `Trap::SizedOverflow` at
+    /// `span` when it is out of range rather than wrapping.
+    fn fit(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        (from, value): (&CalcValue, Value),
+        ty: IntType,
+        span: SourceSpan,
+    ) -> MietteResult<Value> {
+        let overflow = Trap::SizedOverflow(ty);
+        let (source, value) = match from {
+            CalcValue::Sized(n) => (n.ty, value),
+            CalcValue::Bool(_) => (IntType::I64, builder.ins().uextend(types::I64, value)),
+            CalcValue::BigInt(_) => {
+                let signed = builder.ins().iconst(types::I64, i64::from(ty.signed));
+                let bits = builder.ins().iconst(types::I64, i64::from(ty.bits));
+                let fits = self.call_symbol(
+                    module,
+                    builder,
+                    "calc_big_fits_int",
+                    &[value, signed, bits],
+                    types::I64,
+                )?;
+                let out_of_range = builder.ins().icmp_imm(IntCC::Equal, fits, 0);
+                self.trap_if(module, builder, out_of_range, overflow, span);
+                let n =
+                    self.call_symbol(module, builder, "calc_big_to_int", &[value], types::I128)?;
+                return Ok(match ty.bits {
+                    128 => n,
+                    _ => builder.ins().ireduce(ty.ir_type(), n),
+                });
+            }
+            _ => (IntType::I64, value),
+        };
+
+        // Checked at the source's width, where both bounds can be written
+        let source_type = source.ir_type();
+        let mut checks = Vec::new();
+        if source.min() < ty.min() {
+            let min = int_constant(builder, source_type, ty.min());
+            checks.push(builder.ins().icmp(IntCC::SignedLessThan, value, min));
+        }
+        if source.max() > ty.max() {
+            let max = int_constant(builder, source_type, ty.max() as i128);
+            let cc = match source.signed {
+                true => IntCC::SignedGreaterThan,
+                false => IntCC::UnsignedGreaterThan,
+            };
+            checks.push(builder.ins().icmp(cc, value, max));
+        }
+        if let Some(out_of_range) = checks.into_iter().reduce(|a, b| builder.ins().bor(a, b)) {
+            self.trap_if(module, builder, out_of_range, overflow, span);
+        }
+        Ok(match source.bits.cmp(&ty.bits) {
+            std::cmp::Ordering::Less if source.signed => builder.ins().sextend(ty.ir_type(), value),
+            std::cmp::Ordering::Less => builder.ins().uextend(ty.ir_type(), value),
+            std::cmp::Ordering::Greater => builder.ins().ireduce(ty.ir_type(), value),
+            std::cmp::Ordering::Equal => value,
+        })
+    }
+
+    /// `value` of type `from` cast to `to` (as given by `cast_type`). Floats
+    /// that aren't finite have no integer to go to, and trap at `span`.
+    fn compile_cast(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        (from, value): (&CalcValue, Value),
+        to: &CalcValue,
+        span: SourceSpan,
+    ) -> MietteResult<Value> {
+        Ok(match (from, to) {
+            (
+                CalcValue::Bool(_)
+                | CalcValue::Integer(_)
+                | CalcValue::BigInt(_)
+                | CalcValue::Sized(_),
+                CalcValue::Sized(n),
+            ) => self.fit(module, builder, (from, value), n.ty, span)?,
+            (CalcValue::Bool(_), _) => {
+                let n = builder.ins().uextend(types::I64, value);
+                self.convert(module, builder, &CalcValue::Integer(0), to, n)?
+            }
+            (
+                CalcValue::Float(_),
+                CalcValue::Integer(_) | CalcValue::BigInt(_) | CalcValue::Sized(_),
+            ) => {
+                // `x - x` is only NaN when `x` is NaN or infinite
+                let difference = builder.ins().fsub(value, value);
+                let not_finite = builder
+                    .ins()
+                    .fcmp(FloatCC::Unordered, difference, difference);
+                self.trap_if(module, builder, not_finite, Trap::NotFinite, span);
+                if let CalcValue::BigInt(_) = to {
+                    return self.call_symbol(
+                        module,
+                        builder,
+                        "calc_big_from_f64",
+                        &[value],
+                        types::I64,
+                    );
+                }
+                if let CalcValue::Sized(n) = to {
+                    return self.float_to_sized(module, builder, value, n.ty, span);
+                }
+                // The saturating conversion would hide an overflow, so check
+                // first: `-2^63` fits, `2^63` doesn't
+                let limit = builder.ins().f64const(2_f64.powi(63));
+                let too_large = builder
+                    .ins()
+                    .fcmp(FloatCC::GreaterThanOrEqual, value, limit);
+                let limit = builder.ins().fneg(limit);
+                let too_small = builder.ins().fcmp(FloatCC::LessThan, value, limit);
+                let out_of_range = builder.ins().bor(too_large, too_small);
+                self.trap_if(module, builder, out_of_range, Trap::Overflow, span);
+                builder.ins().fcvt_to_sint_sat(types::I64, value)
+            }
+            (
+                CalcValue::Rational(_) | CalcValue::Decimal(_),
+                CalcValue::Integer(_) | CalcValue::BigInt(_) | CalcValue::Sized(_),
+            ) => {
+                let truncated = match from {
+                    CalcValue::Decimal(d) => {
+                        let places = builder.ins().iconst(types::I64, i64::from(d.places));
+                        self.call_symbol(
+                            module,
+                            builder,
+                            "calc_dec_trunc",
+                            &[value, places],
+                            types::I64,
+                        )?
+                    }
+                    _ => {
+                        self.call_symbol(module, builder, "calc_rat_trunc", &[value], types::I64)?
+                    }
+                };
+                let big = CalcValue::BigInt(BigInt::ZERO);
+                match to {
+                    CalcValue::BigInt(_) => truncated,
+                    CalcValue::Sized(n) => {
+                        self.fit(module, builder, (&big, truncated), n.ty, span)?
+                    }
+                    _ => self.narrow(module, builder, &big, truncated, span)?,
+                }
+            }
+            _ => self.convert(module, builder, from, to, value)?,
+        })
+    }
+
+    /// A finite float truncated toward zero as a sized integer of type `ty`,
+    /// trapping with `Trap::SizedOverflow` at `span` when it is out of range.
+    fn float_to_sized(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        value: Value,
+        ty: IntType,
+        span: SourceSpan,
+    ) -> MietteResult<Value> {
+        // Both bounds are powers of two (or zero), so exact as floats
+        let truncated = builder.ins().trunc(value);
+        let min = builder.ins().f64const(ty.min() as f64);
+        let too_small = builder.ins().fcmp(FloatCC::LessThan, truncated, min);
+        let limit = builder.ins().f64const(ty.max() as f64 + 1.0);
+        let too_large = builder
+            .ins()
+            .fcmp(FloatCC::GreaterThanOrEqual, truncated, limit);
+        let out_of_range = builder.ins().bor(too_small, too_large);
+        let overflow = Trap::SizedOverflow(ty);
+        self.trap_if(module, builder, out_of_range, overflow, span);
+        let n = match (ty.bits, ty.signed) {
+            (128, _) => {
+                return self.call_symbol(
+                    module,
+                    builder,
+                    "calc_int_from_f64",
+                    &[value],
+                    types::I128,
+                );
+            }
+            (_, true) => builder.ins().fcvt_to_sint_sat(types::I64, value),
+            (_, false) => builder.ins().fcvt_to_uint_sat(types::I64, value),
+        };
+        Ok(match ty.bits {
+            64 => n,
+            _ => builder.ins().ireduce(ty.ir_type(), n),
+        })
+    }
+
+    fn compile_builtin(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        span: SourceSpan,
+        builtin: Builtin,
+        args: Vec<(CalcValue, Value)>,
+    ) -> MietteResult<(CalcValue, Value)> {
+        let arg_types: Vec<CalcValue> = args.iter().map(|(ty, _)| ty.clone()).collect();
+        let return_type = self.builtin_type(builtin, &arg_types, span)?;
+        // Quantities are worked on as floats
+        let result_type = match &return_type {
+            CalcValue::Quantity(..) => CalcValue::Float(0.0),
+            ty => ty.clone(),
+        };
+
+        if let (Builtin::Abs, [(CalcValue::Complex(_), z)]) = (builtin, args.as_slice()) {
+            let result =
+                self.call_symbol(module, builder, "calc_complex_abs", &[*z], types::F64)?;
+            return Ok((return_type, result));
+        }
+
+        // Promote arguments the same way binary operations do
+        let args = args
+            .into_iter()
+            .map(|(ty, v)| match result_type {
+                CalcValue::Sized(n) => self.fit(module, builder, (&ty, v), n.ty, span),
+                _ => self.convert(module, builder, &ty, &result_type, v),
+            })
+            .collect::<MietteResult<Vec<_>>>()?;
+
+        if let CalcValue::Complex(_) = result_type {
+            let symbol = builtin
+                .complex_symbol()
+                .expect("only builtins defined on complex numbers return them");
+            let result = self.call_symbol(module, builder, symbol, &args, types::F64X2)?;
+            return Ok((return_type, result));
+        }
+
+        // Negative arguments only have complex results, so start over with those
+        let not_real = match builtin {
+            Builtin::Sqrt | Builtin::Ln => {
+                let zero = builder.ins().f64const(0.0);
+                Some(builder.ins().fcmp(FloatCC::LessThan, args[0], zero))
+            }
+            Builtin::Pow => Some(is_complex_power(builder, args[0], args[1])),
+            _ => None,
+        };
+        if let Some(not_real) = not_real {
+            self.trap_if(module, builder, not_real, Trap::NotReal, span);
+        }
+
+        if let Some(symbol) = builtin.symbol() {
+            let result = self.call_symbol(module, builder, symbol, &args, types::F64)?;
+            return Ok((return_type, result));
+        }
+
+        let result = match (builtin, &result_type) {
+            (Builtin::Sqrt, _) => builder.ins().sqrt(args[0]),
+            (Builtin::Abs, CalcValue::Float(_)) => builder.ins().fabs(args[0]),
+            (Builtin::Abs, CalcValue::BigInt(_)) => {
+                self.call_symbol(module, builder, "calc_big_abs", &args, types::I64)?
+            }
+            (Builtin::Abs, CalcValue::Sized(n)) if !n.ty.signed => args[0],
+            // Only the minimum is still negative once negated
+            (Builtin::Abs, CalcValue::Sized(n)) => {
+                let zero = int_constant(builder, n.ty.ir_type(), 0);
+                let (negated, overflowed) = builder.ins().ssub_overflow(zero, args[0]);
+                let negative = builder.ins().icmp_imm(IntCC::SignedLessThan, args[0], 0);
+                let result = builder.ins().select(negative, negated, args[0]);
+                self.check_overflow(
+                    module,
+                    builder,
+                    (result, overflowed),
+                    (Trap::SizedOverflow(n.ty), constant_below(false)),
+                    span,
+                )
+            }
+            // Only `i64::MIN` is still negative once negated
+            (Builtin::Abs, _) => {
+                let zero = builder.ins().iconst(types::I64, 0);
+                let (negated, overflowed) = builder.ins().ssub_overflow(zero, args[0]);
+                let negative = builder.ins().icmp_imm(IntCC::SignedLessThan, args[0], 0);
+                let result = builder.ins().select(negative, negated, args[0]);
+                self.check_overflow(
+                    module,
+                    builder,
+                    (result, overflowed),
+                    (Trap::Overflow, constant_below(false)),
+                    span,
+                )
+            }
+            (Builtin::Floor, CalcValue::Float(_)) => builder.ins().floor(args[0]),
+            (Builtin::Ceil, CalcValue::Float(_)) => builder.ins().ceil(args[0]),
+            // Ties round to even
+            (Builtin::Round, CalcValue::Float(_)) => builder.ins().nearest(args[0]),
+            (Builtin::Floor | Builtin::Ceil | Builtin::Round, _) => args[0],
+            (Builtin::Min, CalcValue::Float(_)) => builder.ins().fmin(args[0], args[1]),
+            (Builtin::Max, CalcValue::Float(_)) => builder.ins().fmax(args[0], args[1]),
+            (Builtin::Min | Builtin::Max, CalcValue::BigInt(_)) => {
+                let ordering =
+                    self.call_symbol(module, builder, "calc_big_cmp", &args, types::I64)?;
+                let cc = match builtin {
+                    Builtin::Min => IntCC::SignedLessThan,
+                    _ => IntCC::SignedGreaterThan,
+                };
+                let first = builder.ins().icmp_imm(cc, ordering, 0);
+                builder.ins().select(first, args[0], args[1])
+            }
+            // Unsigned sized integers are compared without a sign
+            (Builtin::Min | Builtin::Max, ty) => {
+                let signed = !matches!(ty, CalcValue::Sized(n) if !n.ty.signed);
+                let cc = match (builtin, signed) {
+                    (Builtin::Min, true) => IntCC::SignedLessThan,
+                    (Builtin::Min, false) => IntCC::UnsignedLessThan,
+                    (_, true) => IntCC::SignedGreaterThan,
+                    (_, false) => IntCC::UnsignedGreaterThan,
+                };
+                let first = builder.ins().icmp(cc, args[0], args[1]);
+                builder.ins().select(first, args[0], args[1])
+            }
+            (Builtin::Sin | Builtin::Cos | Builtin::Exp | Builtin::Ln | Builtin::Pow, _) => {
+                unreachable!("lowered through their registered symbols")
+            }
+        };
+        Ok((return_type, result))
+    }
+
+    /// Lower an operation on two big integers to a call of its `bignum`
+    /// helper.
+    fn compile_big_binary(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        op: BinaryOpKind,
+        [left, right]: [Value; 2],
+        spans: &BinarySpans,
+    ) -> MietteResult<Value> {
+        if matches!(op, BinaryOpKind::FloorDivide | BinaryOpKind::Modulo) {
+            let sign = self.call_symbol(module, builder, "calc_big_sign", &[right], types::I64)?;
+            let is_zero = builder.ins().icmp_imm(IntCC::Equal, sign, 0);
+            self.trap_if(module, builder, is_zero, Trap::DivisionByZero, spans.right);
+        }
+        if matches!(
+            op,
+            BinaryOpKind::Power | BinaryOpKind::ShiftLeft | BinaryOpKind::ShiftRight
+        ) {
+            let sign = self.call_symbol(module, builder, "calc_big_sign", &[right], types::I64)?;
+            let negative = builder.ins().icmp_imm(IntCC::SignedLessThan, sign, 0);
+            let trap = match op {
+                BinaryOpKind::Power => Trap::NegativeExponent,
+                _ => Trap::ShiftOutOfRange(None),
+            };
+            self.trap_if(module, builder, negative, trap, spans.right);
+        }
+
+        let symbol = match op {
+            BinaryOpKind::Add => "calc_big_add",
+            BinaryOpKind::Subtract => "calc_big_sub",
+            BinaryOpKind::Multiply => "calc_big_mul",
+            BinaryOpKind::FloorDivide => "calc_big_floor_div",
+            BinaryOpKind::Modulo => "calc_big_rem",
+            BinaryOpKind::Power => "calc_big_pow",
+            BinaryOpKind::BitAnd => "calc_big_and",
+            BinaryOpKind::BitOr => "calc_big_or",
+            BinaryOpKind::BitXor => "calc_big_xor",
+            BinaryOpKind::ShiftLeft => "calc_big_shl",
+            BinaryOpKind::ShiftRight => "calc_big_shr",
+            _ => unreachable!("`{}` doesn't give a big integer", op.symbol()),
+        };
+        let result = self.call_symbol(module, builder, symbol, &[left, right], types::I64)?;
+
+        // Helpers that could run out of memory give up with null instead
+        if matches!(
+            op,
+            BinaryOpKind::Multiply
+                | BinaryOpKind::Power
+                | BinaryOpKind::ShiftLeft
+                | BinaryOpKind::ShiftRight
+        ) {
+            let too_large = builder.ins().icmp_imm(IntCC::Equal, result, 0);
+            self.trap_if(module, builder, too_large, Trap::TooLarge, spans.operator);
+        }
+        Ok(result)
+    }
+
+    /// Lower `+ - * /` on two rationals to a call of its `rational` helper.
+    fn compile_rational_binary(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        op: BinaryOpKind,
+        [left, right]: [Value; 2],
+        spans: &BinarySpans,
+    ) -> MietteResult<Value> {
+        let symbol = match op {
+            BinaryOpKind::Add => "calc_rat_add",
+            BinaryOpKind::Subtract => "calc_rat_sub",
+            BinaryOpKind::Multiply => "calc_rat_mul",
+            BinaryOpKind::Divide => {
+                let sign =
+                    self.call_symbol(module, builder, "calc_rat_sign", &[right], types::I64)?;
+                let is_zero = builder.ins().icmp_imm(IntCC::Equal, sign, 0);
+                self.trap_if(module, builder, is_zero, Trap::DivisionByZero, spans.right);
+                "calc_rat_div"
+            }
+            _ => unreachable!("`{}` doesn't give a rational", op.symbol()),
+        };
+        self.call_symbol(module, builder, symbol, &[left, right], types::I64)
+    }
+
+    /// Lower `+ - * /` on two decimals. Sums and differences are plain
+    /// `i128` arithmetic, while products and quotients go through their
+    /// `decimal` helper to be rounded back to the configured places. Either
+    /// way, results that don't fit trap with `Trap::DecimalOverflow`.
+    fn compile_decimal_binary(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        op: BinaryOpKind,
+        [left, right]: [Value; 2],
+        spans: &BinarySpans,
+    ) -> MietteResult<Value> {
+        let (result, overflowed) = match op {
+            // Overflowed when the sum's sign differs from both operands'
+            BinaryOpKind::Add => {
+                let sum = builder.ins().iadd(left, right);
+                let left_flipped = builder.ins().bxor(left, sum);
+                let right_flipped = builder.ins().bxor(right, sum);
+                let both = builder.ins().band(left_flipped, right_flipped);
+                (sum, builder.ins().icmp_imm(IntCC::SignedLessThan, both, 0))
+            }
+            // Overflowed when the operands' signs differ, and the
+            // difference's sign differs from the left one's
+            BinaryOpKind::Subtract => {
+                let difference = builder.ins().isub(left, right);
+                let signs = builder.ins().bxor(left, right);
+                let flipped = builder.ins().bxor(left, difference);
+                let both = builder.ins().band(signs, flipped);
+                (
+                    difference,
+                    builder.ins().icmp_imm(IntCC::SignedLessThan, both, 0),
+                )
+            }
+            BinaryOpKind::Multiply | BinaryOpKind::Divide => {
+                if op == BinaryOpKind::Divide {
+                    let is_zero = builder.ins().icmp_imm(IntCC::Equal, right, 0);
+                    self.trap_if(module, builder, is_zero, Trap::DivisionByZero, spans.right);
+                }
+                let symbol = match op {
+                    BinaryOpKind::Multiply => "calc_dec_mul",
+                    _ => "calc_dec_div",
+                };
+                let places = self.environment.borrow().places();
+                let places = builder.ins().iconst(types::I64, i64::from(places));
+                let result =
+                    self.call_symbol(module, builder, symbol, &[left, right, places], types::I128)?;
+                // Helpers give `i128::MIN` for results that don't fit
+                let min = i128_constant(builder, i128::MIN);
+                (result, builder.ins().icmp(IntCC::Equal, result, min))
+            }
+            _ => unreachable!("`{}` doesn't give a decimal", op.symbol()),
+        };
+        self.trap_if(
+            module,
+            builder,
+            overflowed,
+            Trap::DecimalOverflow,
+            spans.operator,
+        );
+        Ok(result)
+    }
+
+    /// Lower an arithmetic or bitwise operator on two sized integers of type
+    /// `ty`. Results that don't fit go by the overflow mode, trapping with
+    /// `Trap::SizedOverflow` unless it says otherwise. Cranelift has no
+    /// 128-bit `*` with an overflow flag, nor 128-bit division, so those go
+    /// through their `sized` helper, as `^` does for every width.
+    fn compile_sized_binary(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        op: BinaryOpKind,
+        (ty, [left, right]): (IntType, [Value; 2]),
+        spans: &BinarySpans,
+    ) -> MietteResult<Value> {
+        let overflow = Trap::SizedOverflow(ty);
+        let signed = builder.ins().iconst(types::I64, i64::from(ty.signed));
+        Ok(match op {
+            BinaryOpKind::Add | BinaryOpKind::Subtract | BinaryOpKind::Multiply => {
+                let (result, overflowed) = match (op, ty.signed) {
+                    (BinaryOpKind::Add, true) => builder.ins().sadd_overflow(left, right),
+                    (BinaryOpKind::Add, false) => builder.ins().uadd_overflow(left, right),
+                    (BinaryOpKind::Subtract, true) => builder.ins().ssub_overflow(left, right),
+                    (BinaryOpKind::Subtract, false) => builder.ins().usub_overflow(left, right),
+                    (_, _) if ty.bits == 128 => {
+                        let overflows = self.call_symbol(
+                            module,
+                            builder,
+                            "calc_int_mul_overflows",
+                            &[left, right, signed],
+                            types::I64,
+                        )?;
+                        let overflowed = builder.ins().icmp_imm(IntCC::NotEqual, overflows, 0);
+                        (builder.ins().imul(left, right), overflowed)
+                    }
+                    (_, true) => builder.ins().smul_overflow(left, right),
+                    (_, false) => builder.ins().umul_overflow(left, right),
+                };
+                let below = move |builder: &mut FunctionBuilder| match (op, ty.signed) {
+                    (BinaryOpKind::Multiply, true) => product_below(builder, left, right),
+                    (_, true) => wrapped_below(builder, result),
+                    (BinaryOpKind::Subtract, false) => constant_below(true)(builder),
+                    (_, false) => constant_below(false)(builder),
+                };
+                self.check_overflow(
+                    module,
+                    builder,
+                    (result, overflowed),
+                    (overflow, below),
+                    spans.operator,
+                )
+            }
+            BinaryOpKind::FloorDivide | BinaryOpKind::Modulo => {
+                let is_zero = builder.ins().icmp_imm(IntCC::Equal, right, 0);
+                self.trap_if(module, builder, is_zero, Trap::DivisionByZero, spans.right);
+                // The one quotient that doesn't fit: the type's minimum // -1,
+                // which is divided by 1 instead to give the wrapped quotient
+                let overflowed = match op == BinaryOpKind::FloorDivide && ty.signed {
+                    true => {
+                        let min = int_constant(builder, ty.ir_type(), ty.min());
+                        let minus_one = int_constant(builder, ty.ir_type(), -1);
+                        let min_dividend = builder.ins().icmp(IntCC::Equal, left, min);
+                        let minus_one = builder.ins().icmp(IntCC::Equal, right, minus_one);
+                        Some(builder.ins().band(min_dividend, minus_one))
+                    }
+                    false => None,
+                };
+                let right = match overflowed {
+                    Some(overflowed) => {
+                        let one = int_constant(builder, ty.ir_type(), 1);
+                        builder.ins().select(overflowed, one, right)
+                    }
+                    None => right,
+                };
+                let result = match (op, ty.bits, ty.signed) {
+                    (_, 128, _) => {
+                        let symbol = match op {
+                            BinaryOpKind::FloorDivide => "calc_int_floor_div",
+                            _ => "calc_int_rem",
+                        };
+                        self.call_symbol(
+                            module,
+                            builder,
+                            symbol,
+                            &[left, right, signed],
+                            types::I128,
+                        )?
+                    }
+                    (BinaryOpKind::FloorDivide, _, true) => floor_div(builder, left, right),
+                    (BinaryOpKind::FloorDivide, _, false) => builder.ins().udiv(left, right),
+                    (_, _, true) => builder.ins().srem(left, right),
+                    (_, _, false) => builder.ins().urem(left, right),
+                };
+                match overflowed {
+                    Some(overflowed) => self.check_overflow(
+                        module,
+                        builder,
+                        (result, overflowed),
+                        (overflow, constant_below(false)),
+                        spans.operator,
+                    ),
+                    None => result,
+                }
+            }
+            BinaryOpKind::Power => {
+                if ty.signed {
+                    let negative = builder.ins().icmp_imm(IntCC::SignedLessThan, right, 0);
+                    self.trap_if(
+                        module,
+                        builder,
+                        negative,
+                        Trap::NegativeExponent,
+                        spans.right,
+                    );
+                }
+                let base = widen(builder, ty, left);
+                let exponent = widen(builder, ty, right);
+                let bits = builder.ins().iconst(types::I64, i64::from(ty.bits));
+                let overflows = self.call_symbol(
+                    module,
+                    builder,
+                    "calc_int_pow_overflows",
+                    &[base, exponent, signed, bits],
+                    types::I64,
+                )?;
+                let overflowed = builder.ins().icmp_imm(IntCC::NotEqual, overflows, 0);
+                let power = self.call_symbol(
+                    module,
+                    builder,
+                    "calc_int_pow",
+                    &[base, exponent, signed],
+                    types::I128,
+                )?;
+                let power = match ty.bits {
+                    128 => power,
+                    _ => builder.ins().ireduce(ty.ir_type(), power),
+                };
+                let below = move |builder: &mut FunctionBuilder| match ty.signed {
+                    true => power_below(builder, base, exponent),
+                    false => constant_below(false)(builder),
+                };
+                self.check_overflow(
+                    module,
+                    builder,
+                    (power, overflowed),
+                    (overflow, below),
+                    spans.operator,
+                )
+            }
+            BinaryOpKind::BitAnd => builder.ins().band(left, right),
+            BinaryOpKind::BitOr => builder.ins().bor(left, right),
+            BinaryOpKind::BitXor => builder.ins().bxor(left, right),
+            BinaryOpKind::ShiftLeft => {
+                self.check_shift(module, builder, (ty.bits, right), spans);
+                let shifted = shift_left(builder, (left, right), ty.signed);
+                let below = move |builder: &mut FunctionBuilder| match ty.signed {
+                    true => shift_below(builder, left),
+                    false => constant_below(false)(builder),
+                };
+                self.check_overflow(module, builder, shifted, (overflow, below), spans.operator)
+            }
+            BinaryOpKind::ShiftRight => {
+                self.check_shift(module, builder, (ty.bits, right), spans);
+                match ty.signed {
+                    true => builder.ins().sshr(left, right),
+                    false => builder.ins().ushr(left, right),
+                }
+            }
+            _ => unreachable!("`{}` doesn't give a sized integer", op.symbol()),
+        })
+    }
+
+    /// Lower `+ - * / ^` on two complex numbers. Powers go through their
+    /// `complex` helper, the rest work on the real and imaginary parts.
+    fn compile_complex_binary(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        op: BinaryOpKind,
+        [left, right]: [Value; 2],
+    ) -> MietteResult<Value> {
+        Ok(match op {
+            BinaryOpKind::Add => builder.ins().fadd(left, right),
+            BinaryOpKind::Subtract => builder.ins().fsub(left, right),
+            // (a + bi)(c + di) = (ac - bd) + (ad + bc)i
+            BinaryOpKind::Multiply => {
+                let [a, b] = split(builder, left);
+                let [c, d] = split(builder, right);
+                let ac = builder.ins().fmul(a, c);
+                let bd = builder.ins().fmul(b, d);
+                let ad = builder.ins().fmul(a, d);
+                let bc = builder.ins().fmul(b, c);
+                let re = builder.ins().fsub(ac, bd);
+                let im = builder.ins().fadd(ad, bc);
+                join(builder, re, im)
+            }
+            // (a + bi) / (c + di) = ((ac + bd) + (bc - ad)i) / (c² + d²)
+            BinaryOpKind::Divide => {
+                let [a, b] = split(builder, left);
+                let [c, d] = split(builder, right);
+                let ac = builder.ins().fmul(a, c);
+                let bd = builder.ins().fmul(b, d);
+                let bc = builder.ins().fmul(b, c);
+                let ad = builder.ins().fmul(a, d);
+                let cc = builder.ins().fmul(c, c);
+                let dd = builder.ins().fmul(d, d);
+                let re = builder.ins().fadd(ac, bd);
+                let im = builder.ins().fsub(bc, ad);
+                let scale = builder.ins().fadd(cc, dd);
+                let re = builder.ins().fdiv(re, scale);
+                let im = builder.ins().fdiv(im, scale);
+                join(builder, re, im)
+            }
+            BinaryOpKind::Power => self.call_symbol(
+                module,
+                builder,
+                "calc_complex_pow",
+                &[left, right],
+                types::F64X2,
+            )?,
+            _ => unreachable!("`{}` doesn't give a complex number", op.symbol()),
+        })
+    }
+
+    /// Bring `value` from type `from` to the at least as wide type `to`
+    /// (integer, then big integer, then rational, then float, then complex,
+    /// with decimals between integers and floats, and sized integers below
+    /// floats and big integers). Sized integers are brought to each other
+    /// with `fit` instead, which can trap.
+    fn convert(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        from: &CalcValue,
+        to: &CalcValue,
+        value: Value,
+    ) -> MietteResult<Value> {
+        Ok(match (from, to) {
+            // Quantities are floats in SI base units
+            (CalcValue::Quantity(..), _) => {
+                self.convert(module, builder, &CalcValue::Float(0.0), to, value)?
+            }
+            (_, CalcValue::Quantity(..)) => {
+                self.convert(module, builder, from, &CalcValue::Float(0.0), value)?
+            }
+            (CalcValue::Integer(_), CalcValue::Float(_)) => {
+                builder.ins().fcvt_from_sint(types::F64, value)
+            }
+            (CalcValue::Integer(_), CalcValue::BigInt(_)) => {
+                self.call_symbol(module, builder, "calc_big_from_i64", &[value], types::I64)?
+            }
+            (CalcValue::BigInt(_), CalcValue::Float(_)) => {
+                self.call_symbol(module, builder, "calc_big_to_f64", &[value], types::F64)?
+            }
+            (CalcValue::Integer(_), CalcValue::Rational(_)) => {
+                self.call_symbol(module, builder, "calc_rat_from_i64", &[value], types::I64)?
+            }
+            (CalcValue::BigInt(_), CalcValue::Rational(_)) => {
+                self.call_symbol(module, builder, "calc_rat_from_big", &[value], types::I64)?
+            }
+            (CalcValue::Rational(_), CalcValue::Float(_)) => {
+                self.call_symbol(module, builder, "calc_rat_to_f64", &[value], types::F64)?
+            }
+            // Always fits, as there are at most 18 places
+            (CalcValue::Integer(_), CalcValue::Decimal(d)) => {
+                let value = builder.ins().sextend(types::I128, value);
+                let scale = i128_constant(builder, 10_i128.pow(d.places));
+                builder.ins().imul(value, scale)
+            }
+            (CalcValue::Decimal(d), CalcValue::Float(_)) => {
+                let places = builder.ins().iconst(types::I64, i64::from(d.places));
+                self.call_symbol(
+                    module,
+                    builder,
+                    "calc_dec_to_f64",
+                    &[value, places],
+                    types::F64,
+                )?
+            }
+            (CalcValue::Sized(n), CalcValue::Float(_)) => match (n.ty.bits, n.ty.signed) {
+                (128, signed) => {
+                    let signed = builder.ins().iconst(types::I64, i64::from(signed));
+                    self.call_symbol(
+                        module,
+                        builder,
+                        "calc_int_to_f64",
+                        &[value, signed],
+                        types::F64,
+                    )?
+                }
+                (64, false) => builder.ins().fcvt_from_uint(types::F64, value),
+                _ => {
+                    let value =
+                        self.convert(module, builder, from, &CalcValue::Integer(0), value)?;
+                    builder.ins().fcvt_from_sint(types::F64, value)
+                }
+            },
+            (CalcValue::Sized(n), CalcValue::BigInt(_)) => {
+                let value = widen(builder, n.ty, value);
+                let signed = builder.ins().iconst(types::I64, i64::from(n.ty.signed));
+                self.call_symbol(
+                    module,
+                    builder,
+                    "calc_int_to_big",
+                    &[value, signed],
+                    types::I64,
+                )?
+            }
+            // Only types that fit get here
+            (CalcValue::Sized(n), CalcValue::Integer(_)) => match (n.ty.bits, n.ty.signed) {
+                (64, _) => value,
+                (_, true) => builder.ins().sextend(types::I64, value),
+                (_, false) => builder.ins().uextend(types::I64, value),
+            },
+            (CalcValue::Complex(_), _) => value,
+            (_, CalcValue::Complex(_)) => {
+                let re = self.convert(module, builder, from, &CalcValue::Float(0.0), value)?;
+                let im = builder.ins().f64const(0.0);
+                join(builder, re, im)
+            }
+            _ => value,
+        })
+    }
+
+    fn big_constant(&self, builder: &mut FunctionBuilder, value: BigInt) -> Value {
+        let ptr = self.environment.borrow_mut().constant(value);
+        builder.ins().iconst(types::I64, ptr as i64)
+    }
+
+    /// Pass through the result of an overflow-checked instruction (wrapped,
+    /// when it overflowed), doing what the overflow mode says when it did:
+    /// trapping with `trap` at `span`, keeping it, or saturating to the
+    /// limit of the type `trap` is for that `below` says it went past.
+    fn check_overflow(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        (result, overflowed): (Value, Value),
+        (trap, below): (Trap, impl FnOnce(&mut FunctionBuilder) -> Value),
+        span: SourceSpan,
+    ) -> Value {
+        let mode = self.environment.borrow().overflow();
+        match mode {
+            OverflowMode::Promote | OverflowMode::Checked => {
+                self.trap_if(module, builder, overflowed, trap, span);
+                result
+            }
+            OverflowMode::Wrapping => result,
+            OverflowMode::Saturating => {
+                let ty = match trap {
+                    Trap::SizedOverflow(ty) => ty,
+                    _ => IntType::I64,
+                };
+                let below = below(builder);
+                let min = int_constant(builder, ty.ir_type(), ty.min());
+                let max = int_constant(builder, ty.ir_type(), ty.max() as i128);
+                let limit = builder.ins().select(below, min, max);
+                builder.ins().select(overflowed, limit, result)
+            }
+        }
+    }
+
+    /// Trap on a shift `amount` that is negative, or not below the `bits`
+    /// of the integer being shifted, rather than letting the hardware mask
+    /// it. Negative amounts are huge ones as far as an unsigned comparison
+    /// goes.
+    fn check_shift(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        (bits, amount): (u32, Value),
+        spans: &BinarySpans,
+    ) {
+        let out_of_range =
+            builder
+                .ins()
+                .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, amount, i64::from(bits));
+        self.trap_if(
+            module,
+            builder,
+            out_of_range,
+            Trap::ShiftOutOfRange(Some(bits)),
+            spans.right,
+        );
+    }
+
+    /// Compile a runtime check: when `condition` holds, record `trap` at
+    /// `span` and return from the function being compiled.
+    fn trap_if(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        condition: Value,
+        trap: Trap,
+        span: SourceSpan,
+    ) {
+        let site = self
+            .environment
+            .borrow_mut()
+            .add_trap_site(TrapSite { trap, span });
+
+        let trap_block = builder.create_block();
+        let continue_block = builder.create_block();
+        builder
+            .ins()
+            .brif(condition, trap_block, &[], continue_block, &[]);
+
+        builder.switch_to_block(trap_block);
+        builder.seal_block(trap_block);
+        let fault = self.fault_address(module, builder);
+        let site = builder.ins().iconst(types::I64, site);
+        builder.ins().store(MemFlags::trusted(), site, fault, 0);
+        self.return_placeholder(builder);
+
+        builder.switch_to_block(continue_block);
+        builder.seal_block(continue_block);
+    }
+
+    /// Return from the function being compiled when `condition` is non-zero,
+    /// leaving the recorded trap for the caller.
+    fn bail_out_if(&self, builder: &mut FunctionBuilder, condition: Value) {
+        let bail_block = builder.create_block();
+        let continue_block = builder.create_block();
+        builder
+            .ins()
+            .brif(condition, bail_block, &[], continue_block, &[]);
+
+        builder.switch_to_block(bail_block);
+        builder.seal_block(bail_block);
+        self.return_placeholder(builder);
+
+        builder.switch_to_block(continue_block);
+        builder.seal_block(continue_block);
+    }
+
+    /// Return zeroes of the current function's return types; the caller
+    /// ignores them once it sees the fault.
+    fn return_placeholder(&self, builder: &mut FunctionBuilder) {
+        let returns: Vec<Value> = builder
+            .func
+            .signature
+            .returns
+            .clone()
+            .iter()
+            .map(|param| match param.value_type {
+                types::F64 => builder.ins().f64const(0.0),
+                types::F64X2 => {
+                    let zero = builder.ins().f64const(0.0);
+                    builder.ins().splat(types::F64X2, zero)
+                }
+                types::I128 => i128_constant(builder, 0),
+                ty => builder.ins().iconst(ty, 0),
+            })
+            .collect();
+        builder.ins().return_(&returns);
+    }
+
+    fn fault_address(&self, module: &mut JITModule, builder: &mut FunctionBuilder) -> Value {
+        let fault = self.environment.borrow().fault();
+        self.data_address(module, builder, fault)
+    }
+
+    fn trap_error(
+        &self,
+        fault: i64,
+        compiled_fn: &CompiledFunction,
+        ast: &Expr,
+    ) -> CalculatorError {
+        let environment = self.environment.borrow();
+        // Traps in the body of a function point into its definition
+        let (site, source) = match compiled_fn.trap_sites.get(&fault) {
+            Some(site) => (site, None),
+            None => match environment.function_trap_site(fault) {
+                Some((site, source)) => (site, Some(source)),
+                None => {
+                    return CalculatorError {
+                        src: self.source.clone(),
+                        span: (0, 0).into(),
+                        kind: CalcErrorKind::SystemError(format!("Unknown trap {}", fault)),
+                        help: None,
+                    }
+                }
+            },
+        };
+        let (kind, help): (_, String) = match site.trap {
+            Trap::DivisionByZero => (
+                CalcErrorKind::DivisionByZero,
+                "The right operand evaluated to zero".into(),
+            ),
+            Trap::Overflow => (
+                CalcErrorKind::Overflow,
+                "The result doesn't fit in a 64-bit integer".into(),
+            ),
+            Trap::NegativeExponent => (
+                CalcErrorKind::NegativeExponent,
+                "Integer powers need an exponent of at least zero; use a float base, \
+                 as in `2.0 ^ n`, for a fraction"
+                    .into(),
+            ),
+            Trap::ShiftOutOfRange(Some(bits)) => (
+                CalcErrorKind::ShiftOutOfRange,
+                format!(
+                    "A {}-bit integer can only be shifted by 0 to {}",
+                    bits,
+                    bits - 1
+                ),
+            ),
+            Trap::ShiftOutOfRange(None) => (
+                CalcErrorKind::ShiftOutOfRange,
+                "Integers can't be shifted by a negative amount".into(),
+            ),
+            Trap::RecursionLimit => (
+                CalcErrorKind::RecursionLimit,
+                format!(
+                    "Calls can only be nested {} deep; check that the recursion reaches a base case",
+                    MAX_CALL_DEPTH
+                ),
+            ),
+            Trap::TooLarge => (
+                CalcErrorKind::Overflow,
+                "Big integers are limited to about a million bits".into(),
+            ),
+            Trap::NotReal => (CalcErrorKind::NotReal, "The result is a complex number".into()),
+            Trap::DecimalOverflow => (
+                CalcErrorKind::DecimalOverflow,
+                "The result doesn't fit in a decimal with this many places".into(),
+            ),
+            Trap::NotFinite => (
+                CalcErrorKind::NotFinite,
+                "Only finite floats can be cast to an integer".into(),
+            ),
+            Trap::SizedOverflow(ty) => (CalcErrorKind::SizedOverflow(ty), ty.describe()),
+        };
+        let (src, span) = match source {
+            Some(source) => (
+                NamedSource::new("calculator", source.to_string()),
+                site.span,
+            ),
+            None => (
+                self.source.clone(),
+                self.trap_span(site.span, compiled_fn, ast)
+                    .unwrap_or(site.span),
+            ),
+        };
+        CalculatorError {
+            src,
+            span,
+            kind,
+            help: Some(help),
+        }
+    }
+
+    /// Where a trap at `span` in the code compiled for `compiled_fn` is in
+    /// `ast`, which may have moved since (e.g. after a comment before it
+    /// changed), as the cache ignores spans.
+    fn trap_span(
+        &self,
+        span: SourceSpan,
+        compiled_fn: &CompiledFunction,
+        ast: &Expr,
+    ) -> Option<SourceSpan> {
+        let index = compiled_fn.spans.iter().position(|s| *s == span)?;
+        let mut current = Vec::new();
+        collect_spans(ast, &mut current);
+        current.get(index).copied()
+    }
+
+    /// Call one of the helpers registered on the `JITBuilder`, which
+    /// returns a `returns`. Complex numbers (`F64X2`) and decimals (`I128`)
+    /// are passed and returned as their two parts.
+    fn call_symbol(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        symbol: &str,
+        args: &[Value],
+        returns: Type,
+    ) -> MietteResult<Value> {
+        let args: Vec<Value> = args
+            .iter()
+            .flat_map(|arg| match builder.func.dfg.value_type(*arg) {
+                types::F64X2 => split(builder, *arg).to_vec(),
+                types::I128 => {
+                    let (low, high) = builder.ins().isplit(*arg);
+                    vec![low, high]
+                }
+                _ => vec![*arg],
+            })
+            .collect();
+        let mut signature = module.make_signature();
+        signature.params.extend(
+            args.iter()
+                .map(|arg| AbiParam::new(builder.func.dfg.value_type(*arg))),
+        );
+        match returns {
+            types::F64X2 => signature.returns.extend([AbiParam::new(types::F64); 2]),
+            types::I128 => signature.returns.extend([AbiParam::new(types::I64); 2]),
+            _ => signature.returns.push(AbiParam::new(returns)),
+        }
+
+        let func_id = module
+            .declare_function(symbol, Linkage::Import, &signature)
+            .map_err(|e| CalculatorError {
+                src: self.source.clone(),
+                span: (0, 0).into(),
+                kind: CalcErrorKind::JitError(e.to_string()),
+                help: None,
+            })?;
+        let func_ref = module.declare_func_in_func(func_id, builder.func);
+        let call = builder.ins().call(func_ref, &args);
+        Ok(match returns {
+            types::F64X2 => {
+                let [re, im] = [0, 1].map(|i| builder.inst_results(call)[i]);
+                join(builder, re, im)
+            }
+            types::I128 => {
+                let [low, high] = [0, 1].map(|i| builder.inst_results(call)[i]);
+                builder.ins().iconcat(low, high)
+            }
+            _ => builder.inst_results(call)[0],
+        })
+    }
+
+    fn data_address(
+        &self,
+        module: &mut JITModule,
+        builder: &mut FunctionBuilder,
+        data_id: DataId,
+    ) -> cranelift::prelude::Value {
+        let global = module.declare_data_in_func(data_id, builder.func);
+        let pointer_type = module.target_config().pointer_type();
+        builder.ins().global_value(pointer_type, global)
     }
 
     fn cleanup_cache(&self) {
@@ -599,228 +5250,2423 @@ impl Calculator {
         });
     }
 
-    fn hash_ast(&self, expr: &Expr) -> u64 {
-        let mut hasher = AHasher::default();
-        expr.hash(&mut hasher);
-        hasher.finish()
-    }
-}
+    fn hash_ast(&self, expr: &Expr) -> u64 {
+        let mut hasher = AHasher::default();
+        expr.hash(&mut hasher);
+        let environment = self.environment.borrow();
+        environment.generation().hash(&mut hasher);
+        environment.promotion().hash(&mut hasher);
+        environment.overflow().hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::error::{CalcErrorKind, CalculatorError};
+    use crossterm::terminal;
+    use miette::GraphicalReportHandler;
+
+    // Helper function to create a controlled test environment
+    fn setup_test_calculator() -> Calculator {
+        Calculator::new().expect("Failed to create calculator")
+    }
+
+    fn eval(calc: &mut Calculator, input: &str) -> MietteResult<CalcValue> {
+        calc.update_input(input, 0, 0, input.len())
+    }
+
+    // The kind and span of the error `input` fails with
+    fn error(calc: &mut Calculator, input: &str) -> (CalcErrorKind, SourceSpan) {
+        let report = eval(calc, input).unwrap_err();
+        let error = report.downcast::<CalculatorError>().unwrap();
+        (error.kind, error.span)
+    }
+
+    mod parser_tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_single_number() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("42", 0, 0, 2);
+            assert!(matches!(result, Ok(CalcValue::Integer(42))));
+        }
+
+        #[test]
+        fn test_parse_single_float() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("42.5", 0, 0, 4);
+            assert!(matches!(result, Ok(CalcValue::Float(42.5))));
+        }
+
+        #[test]
+        fn test_parse_negative_number() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("-42", 0, 0, 3);
+            assert!(matches!(result, Ok(CalcValue::Integer(-42))));
+        }
+
+        #[test]
+        fn test_parse_invalid_input() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("abc", 0, 0, 3);
+            assert!(matches!(result, Err(_)));
+        }
+    }
+
+    mod expression_tests {
+        use super::*;
+
+        #[test]
+        fn test_simple_addition() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("5 + 3", 0, 0, 5);
+            assert!(matches!(result, Ok(CalcValue::Integer(8))));
+        }
+
+        #[test]
+        fn test_simple_multiplication() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("5 * 3", 0, 0, 5);
+            assert!(matches!(result, Ok(CalcValue::Integer(15))));
+        }
+
+        #[test]
+        fn test_mixed_operations() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("2 + 3 * 4", 0, 0, 9);
+            assert!(matches!(result, Ok(CalcValue::Integer(14))));
+        }
+
+        #[test]
+        fn test_float_operations() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("2.5 + 3.5", 0, 0, 9);
+            if let Ok(CalcValue::Float(val)) = result {
+                assert!((val - 6.0).abs() < f64::EPSILON);
+            } else {
+                panic!("Expected float result");
+            }
+        }
+
+        #[test]
+        fn test_mixed_types() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("2 + 3.5", 0, 0, 7);
+            if let Ok(CalcValue::Float(val)) = result {
+                assert!((val - 5.5).abs() < f64::EPSILON);
+            } else {
+                panic!("Expected float result");
+            }
+        }
+    }
+
+    mod type_determination_tests {
+        use super::*;
+
+        #[test]
+        fn test_determine_type_integer() {
+            let calc = setup_test_calculator();
+            let expr = Expr::Integer(42);
+            let result = calc.determine_type(&expr);
+            assert!(matches!(result, Ok((CalcValue::Integer(42), false))));
+        }
+
+        #[test]
+        fn test_determine_type_float() {
+            let calc = setup_test_calculator();
+            let expr = Expr::Float(42.5);
+            let result = calc.determine_type(&expr);
+            assert!(matches!(result, Ok((CalcValue::Float(42.5), true))));
+        }
+
+        #[test]
+        fn test_determine_type_mixed_operation() {
+            let calc = setup_test_calculator();
+            let expr = Expr::BinaryOp {
+                left: Box::new(Expr::Integer(2)),
+                op: BinaryOpKind::Add,
+                right: Box::new(Expr::Float(3.5)),
+                spans: BinarySpans::default(),
+            };
+            let result = calc.determine_type(&expr);
+            assert!(matches!(result, Ok((CalcValue::Float(_), true))));
+        }
+    }
+
+    mod cache_tests {
+        use super::*;
+        use std::thread;
+        use std::time::Duration;
+
+        #[test]
+        fn test_cache_hit() {
+            let mut calc = setup_test_calculator();
+            // First evaluation should compile
+            let result1 = calc.update_input("2 + 3", 0, 0, 5);
+            assert!(matches!(result1, Ok(CalcValue::Integer(5))));
+
+            // Second evaluation should use cache
+            let result2 = calc.update_input("2 + 3", 0, 0, 5);
+            assert!(matches!(result2, Ok(CalcValue::Integer(5))));
+        }
+
+        #[test]
+        fn test_cache_cleanup() {
+            let mut calc = setup_test_calculator();
+            calc.update_input("2 + 3", 0, 0, 5).unwrap();
+
+            // Wait for cleanup thread to run
+            thread::sleep(Duration::from_secs(1));
+
+            // Check if cache was cleaned
+            assert!(calc.cache.function_cache.len() <= 1);
+        }
+    }
+
+    mod error_handling_tests {
+        use super::*;
+
+        #[test]
+        fn test_syntax_error() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("2 +", 0, 0, 3);
+            assert!(matches!(result, Err(_)));
+        }
+
+        #[test]
+        fn test_invalid_operator() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("2 _ 3", 0, 0, 5);
+            assert!(matches!(result, Err(_))); // First check if it's an error
+
+            if let Err(e) = result {
+                let diagnostic_msg = format!("{:?}", e);
+                assert!(diagnostic_msg.contains("Invalid operator")); // Then check the error message
+            } else {
+                panic!("Expected error for invalid operator");
+            }
+        }
+
+        #[test]
+        fn test_invalid_number() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("2.a", 0, 0, 3);
+            assert!(matches!(result, Err(_)));
+        }
+    }
+
+    mod incremental_update_tests {
+        use super::*;
+
+        #[test]
+        fn test_incremental_input() {
+            let mut calc = setup_test_calculator();
+
+            // Type "2"
+            let result1 = calc.update_input("2", 0, 0, 1);
+            assert!(matches!(result1, Ok(CalcValue::Integer(2))));
+
+            // Type "2 + "
+            let result2 = calc.update_input("2 + ", 1, 1, 4);
+            assert!(matches!(result2, Err(_))); // Incomplete expression
+
+            // Type "2 + 3"
+            let result3 = calc.update_input("2 + 3", 4, 4, 5);
+            assert!(matches!(result3, Ok(CalcValue::Integer(5))));
+        }
+
+        #[test]
+        fn test_backspace() {
+            let mut calc = setup_test_calculator();
+
+            // Type "2 + 3"
+            calc.update_input("2 + 3", 0, 0, 5).unwrap();
+
+            // Backspace to "2 + "
+            let result = calc.update_input("2 + ", 0, 5, 4);
+            assert!(matches!(result, Err(_))); // Incomplete expression
+        }
+    }
+
+    mod variable_tests {
+        use super::*;
+
+        #[test]
+        fn test_assignment_persists() {
+            let mut calc = setup_test_calculator();
+            let result = calc.update_input("x = 2 * 3", 0, 0, 9);
+            assert!(matches!(result, Ok(CalcValue::Integer(6))));
+            assert_eq!(calc.variable("x"), Some(CalcValue::Integer(6)));
+
+            let result = calc.update_input("x + 1", 0, 0, 5);
+            assert!(matches!(result, Ok(CalcValue::Integer(7))));
+        }
+
+        #[test]
+        fn test_cache_key_survives_value_change() {
+            let mut calc = setup_test_calculator();
+            calc.update_input("x = 1", 0, 0, 5).unwrap();
+
+            let tree = calc.parser.parse("x * 10", None).unwrap();
+            let expr = calc.node_to_expr("x * 10", tree.root_node()).unwrap();
+            let hash_before = calc.hash_ast(&expr);
+            assert!(matches!(
+                calc.update_input("x * 10", 0, 0, 6),
+                Ok(CalcValue::Integer(10))
+            ));
+
+            calc.update_input("x = 5", 0, 0, 5).unwrap();
+            assert_eq!(calc.hash_ast(&expr), hash_before);
+            assert!(matches!(
+                calc.update_input("x * 10", 0, 0, 6),
+                Ok(CalcValue::Integer(50))
+            ));
+        }
+
+        #[test]
+        fn test_variable_type_change() {
+            let mut calc = setup_test_calculator();
+            calc.update_input("x = 1", 0, 0, 5).unwrap();
+            assert!(matches!(
+                calc.update_input("x + 1", 0, 0, 5),
+                Ok(CalcValue::Integer(2))
+            ));
+
+            calc.update_input("x = 1.5", 0, 0, 7).unwrap();
+            if let Ok(CalcValue::Float(val)) = calc.update_input("x + 1", 0, 0, 5) {
+                assert!((val - 2.5).abs() < f64::EPSILON);
+            } else {
+                panic!("Expected float result");
+            }
+        }
+
+        #[test]
+        fn test_rollback_to_commit() {
+            let mut calc = setup_test_calculator();
+            calc.update_input("x = 1", 0, 0, 5).unwrap();
+            calc.update_input("b = 2^100", 0, 0, 9).unwrap();
+            calc.commit();
+
+            // The repl evaluates a line again after every edit
+            for _ in 0..3 {
+                calc.rollback();
+                calc.update_input("x = x + 1", 0, 0, 9).unwrap();
+                calc.update_input("b = b + 1", 0, 0, 9).unwrap();
+            }
+            assert_eq!(calc.variable("x"), Some(CalcValue::Integer(2)));
+            calc.rollback();
+            assert_eq!(calc.variable("x"), Some(CalcValue::Integer(1)));
+            assert_eq!(
+                calc.variable("b"),
+                Some(CalcValue::BigInt(BigInt::from(2).pow(100)))
+            );
+
+            // New variables and functions, and changed types, go too
+            calc.update_program("y = 2; x = 0.5; f(a) = a", 0, 0, 24);
+            calc.rollback();
+            assert_eq!(calc.variable("y"), None);
+            assert_eq!(
+                calc.update_input("x + 1", 0, 0, 5).unwrap(),
+                CalcValue::Integer(2)
+            );
+            let error = calc.update_input("f(1)", 0, 0, 4).unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::UndefinedFunction(_)));
+
+            calc.update_input("x = 5", 0, 0, 5).unwrap();
+            calc.commit();
+            calc.rollback();
+            assert_eq!(calc.variable("x"), Some(CalcValue::Integer(5)));
+        }
+
+        #[test]
+        fn test_undefined_variable() {
+            let output = simulate_calc_input("1 + y");
+            assert!(output.contains("Undefined variable: y"));
+            assert!(output.contains("y = 1"));
+        }
+    }
+
+    mod unary_tests {
+        use super::*;
+
+        fn eval(input: &str) -> MietteResult<CalcValue> {
+            setup_test_calculator().update_input(input, 0, 0, input.len())
+        }
+
+        #[test]
+        fn test_negate_expressions() {
+            assert_eq!(eval("-(2 + 3)").unwrap(), CalcValue::Integer(-5));
+            assert_eq!(eval("--4").unwrap(), CalcValue::Integer(4));
+            assert_eq!(eval("2 - -3").unwrap(), CalcValue::Integer(5));
+            assert_eq!(eval("-(1.5 * 2)").unwrap(), CalcValue::Float(-3.0));
+            assert_eq!(eval("+5").unwrap(), CalcValue::Integer(5));
+        }
+
+        #[test]
+        fn test_negate_binds_tighter_than_binary() {
+            assert_eq!(eval("-2 * 3 + 1").unwrap(), CalcValue::Integer(-5));
+            assert_eq!(eval("1 - -2 * 3").unwrap(), CalcValue::Integer(7));
+        }
+
+        #[test]
+        fn test_negative_literal_stays_literal() {
+            let mut calc = setup_test_calculator();
+            let tree = calc.parser.parse("-9223372036854775808", None).unwrap();
+            let expr = calc
+                .node_to_expr("-9223372036854775808", tree.root_node())
+                .unwrap();
+            assert!(matches!(expr, Expr::Integer(i64::MIN)));
+            assert_eq!(
+                calc.update_input("-9223372036854775808", 0, 0, 20).unwrap(),
+                CalcValue::Integer(i64::MIN)
+            );
+        }
+    }
+
+    mod power_tests {
+        use super::*;
+
+        fn eval(input: &str) -> MietteResult<CalcValue> {
+            setup_test_calculator().update_input(input, 0, 0, input.len())
+        }
+
+        #[test]
+        fn test_integer_power() {
+            assert_eq!(eval("2^10").unwrap(), CalcValue::Integer(1024));
+            assert_eq!(eval("2**10").unwrap(), CalcValue::Integer(1024));
+            assert_eq!(eval("(-3)^3").unwrap(), CalcValue::Integer(-27));
+            assert_eq!(eval("5^0").unwrap(), CalcValue::Integer(1));
+        }
+
+        #[test]
+        fn test_power_precedence_and_associativity() {
+            assert_eq!(eval("2^3^2").unwrap(), CalcValue::Integer(512));
+            assert_eq!(eval("2 * 3^2").unwrap(), CalcValue::Integer(18));
+            assert_eq!(eval("-2^2").unwrap(), CalcValue::Integer(-4));
+        }
+
+        #[test]
+        fn test_float_power() {
+            assert_eq!(eval("2^-1").unwrap(), CalcValue::Float(0.5));
+            assert_eq!(eval("4^0.5").unwrap(), CalcValue::Float(2.0));
+            assert_eq!(eval("1.5^2").unwrap(), CalcValue::Float(2.25));
+        }
+
+        #[test]
+        fn test_runtime_negative_exponent() {
+            let mut calc = setup_test_calculator();
+            calc.update_input("n = -2", 0, 0, 6).unwrap();
+            for (input, span) in [
+                ("2^n", (2, 1)),
+                ("2^(1 - 2)", (2, 7)),
+                ("10^30 + 2^n", (10, 1)),
+                ("2i8^n", (4, 1)),
+            ] {
+                let error = calc.update_input(input, 0, 0, input.len()).unwrap_err();
+                let error = error.downcast_ref::<CalculatorError>().unwrap();
+                assert!(matches!(error.kind, CalcErrorKind::NegativeExponent));
+                assert_eq!(error.span, span.into());
+            }
+            assert_eq!(
+                calc.update_input("2.0^n", 0, 0, 5).unwrap(),
+                CalcValue::Float(0.25)
+            );
+        }
+    }
+
+    mod modulo_tests {
+        use super::*;
+
+        #[test]
+        fn test_modulo() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(
+                calc.update_input("7 % 3", 0, 0, 5).unwrap(),
+                CalcValue::Integer(1)
+            );
+            assert_eq!(
+                calc.update_input("-7 % 3", 0, 0, 6).unwrap(),
+                CalcValue::Integer(-1)
+            );
+            assert_eq!(
+                calc.update_input("7.5 % 2", 0, 0, 7).unwrap(),
+                CalcValue::Float(1.5)
+            );
+        }
+
+        #[test]
+        fn test_modulo_by_zero_points_at_right_operand() {
+            let mut calc = setup_test_calculator();
+            calc.update_input("z = 0", 0, 0, 5).unwrap();
+
+            let error = calc.update_input("1 + 10 % (z * 2)", 0, 0, 16).unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::DivisionByZero));
+            assert_eq!(error.span, (9, 7).into());
+
+            // A cached copy of the same expression reports the current location
+            let error = calc
+                .update_input("1 + 10 %  (z * 2)", 0, 0, 17)
+                .unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert_eq!(error.span, (10, 7).into());
+
+            calc.update_input("z = 4", 0, 0, 5).unwrap();
+            assert_eq!(
+                calc.update_input("1 + 10 % (z * 2)", 0, 0, 16).unwrap(),
+                CalcValue::Integer(3)
+            );
+        }
+
+        #[test]
+        fn test_modulo_by_zero_inside_function() {
+            let mut calc = setup_test_calculator();
+            calc.update_input("m(a, b) = a % b", 0, 0, 15).unwrap();
+            let error = calc.update_input("1 + m(7, 0)", 0, 0, 11).unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::DivisionByZero));
+            assert_eq!(error.span, (14, 1).into());
+        }
+    }
+
+    mod bitwise_tests {
+        use super::*;
+
+        fn eval(input: &str) -> MietteResult<CalcValue> {
+            setup_test_calculator().update_input(input, 0, 0, input.len())
+        }
+
+        #[test]
+        fn test_bitwise_operators() {
+            assert_eq!(eval("12 & 10").unwrap(), CalcValue::Integer(8));
+            assert_eq!(eval("12 | 3").unwrap(), CalcValue::Integer(15));
+            assert_eq!(eval("6 xor 3").unwrap(), CalcValue::Integer(5));
+            assert_eq!(eval("~0").unwrap(), CalcValue::Integer(-1));
+            assert_eq!(eval("1 << 4").unwrap(), CalcValue::Integer(16));
+            assert_eq!(eval("-16 >> 2").unwrap(), CalcValue::Integer(-4));
+        }
+
+        #[test]
+        fn test_c_precedence() {
+            assert_eq!(eval("1 | 2 & 3").unwrap(), CalcValue::Integer(3));
+            assert_eq!(eval("1 << 2 + 1").unwrap(), CalcValue::Integer(8));
+            assert_eq!(eval("5 & 4 xor 1").unwrap(), CalcValue::Integer(5));
+            assert_eq!(eval("~1 & 7").unwrap(), CalcValue::Integer(6));
+        }
+
+        #[test]
+        fn test_shift_amount_out_of_range() {
+            for (input, span) in [
+                ("1 << 64", (5, 2)),
+                ("1 << -1", (5, 2)),
+                ("-1 >> 64", (6, 2)),
+                ("1u8 << 9", (7, 1)),
+                ("1i128 >> 128", (9, 3)),
+                ("2^100 << -1", (9, 2)),
+            ] {
+                let error = eval(input).unwrap_err();
+                let error = error.downcast_ref::<CalculatorError>().unwrap();
+                assert!(
+                    matches!(error.kind, CalcErrorKind::ShiftOutOfRange),
+                    "{}",
+                    input
+                );
+                assert_eq!(error.span, span.into(), "{}", input);
+            }
+            assert_eq!(eval("-1 >> 63").unwrap(), CalcValue::Integer(-1));
+        }
+
+        #[test]
+        fn test_float_operand_is_type_mismatch() {
+            let error = eval("1 + 2.5 & 3").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::TypeMismatch(_)));
+            assert_eq!(error.span, (8, 1).into());
+
+            let error = eval("~1.5").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::TypeMismatch(_)));
+            assert_eq!(error.span, (0, 1).into());
+        }
+    }
+
+    mod boolean_tests {
+        use super::*;
+
+        #[test]
+        fn test_comparisons() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(eval(&mut calc, "1 < 2").unwrap(), CalcValue::Bool(true));
+            assert_eq!(eval(&mut calc, "2 <= 1.5").unwrap(), CalcValue::Bool(false));
+            assert_eq!(eval(&mut calc, "3 == 3.0").unwrap(), CalcValue::Bool(true));
+            assert_eq!(
+                eval(&mut calc, "1 + 1 != 2").unwrap(),
+                CalcValue::Bool(false)
+            );
+            assert_eq!(
+                eval(&mut calc, "1 < 2 == true").unwrap(),
+                CalcValue::Bool(true)
+            );
+        }
+
+        #[test]
+        fn test_logical_operators_short_circuit() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "z = 0").unwrap();
+            assert_eq!(
+                eval(&mut calc, "z != 0 && 10 % z == 1").unwrap(),
+                CalcValue::Bool(false)
+            );
+            assert_eq!(
+                eval(&mut calc, "z == 0 || 10 % z == 1").unwrap(),
+                CalcValue::Bool(true)
+            );
+            assert_eq!(
+                eval(&mut calc, "!(z > 0) && true").unwrap(),
+                CalcValue::Bool(true)
+            );
+        }
+
+        #[test]
+        fn test_boolean_variables_and_functions() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "big = 10 > 3").unwrap();
+            assert_eq!(calc.variable("big"), Some(CalcValue::Bool(true)));
+            assert_eq!(eval(&mut calc, "!big").unwrap(), CalcValue::Bool(false));
+
+            eval(&mut calc, "even(n) = n == 0 || n != 1 && even(n - 2)").unwrap();
+            assert_eq!(eval(&mut calc, "even(10)").unwrap(), CalcValue::Bool(true));
+            assert_eq!(eval(&mut calc, "even(7)").unwrap(), CalcValue::Bool(false));
+        }
+
+        #[test]
+        fn test_mixing_booleans_and_numbers() {
+            let mut calc = setup_test_calculator();
+            let error = eval(&mut calc, "1 + (2 > 1)").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::TypeMismatch(_)));
+            assert_eq!(error.span, (2, 1).into());
+
+            let error = eval(&mut calc, "!1").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::TypeMismatch(_)));
+            assert_eq!(error.span, (0, 1).into());
+        }
+    }
+
+    mod conditional_tests {
+        use super::*;
+
+        #[test]
+        fn test_both_syntaxes() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(
+                eval(&mut calc, "if 1 < 2 then 10 else 20").unwrap(),
+                CalcValue::Integer(10)
+            );
+            assert_eq!(
+                eval(&mut calc, "1 > 2 ? 10 : 20").unwrap(),
+                CalcValue::Integer(20)
+            );
+            assert_eq!(
+                eval(&mut calc, "true ? 1 : false ? 2 : 3").unwrap(),
+                CalcValue::Integer(1)
+            );
+        }
+
+        #[test]
+        fn test_branches_are_promoted() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(
+                eval(&mut calc, "if true then 1 else 2.5").unwrap(),
+                CalcValue::Float(1.0)
+            );
+        }
+
+        #[test]
+        fn test_only_taken_branch_runs() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "z = 0").unwrap();
+            assert_eq!(
+                eval(&mut calc, "z == 0 ? -1 : 10 % z").unwrap(),
+                CalcValue::Integer(-1)
+            );
+        }
+
+        #[test]
+        fn test_recursive_factorial() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "fact(n) = if n <= 1 then 1 else n * fact(n - 1)").unwrap();
+            assert_eq!(
+                eval(&mut calc, "fact(10)").unwrap(),
+                CalcValue::Integer(3628800)
+            );
+            assert_eq!(eval(&mut calc, "fact(3.0)").unwrap(), CalcValue::Float(6.0));
+        }
+
+        #[test]
+        fn test_type_errors() {
+            let mut calc = setup_test_calculator();
+            let error = eval(&mut calc, "if 1 then 2 else 3").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::TypeMismatch(_)));
+            assert_eq!(error.span, (3, 1).into());
+
+            let error = eval(&mut calc, "true ? 1 : false").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::TypeMismatch(_)));
+            assert_eq!(error.span, (11, 5).into());
+        }
+    }
+
+    mod floor_division_tests {
+        use super::*;
+
+        #[test]
+        fn test_rounds_toward_negative_infinity() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(eval(&mut calc, "7 // 2").unwrap(), CalcValue::Integer(3));
+            assert_eq!(eval(&mut calc, "-7 // 2").unwrap(), CalcValue::Integer(-4));
+            assert_eq!(eval(&mut calc, "7 // -2").unwrap(), CalcValue::Integer(-4));
+            assert_eq!(eval(&mut calc, "-7 // -2").unwrap(), CalcValue::Integer(3));
+            assert_eq!(eval(&mut calc, "-6 // 3").unwrap(), CalcValue::Integer(-2));
+            assert_eq!(
+                eval(&mut calc, "-7.5 // 2").unwrap(),
+                CalcValue::Float(-4.0)
+            );
+            assert_eq!(eval(&mut calc, "7 / 2").unwrap(), CalcValue::Float(3.5));
+        }
+
+        #[test]
+        fn test_left_to_right_within_a_level() {
+            let mut calc = setup_test_calculator();
+            // `*`, `/`, `//` and `%` share a level, as do `+` and `-`
+            assert_eq!(eval(&mut calc, "7 % 4 * 2").unwrap(), CalcValue::Integer(6));
+            assert_eq!(
+                eval(&mut calc, "7 // 2 * 2").unwrap(),
+                CalcValue::Integer(6)
+            );
+            assert_eq!(
+                eval(&mut calc, "2 * 7 // 4").unwrap(),
+                CalcValue::Integer(3)
+            );
+            assert_eq!(eval(&mut calc, "2 * 3 % 4").unwrap(), CalcValue::Integer(2));
+            assert_eq!(eval(&mut calc, "6 / 2 * 3").unwrap(), CalcValue::Float(9.0));
+            assert_eq!(
+                eval(&mut calc, "9 / 3 // 2").unwrap(),
+                CalcValue::Float(1.0)
+            );
+            assert_eq!(eval(&mut calc, "1 - 2 + 3").unwrap(), CalcValue::Integer(2));
+            assert_eq!(
+                eval(&mut calc, "10 - 2 - 3").unwrap(),
+                CalcValue::Integer(5)
+            );
+        }
+
+        #[test]
+        fn test_division_by_zero() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "z = 0").unwrap();
+            let error = eval(&mut calc, "1 + 5 // z").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::DivisionByZero));
+            assert_eq!(error.span, (9, 1).into());
+        }
+
+        #[test]
+        fn test_overflow_falls_back_to_big_integers() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "m = -9223372036854775808").unwrap();
+            assert_eq!(
+                eval(&mut calc, "m // -1").unwrap(),
+                CalcValue::BigInt(BigInt::from(i64::MAX) + 1)
+            );
+            assert_eq!(
+                eval(&mut calc, "m // 2").unwrap(),
+                CalcValue::Integer(i64::MIN / 2)
+            );
+        }
+    }
+
+    mod literal_tests {
+        use super::*;
+
+        fn eval(input: &str) -> MietteResult<CalcValue> {
+            setup_test_calculator().update_input(input, 0, 0, input.len())
+        }
+
+        fn number_error(input: &str) -> (String, SourceSpan) {
+            let error = eval(input).unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            match &error.kind {
+                CalcErrorKind::NumberError(message) => (message.clone(), error.span),
+                kind => panic!("Expected a number error, got {:?}", kind),
+            }
+        }
+
+        #[test]
+        fn test_radix_prefixes_and_separators() {
+            assert_eq!(eval("0xFF").unwrap(), CalcValue::Integer(255));
+            assert_eq!(eval("0o755").unwrap(), CalcValue::Integer(493));
+            assert_eq!(eval("0b1010").unwrap(), CalcValue::Integer(10));
+            assert_eq!(eval("1_000_000").unwrap(), CalcValue::Integer(1_000_000));
+            assert_eq!(
+                eval("0xFFFF_FFFF").unwrap(),
+                CalcValue::Integer(0xFFFF_FFFF)
+            );
+            assert_eq!(
+                eval("-0x8000_0000_0000_0000").unwrap(),
+                CalcValue::Integer(i64::MIN)
+            );
+        }
+
+        #[test]
+        fn test_scientific_notation() {
+            assert_eq!(eval("1e-9").unwrap(), CalcValue::Float(1e-9));
+            assert_eq!(eval("6.02e23").unwrap(), CalcValue::Float(6.02e23));
+            assert_eq!(eval("2.5E+3").unwrap(), CalcValue::Float(2500.0));
+            assert_eq!(eval("1_000.5").unwrap(), CalcValue::Float(1000.5));
+        }
+
+        #[test]
+        fn test_errors_point_at_invalid_part() {
+            let (message, span) = number_error("1 + 0b102");
+            assert_eq!(message, "`2` is not a valid binary digit");
+            assert_eq!(span, (8, 1).into());
+
+            let (message, span) = number_error("0o78");
+            assert_eq!(message, "`8` is not a valid octal digit");
+            assert_eq!(span, (3, 1).into());
+
+            let (message, span) = number_error("1__000");
+            assert_eq!(message, "`_` has to be between two digits");
+            assert_eq!(span, (1, 1).into());
+
+            let (message, span) = number_error("0x");
+            assert_eq!(message, "`0x` has no digits");
+            assert_eq!(span, (0, 2).into());
+        }
+
+        #[test]
+        fn test_large_literals_are_big_integers() {
+            assert_eq!(
+                eval("0x8000_0000_0000_0000").unwrap(),
+                CalcValue::BigInt(BigInt::from(1_u64 << 63))
+            );
+            assert_eq!(
+                eval("-0x8000_0000_0000_0001").unwrap(),
+                CalcValue::BigInt(BigInt::from(i64::MIN) - 1)
+            );
+        }
+    }
+
+    mod bignum_tests {
+        use super::*;
+
+        fn big(digits: &str) -> CalcValue {
+            CalcValue::BigInt(digits.parse().unwrap())
+        }
+
+        #[test]
+        fn test_overflow_is_exact() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(
+                eval(&mut calc, "9223372036854775807 + 1").unwrap(),
+                big("9223372036854775808")
+            );
+            assert_eq!(
+                eval(&mut calc, "-9223372036854775807 - 2").unwrap(),
+                big("-9223372036854775809")
+            );
+            assert_eq!(
+                eval(&mut calc, "-(-9223372036854775808)").unwrap(),
+                big("9223372036854775808")
+            );
+            assert_eq!(
+                eval(&mut calc, "abs(-9223372036854775808)").unwrap(),
+                big("9223372036854775808")
+            );
+            assert_eq!(
+                eval(&mut calc, "2 ^ 100").unwrap(),
+                big("1267650600228229401496703205376")
+            );
+            assert_eq!(
+                eval(&mut calc, "3037000500 * 3037000500").unwrap(),
+                big("9223372037000250000")
+            );
+        }
+
+        #[test]
+        fn test_results_that_fit_are_integers() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(
+                eval(&mut calc, "2 ^ 64 - (2 ^ 64 - 1)").unwrap(),
+                CalcValue::Integer(1)
+            );
+            assert_eq!(
+                eval(&mut calc, "99999999999999999999 // 10 ^ 10").unwrap(),
+                CalcValue::Integer(9_999_999_999)
+            );
+        }
+
+        #[test]
+        fn test_mixed_with_floats_and_comparisons() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(
+                eval(&mut calc, "2 ^ 70 / 2 ^ 69").unwrap(),
+                CalcValue::Float(2.0)
+            );
+            assert_eq!(
+                eval(&mut calc, "2 ^ 64 > 9223372036854775807").unwrap(),
+                CalcValue::Bool(true)
+            );
+            assert_eq!(
+                eval(&mut calc, "if 2 ^ 64 > 0 then 2 ^ 64 else 0").unwrap(),
+                big("18446744073709551616")
+            );
+        }
+
+        #[test]
+        fn test_recursion() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "fact(n) = if n <= 1 then 1 else n * fact(n - 1)").unwrap();
+            assert_eq!(
+                eval(&mut calc, "fact(20)").unwrap(),
+                CalcValue::Integer(2_432_902_008_176_640_000)
+            );
+            assert_eq!(
+                eval(&mut calc, "fact(25)").unwrap(),
+                big("15511210043330985984000000")
+            );
+        }
+
+        #[test]
+        fn test_variables() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "x = 2 ^ 64").unwrap();
+            assert_eq!(calc.variable("x"), Some(big("18446744073709551616")));
+            assert_eq!(
+                eval(&mut calc, "x * x % 1000").unwrap(),
+                CalcValue::Integer(456)
+            );
+            eval(&mut calc, "y = x").unwrap();
+            eval(&mut calc, "x = 1").unwrap();
+            assert_eq!(calc.variable("y"), Some(big("18446744073709551616")));
+            assert_eq!(calc.variable("x"), Some(CalcValue::Integer(1)));
+        }
+
+        #[test]
+        fn test_errors() {
+            let mut calc = setup_test_calculator();
+            let error = eval(&mut calc, "2 ^ 70 % 0").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::DivisionByZero));
+            assert_eq!(error.span, (9, 1).into());
+
+            let error = eval(&mut calc, "2 ^ 10 ^ 10").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::Overflow));
+            assert_eq!(error.span, (2, 1).into());
+        }
+    }
+
+    mod rational_tests {
+        use super::*;
+
+        fn exact_calculator() -> Calculator {
+            let mut calc = setup_test_calculator();
+            calc.set_exact(true);
+            calc
+        }
+
+        fn ratio(numerator: i64, denominator: i64) -> CalcValue {
+            CalcValue::Rational(BigRational::new(numerator.into(), denominator.into()))
+        }
+
+        #[test]
+        fn test_division_is_exact() {
+            let mut calc = exact_calculator();
+            assert_eq!(eval(&mut calc, "1 / 3").unwrap(), ratio(1, 3));
+            assert_eq!(
+                eval(&mut calc, "(1 / 3) * 3").unwrap(),
+                CalcValue::Integer(1)
+            );
+            assert_eq!(eval(&mut calc, "1 / 3 + 1 / 6").unwrap(), ratio(1, 2));
+            assert_eq!(eval(&mut calc, "-(6 / 4)").unwrap(), ratio(-3, 2));
+            assert_eq!(eval(&mut calc, "(1 / 3) / (2 / 3)").unwrap(), ratio(1, 2));
+            assert_eq!(
+                eval(&mut calc, "2 ^ 70 / 3 - 2 ^ 70 / 3").unwrap(),
+                CalcValue::Integer(0)
+            );
+        }
+
+        #[test]
+        fn test_floats_and_comparisons() {
+            let mut calc = exact_calculator();
+            assert_eq!(
+                eval(&mut calc, "1 / 4 + 0.5").unwrap(),
+                CalcValue::Float(0.75)
+            );
+            assert_eq!(
+                eval(&mut calc, "sqrt(1 / 4)").unwrap(),
+                CalcValue::Float(0.5)
+            );
+            assert_eq!(
+                eval(&mut calc, "1 / 3 < 1 / 2").unwrap(),
+                CalcValue::Bool(true)
+            );
+            assert_eq!(
+                eval(&mut calc, "2 / 4 == 1 / 2").unwrap(),
+                CalcValue::Bool(true)
+            );
+            assert_eq!(
+                eval(&mut calc, "if 1 / 3 > 0 then 1 / 3 else 0").unwrap(),
+                ratio(1, 3)
+            );
+        }
+
+        #[test]
+        fn test_variables_and_functions() {
+            let mut calc = exact_calculator();
+            eval(&mut calc, "third = 1 / 3").unwrap();
+            assert_eq!(calc.variable("third"), Some(ratio(1, 3)));
+            eval(&mut calc, "half(x) = x / 2").unwrap();
+            assert_eq!(eval(&mut calc, "half(third)").unwrap(), ratio(1, 6));
+            assert_eq!(eval(&mut calc, "half(3)").unwrap(), ratio(3, 2));
+        }
+
+        #[test]
+        fn test_division_by_zero() {
+            let mut calc = exact_calculator();
+            let error = eval(&mut calc, "1 / (3 - 3)").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::DivisionByZero));
+            assert_eq!(error.span, (4, 7).into());
+        }
+
+        #[test]
+        fn test_opt_in() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(eval(&mut calc, "1 / 4").unwrap(), CalcValue::Float(0.25));
+            calc.set_exact(true);
+            assert_eq!(eval(&mut calc, "1 / 4").unwrap(), ratio(1, 4));
+            calc.set_exact(false);
+            assert_eq!(eval(&mut calc, "1 / 4").unwrap(), CalcValue::Float(0.25));
+        }
+    }
+
+    mod complex_tests {
+        use super::*;
+
+        fn complex(re: f64, im: f64) -> CalcValue {
+            CalcValue::Complex(Complex64::new(re, im))
+        }
+
+        #[test]
+        fn test_arithmetic() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(eval(&mut calc, "3 + 4i").unwrap(), complex(3.0, 4.0));
+            assert_eq!(eval(&mut calc, "-2.5j").unwrap(), complex(0.0, -2.5));
+            assert_eq!(
+                eval(&mut calc, "(1 + 2i) * (3 - 1i)").unwrap(),
+                complex(5.0, 5.0)
+            );
+            assert_eq!(
+                eval(&mut calc, "(1 + 2i) / (3 - 4i)").unwrap(),
+                complex(-0.2, 0.4)
+            );
+            assert_eq!(eval(&mut calc, "(1 + 2i) ^ 2").unwrap(), complex(-3.0, 4.0));
+            assert_eq!(eval(&mut calc, "-(1 + 2i)").unwrap(), complex(-1.0, -2.0));
+            assert_eq!(
+                eval(&mut calc, "abs(3 + 4i)").unwrap(),
+                CalcValue::Float(5.0)
+            );
+            assert_eq!(eval(&mut calc, "2i == 2j").unwrap(), CalcValue::Bool(true));
+            assert_eq!(
+                eval(&mut calc, "1 + 1i != 1").unwrap(),
+                CalcValue::Bool(true)
+            );
+        }
+
+        #[test]
+        fn test_real_results_are_floats() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(
+                eval(&mut calc, "(1 + 2i) * (1 - 2i)").unwrap(),
+                CalcValue::Float(5.0)
+            );
+            assert_eq!(eval(&mut calc, "1i * 1i").unwrap(), CalcValue::Float(-1.0));
+        }
+
+        #[test]
+        fn test_functions_of_negative_numbers() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(eval(&mut calc, "sqrt(-1)").unwrap(), complex(0.0, 1.0));
+            assert_eq!(eval(&mut calc, "sqrt(-4) + 1").unwrap(), complex(1.0, 2.0));
+            assert_eq!(
+                eval(&mut calc, "ln(-1)").unwrap(),
+                complex(0.0, std::f64::consts::PI)
+            );
+            let CalcValue::Complex(z) = eval(&mut calc, "(-8) ^ (1 / 3)").unwrap() else {
+                panic!("expected a complex cube root");
+            };
+            assert!((z - Complex64::new(1.0, 3_f64.sqrt())).norm() < 1e-12);
+            // Only the evaluation that needed them gets complex results
+            assert_eq!(eval(&mut calc, "sqrt(4)").unwrap(), CalcValue::Float(2.0));
+            assert_eq!(
+                eval(&mut calc, "(-8) ^ 2.0").unwrap(),
+                CalcValue::Float(64.0)
+            );
+        }
+
+        #[test]
+        fn test_variables_and_functions() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "z = 1 + 1i").unwrap();
+            assert_eq!(calc.variable("z"), Some(complex(1.0, 1.0)));
+            assert_eq!(eval(&mut calc, "z * z").unwrap(), complex(0.0, 2.0));
+            eval(&mut calc, "f(x) = sqrt(x) + 1").unwrap();
+            assert_eq!(eval(&mut calc, "f(-9)").unwrap(), complex(1.0, 3.0));
+            assert_eq!(eval(&mut calc, "f(9)").unwrap(), CalcValue::Float(4.0));
+            assert_eq!(
+                eval(&mut calc, "f(z)").unwrap(),
+                eval(&mut calc, "sqrt(z) + 1").unwrap()
+            );
+        }
+
+        #[test]
+        fn test_no_ordering() {
+            let mut calc = setup_test_calculator();
+            for input in [
+                "1i < 2",
+                "sqrt(-1) >= 0",
+                "5 // 2i",
+                "floor(1i)",
+                "max(1i, 2)",
+            ] {
+                let error = eval(&mut calc, input).unwrap_err();
+                let error = error.downcast_ref::<CalculatorError>().unwrap();
+                assert!(
+                    matches!(error.kind, CalcErrorKind::TypeMismatch(_)),
+                    "{}",
+                    input
+                );
+            }
+        }
+    }
+
+    mod unit_tests {
+        use super::*;
+
+        fn quantity(value: f64, unit: &str) -> CalcValue {
+            let mut calc = setup_test_calculator();
+            let CalcValue::Quantity(_, unit) = eval(&mut calc, &format!("1 {}", unit)).unwrap()
+            else {
+                panic!("`{}` is not a unit", unit);
+            };
+            CalcValue::Quantity(value, unit)
+        }
+
+        /// Where the type mismatch `input` raises is, and its help.
+        fn type_mismatch(calc: &mut Calculator, input: &str) -> (SourceSpan, Option<String>) {
+            let error = eval(calc, input).unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(
+                matches!(error.kind, CalcErrorKind::TypeMismatch(_)),
+                "{}",
+                input
+            );
+            (error.span, error.help.clone())
+        }
+
+        #[test]
+        fn test_arithmetic() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(
+                eval(&mut calc, "3 km + 500 m").unwrap(),
+                quantity(3.5, "km")
+            );
+            assert_eq!(eval(&mut calc, "2 * 3 km").unwrap(), quantity(6.0, "km"));
+            assert_eq!(eval(&mut calc, "-(2 h)").unwrap(), quantity(-2.0, "h"));
+            assert_eq!(eval(&mut calc, "(3 m) ^ 2").unwrap(), quantity(9.0, "m^2"));
+            assert_eq!(eval(&mut calc, "4 m^2").unwrap(), quantity(4.0, "m^2"));
+            assert_eq!(eval(&mut calc, "sqrt(9 m^2)").unwrap(), quantity(3.0, "m"));
+            assert_eq!(
+                eval(&mut calc, "1 km > 999 m").unwrap(),
+                CalcValue::Bool(true)
+            );
+            // Units that cancel out leave a plain number
+            assert_eq!(
+                eval(&mut calc, "3 km / 1 m").unwrap(),
+                CalcValue::Float(3000.0)
+            );
+        }
+
+        #[test]
+        fn test_conversion() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(
+                eval(&mut calc, "3 km / 20 min in km/h").unwrap(),
+                quantity(9.0, "km/h")
+            );
+            assert_eq!(
+                eval(&mut calc, "10 kg * 9.8 m/s^2 in N").unwrap(),
+                quantity(98.0, "N")
+            );
+            assert_eq!(
+                eval(&mut calc, "1 mi in ft").unwrap(),
+                quantity(5280.0, "ft")
+            );
+            assert_eq!(
+                eval(&mut calc, "3 km / 20 min in km/h")
+                    .unwrap()
+                    .to_string(),
+                "9 km/h"
+            );
+
+            let (span, _) = type_mismatch(&mut calc, "3 km in s");
+            assert_eq!(span, (5, 2).into());
+        }
+
+        #[test]
+        fn test_dimension_mismatch() {
+            let mut calc = setup_test_calculator();
+            let (span, help) = type_mismatch(&mut calc, "3 m + 2 s");
+            assert_eq!(span, (4, 1).into());
+            assert_eq!(
+                help.as_deref(),
+                Some("The left side is a length (m), the right side a time (s)")
+            );
+            for input in [
+                "3 m - 2",
+                "3 m < 2 kg",
+                "(3 m) ^ 0.5",
+                "floor(2.5 km)",
+                "max(1 m, 1 s)",
+                "sqrt(3 m)",
+                "if true then 1 m else 1 s",
+            ] {
+                type_mismatch(&mut calc, input);
+            }
+        }
+
+        #[test]
+        fn test_unknown_unit() {
+            let mut calc = setup_test_calculator();
+            let error = eval(&mut calc, "3 km in kmh").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::UnknownUnit(_)));
+            assert_eq!(error.help.as_deref(), Some("Did you mean `km`?"));
+            // After a number, an unknown name is a variable being multiplied
+            let error = eval(&mut calc, "3 kmh").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::UndefinedVariable(_)));
+            assert_eq!(error.span, (2, 3).into());
+            assert_eq!(error.help.as_deref(), Some("Did you mean `km`?"));
+
+            // A power too large for an integer is reported on the name it is on
+            eval(&mut calc, "x = 2").unwrap();
+            let error = eval(&mut calc, "3x^99999999999999999999").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::NumberError(_)));
+            assert_eq!(error.span, (1, 22).into());
+
+            // A power too large to read is reported on the unit it is on
+            let error = eval(&mut calc, "3 m^99999999999999999999").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::NumberError(_)));
+            assert_eq!(error.span, (2, 22).into());
+        }
+
+        #[test]
+        fn test_variables_and_functions() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "d = 5 km").unwrap();
+            assert_eq!(calc.variable("d"), Some(quantity(5.0, "km")));
+            assert_eq!(eval(&mut calc, "d + 300 m").unwrap(), quantity(5.3, "km"));
+            eval(&mut calc, "f(x) = x * 2").unwrap();
+            assert_eq!(eval(&mut calc, "f(3 km)").unwrap(), quantity(6.0, "km"));
+            assert_eq!(eval(&mut calc, "f(3 h)").unwrap(), quantity(6.0, "h"));
+            // A variable named like a unit takes its place
+            assert_eq!(eval(&mut calc, "10 / h").unwrap().to_string(), "10 1/h");
+            eval(&mut calc, "h = 2").unwrap();
+            assert!(matches!(
+                eval(&mut calc, "10 / h"),
+                Ok(CalcValue::Float(x)) if x == 5.0
+            ));
+        }
+    }
+
+    mod program_tests {
+        use super::*;
+
+        fn run(calc: &mut Calculator, input: &str) -> Vec<MietteResult<CalcValue>> {
+            calc.update_program(input, 0, 0, input.len())
+        }
+
+        #[test]
+        fn test_statements() {
+            let mut calc = setup_test_calculator();
+            let results = run(&mut calc, "x = 2\ny = x * 3\n\nx + y; f(a) = a * a; f(y)");
+            let values: Vec<CalcValue> = results.into_iter().map(Result::unwrap).collect();
+            assert_eq!(
+                values,
+                [
+                    CalcValue::Integer(2),
+                    CalcValue::Integer(6),
+                    CalcValue::Integer(8),
+                    CalcValue::Function("f(a)".into()),
+                    CalcValue::Integer(36),
+                ]
+            );
+            assert!(run(&mut calc, "\n ; \n").is_empty());
+            // A single result is the last statement's
+            assert!(matches!(
+                calc.update_input("1; 2", 0, 0, 4),
+                Ok(CalcValue::Integer(2))
+            ));
+        }
+
+        #[test]
+        fn test_errors_per_statement() {
+            let mut calc = setup_test_calculator();
+            let results = run(&mut calc, "z = 4\nw + 1\nz * 2");
+            assert_eq!(results.len(), 3);
+            assert!(matches!(results[0], Ok(CalcValue::Integer(4))));
+            assert!(matches!(results[2], Ok(CalcValue::Integer(8))));
+            let error = results[1].as_ref().unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::UndefinedVariable(_)));
+            assert_eq!(error.span, (6, 1).into());
+
+            let results = run(&mut calc, "1 / 0.5\n7 // 0");
+            assert!(matches!(results[0], Ok(CalcValue::Float(x)) if x == 2.0));
+            let error = results[1].as_ref().unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::DivisionByZero));
+            assert_eq!(error.span, (13, 1).into());
+        }
+
+        #[test]
+        fn test_edit_on_earlier_line() {
+            let mut calc = setup_test_calculator();
+            let input = "a = 1\nb = 2\na + b";
+            calc.update_program(input, 0, 0, input.len());
+            // `2` becomes `20` on the second line
+            let results = calc.update_program("a = 1\nb = 20\na + b", 10, 11, 12);
+            assert!(matches!(results[1], Ok(CalcValue::Integer(20))));
+            assert!(matches!(results[2], Ok(CalcValue::Integer(21))));
+        }
+
+        #[test]
+        fn test_point_at() {
+            let text = "a = 1\nbb = 2\n";
+            assert_eq!(point_at(text, 3), tree_sitter::Point::new(0, 3));
+            assert_eq!(point_at(text, 6), tree_sitter::Point::new(1, 0));
+            assert_eq!(point_at(text, 9), tree_sitter::Point::new(1, 3));
+            assert_eq!(point_at(text, 100), tree_sitter::Point::new(2, 0));
+        }
+    }
+
+    mod comment_tests {
+        use super::*;
+
+        fn run(calc: &mut Calculator, input: &str) -> Vec<MietteResult<CalcValue>> {
+            calc.update_program(input, 0, 0, input.len())
+        }
+
+        fn error_span(result: &MietteResult<CalcValue>) -> SourceSpan {
+            let error = result.as_ref().unwrap_err();
+            error.downcast_ref::<CalculatorError>().unwrap().span
+        }
+
+        #[test]
+        fn test_comments() {
+            let mut calc = setup_test_calculator();
+            let results = run(
+                &mut calc,
+                "# setup\nx = 2 # two\n\n/* block\n   comment */ x /* times */ * 3",
+            );
+            let values: Vec<CalcValue> = results.into_iter().map(Result::unwrap).collect();
+            assert_eq!(values, [CalcValue::Integer(2), CalcValue::Integer(6)]);
+            assert!(matches!(
+                calc.update_input("1 + 2 # three", 0, 0, 13),
+                Ok(CalcValue::Integer(3))
+            ));
+            assert!(run(&mut calc, "# nothing").is_empty());
+        }
+
+        #[test]
+        fn test_comment_edits_hit_cache() {
+            let mut calc = setup_test_calculator();
+            let input = "f(x) = 10 // x # halve\nf(5)\n7 /* c */ // 0";
+            let results = run(&mut calc, input);
+            assert!(matches!(results[1], Ok(CalcValue::Integer(2))));
+            assert_eq!(error_span(&results[2]), (41, 1).into());
+            let cached = calc.cache.function_cache.len();
+
+            // `halve` becomes `halves`, moving everything after it
+            let input = "f(x) = 10 // x # halves\nf(5)\n7 /* c */ // 0";
+            let results = calc.update_program(input, 22, 22, 23);
+            assert!(matches!(results[1], Ok(CalcValue::Integer(2))));
+            assert_eq!(error_span(&results[2]), (42, 1).into());
+            // And a comment inside the expression
+            let input = "f(x) = 10 // x # halves\nf(5)\n7 /* cc */ // 0";
+            let results = calc.update_program(input, 35, 35, 36);
+            assert_eq!(error_span(&results[2]), (43, 1).into());
+            assert_eq!(calc.cache.function_cache.len(), cached);
+        }
+
+        #[test]
+        fn test_hash_is_not_an_operator() {
+            let mut calc = setup_test_calculator();
+            assert!(matches!(
+                calc.update_input("6 # 2", 0, 0, 5),
+                Ok(CalcValue::Integer(6))
+            ));
+        }
+    }
+
+    mod aggregate_tests {
+        use super::*;
+
+        #[test]
+        fn test_sum_and_product() {
+            let mut calc = setup_test_calculator();
+            assert!(matches!(
+                eval(&mut calc, "sum(i = 1..100, i * i)"),
+                Ok(CalcValue::Integer(338350))
+            ));
+            eval(&mut calc, "n = 10").unwrap();
+            assert!(matches!(
+                eval(&mut calc, "prod(k = 1..n, k)"),
+                Ok(CalcValue::Integer(3628800))
+            ));
+            assert!(matches!(
+                eval(&mut calc, "sum(i = 1..3, sum(j = 1..i, j))"),
+                Ok(CalcValue::Integer(10))
+            ));
+            // Empty ranges give the identity
+            assert!(matches!(
+                eval(&mut calc, "sum(i = 5..1, i)"),
+                Ok(CalcValue::Integer(0))
+            ));
+            assert!(matches!(
+                eval(&mut calc, "prod(i = 5..1, i)"),
+                Ok(CalcValue::Integer(1))
+            ));
+            assert!(matches!(
+                eval(
+                    &mut calc,
+                    "sum(i = 9223372036854775806..9223372036854775807, 1)"
+                ),
+                Ok(CalcValue::Integer(2))
+            ));
+        }
+
+        #[test]
+        fn test_term_types() {
+            let mut calc = setup_test_calculator();
+            let CalcValue::Float(x) = eval(&mut calc, "sum(i = 1..1000000, 1 / i)").unwrap() else {
+                panic!("expected a float");
+            };
+            assert!((x - 14.392726722864989).abs() < 1e-9);
+            // Overflowing totals are redone with big integers
+            assert_eq!(
+                eval(&mut calc, "sum(i = 1..100000, i * i * i)").unwrap(),
+                CalcValue::BigInt("25000500002500000000".parse().unwrap())
+            );
+            assert_eq!(
+                eval(&mut calc, "prod(k = 1..25, k)").unwrap(),
+                CalcValue::BigInt("15511210043330985984000000".parse().unwrap())
+            );
+            assert_eq!(
+                eval(&mut calc, "sum(i = 1..3, 1i * i)").unwrap(),
+                CalcValue::Complex(Complex64::new(0.0, 6.0))
+            );
+            assert_eq!(
+                eval(&mut calc, "sum(i = 1..3, 2 km) in m")
+                    .unwrap()
+                    .to_string(),
+                "6000 m"
+            );
+            calc.set_exact(true);
+            assert_eq!(
+                eval(&mut calc, "sum(i = 1..3, 1 / i)").unwrap(),
+                CalcValue::Rational(BigRational::new(11.into(), 6.into()))
+            );
+        }
+
+        #[test]
+        fn test_in_functions() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "f(n) = sum(i = 1..n, i)").unwrap();
+            assert!(matches!(
+                eval(&mut calc, "f(100)"),
+                Ok(CalcValue::Integer(5050))
+            ));
+            // The loop variable shadows variables of the same name
+            eval(&mut calc, "i = 1000").unwrap();
+            assert!(matches!(
+                eval(&mut calc, "sum(i = 1..4, i) + i"),
+                Ok(CalcValue::Integer(1010))
+            ));
+        }
+
+        #[test]
+        fn test_errors() {
+            let mut calc = setup_test_calculator();
+            for (input, span) in [
+                ("sum(i = 1.5..3, i)", (8, 3)),
+                ("sum(i = 1..3, i > 1)", (14, 5)),
+                ("prod(i = 1..3, 2 km)", (15, 4)),
+            ] {
+                let error = eval(&mut calc, input).unwrap_err();
+                let error = error.downcast_ref::<CalculatorError>().unwrap();
+                assert!(
+                    matches!(error.kind, CalcErrorKind::TypeMismatch(_)),
+                    "{}",
+                    input
+                );
+                assert_eq!(error.span, span.into(), "{}", input);
+            }
+
+            let error = eval(&mut calc, "sum(i = 1..3, 7 // (i - 2))").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::DivisionByZero));
+            assert_eq!(error.span, (19, 7).into());
+        }
+    }
+
+    mod implicit_multiplication_tests {
+        use super::*;
+
+        #[test]
+        fn test_juxtaposition() {
+            let mut calc = setup_test_calculator();
+            for (input, expected) in [
+                ("2(3 + 4)", 14),
+                ("(2)(3)(4)", 24),
+                ("(1 + 1) max(2, 3)", 6),
+                ("2 sqrt(9)", 6),
+                ("-2(3)", -6),
+            ] {
+                assert_eq!(
+                    eval(&mut calc, input).unwrap().to_string(),
+                    expected.to_string(),
+                    "{}",
+                    input
+                );
+            }
+            // A name followed by parentheses is called, even a unit's
+            assert_eq!(
+                eval(&mut calc, "(2 km)(3)").unwrap(),
+                CalcValue::Quantity(6.0, Unit::named("km").unwrap())
+            );
+            assert!(eval(&mut calc, "2 km (3)").is_err());
+        }
+
+        #[test]
+        fn test_variables() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "x = 3").unwrap();
+            assert!(matches!(eval(&mut calc, "2x"), Ok(CalcValue::Integer(6))));
+            assert!(matches!(
+                eval(&mut calc, "2x^2"),
+                Ok(CalcValue::Integer(18))
+            ));
+            assert!(matches!(
+                eval(&mut calc, "(x + 1) x"),
+                Ok(CalcValue::Integer(12))
+            ));
+            assert!(matches!(eval(&mut calc, "0.5x"), Ok(CalcValue::Float(x)) if x == 1.5));
+            eval(&mut calc, "f(y) = 2y + 1").unwrap();
+            assert!(matches!(eval(&mut calc, "f(3)"), Ok(CalcValue::Integer(7))));
+        }
+
+        #[test]
+        fn test_precedence() {
+            let mut calc = setup_test_calculator();
+            // Tighter than every operator
+            assert!(matches!(eval(&mut calc, "1 / 2(4)"), Ok(CalcValue::Float(x)) if x == 0.125));
+            assert!(matches!(
+                eval(&mut calc, "2(3) + 1"),
+                Ok(CalcValue::Integer(7))
+            ));
+        }
+
+        #[test]
+        fn test_virtual_operator_span() {
+            let mut calc = setup_test_calculator();
+            let error = eval(&mut calc, "(true)(2)").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::TypeMismatch(_)));
+            assert_eq!(error.span, (6, 0).into());
+
+            let error = eval(&mut calc, "(true) (2)").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert_eq!(error.span, (6, 1).into());
+        }
+    }
+
+    mod constant_tests {
+        use super::*;
+
+        #[test]
+        fn test_constants() {
+            let mut calc = setup_test_calculator();
+            for (input, expected) in [
+                ("pi", std::f64::consts::PI),
+                ("2pi", std::f64::consts::TAU),
+                ("tau / 2", std::f64::consts::PI),
+                ("ln(e)", 1.0),
+                ("-inf", f64::NEG_INFINITY),
+            ] {
+                assert!(
+                    matches!(eval(&mut calc, input), Ok(CalcValue::Float(x)) if x == expected),
+                    "{}",
+                    input
+                );
+            }
+            assert!(matches!(eval(&mut calc, "nan"), Ok(CalcValue::Float(x)) if x.is_nan()));
+            // Still a float literal
+            assert!(matches!(eval(&mut calc, "2e3"), Ok(CalcValue::Float(x)) if x == 2000.0));
+        }
+
+        #[test]
+        fn test_parsed_as_literals() {
+            let mut calc = setup_test_calculator();
+            let tree = calc.parser.parse("tau", None).unwrap();
+            let expr = calc.node_to_expr("tau", tree.root_node()).unwrap();
+            assert!(matches!(expr, Expr::Float(x) if x == std::f64::consts::TAU));
+        }
+
+        #[test]
+        fn test_misspelt_constant() {
+            let mut calc = setup_test_calculator();
+            let error = eval(&mut calc, "2 * pii").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::UndefinedVariable(_)));
+            assert_eq!(error.span, (4, 3).into());
+            assert_eq!(error.help.as_deref(), Some("Did you mean `pi`?"));
+        }
+
+        #[test]
+        fn test_not_assignable() {
+            let mut calc = setup_test_calculator();
+            for (input, span) in [
+                ("pi = 3", (0, 2)),
+                ("f(e) = e", (2, 1)),
+                ("sum(e = 1..3, e)", (4, 1)),
+            ] {
+                let error = eval(&mut calc, input).unwrap_err();
+                let error = error.downcast_ref::<CalculatorError>().unwrap();
+                assert_eq!(error.span, span.into(), "{}", input);
+            }
+        }
+    }
+
+    mod percent_tests {
+        use super::*;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::language::error::{CalcErrorKind, CalculatorError};
-    use crossterm::terminal;
-    use miette::GraphicalReportHandler;
+        fn float(calc: &mut Calculator, input: &str) -> f64 {
+            match eval(calc, input) {
+                Ok(CalcValue::Float(x)) => x,
+                other => panic!("{}: {:?}", input, other),
+            }
+        }
 
-    // Helper function to create a controlled test environment
-    fn setup_test_calculator() -> Calculator {
-        Calculator::new().expect("Failed to create calculator")
+        #[test]
+        fn test_percentages() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(float(&mut calc, "50%"), 0.5);
+            assert_eq!(float(&mut calc, "200 + 10%"), 220.0);
+            assert_eq!(float(&mut calc, "200 - 10%"), 180.0);
+            assert_eq!(float(&mut calc, "200 * 10%"), 20.0);
+            assert_eq!(float(&mut calc, "(50%) - 3"), -2.5);
+            assert_eq!(float(&mut calc, "1 + 2 + 10%"), 3.3);
+            // Parentheses opt out of the change-by-percent reading
+            assert_eq!(float(&mut calc, "100 + (10%)"), 100.1);
+            eval(&mut calc, "price = 80").unwrap();
+            assert_eq!(float(&mut calc, "price + 25%"), 100.0);
+            assert_eq!(
+                eval(&mut calc, "10 km + 5%").unwrap(),
+                CalcValue::Quantity(10.5, Unit::named("km").unwrap())
+            );
+        }
+
+        #[test]
+        fn test_still_modulo() {
+            let mut calc = setup_test_calculator();
+            assert!(matches!(
+                eval(&mut calc, "7 % 3"),
+                Ok(CalcValue::Integer(1))
+            ));
+            assert!(matches!(
+                eval(&mut calc, "7 % (-3)"),
+                Ok(CalcValue::Integer(1))
+            ));
+            assert!(matches!(
+                eval(&mut calc, "7 % -3"),
+                Ok(CalcValue::Integer(1))
+            ));
+            assert!(matches!(
+                eval(&mut calc, "7 % +3"),
+                Ok(CalcValue::Integer(1))
+            ));
+            assert!(matches!(
+                eval(&mut calc, "50% - 3"),
+                Ok(CalcValue::Integer(2))
+            ));
+            assert!(matches!(eval(&mut calc, "17 % 5%"), Ok(CalcValue::Float(x)) if x == 0.02));
+        }
+
+        #[test]
+        fn test_exact() {
+            let mut calc = setup_test_calculator();
+            calc.set_exact(true);
+            assert_eq!(eval(&mut calc, "30 + 10%").unwrap(), CalcValue::Integer(33));
+            assert_eq!(
+                eval(&mut calc, "30 + 5%").unwrap(),
+                CalcValue::Rational(BigRational::new(63.into(), 2.into()))
+            );
+        }
+
+        #[test]
+        fn test_reading_in_diagnostics() {
+            let mut calc = setup_test_calculator();
+            let error = eval(&mut calc, "true + 5%").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::TypeMismatch(_)));
+            assert_eq!(error.span, (5, 1).into());
+            assert!(error
+                .help
+                .as_deref()
+                .unwrap()
+                .contains("`a + b%` is `a * (100 + b) / 100`, which is a float"));
+
+            let error = eval(&mut calc, "5% & 3").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(error.help.as_deref().unwrap().contains("`b%` is `b / 100`"));
+        }
     }
 
-    mod parser_tests {
+    mod decimal_tests {
         use super::*;
 
+        fn shown(calc: &mut Calculator, input: &str) -> String {
+            match eval(calc, input) {
+                Ok(value @ CalcValue::Decimal(_)) => value.to_string(),
+                other => panic!("{}: {:?}", input, other),
+            }
+        }
+
         #[test]
-        fn test_parse_single_number() {
+        fn test_exact_arithmetic() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("42", 0, 0, 2);
-            assert!(matches!(result, Ok(CalcValue::Integer(42))));
+            assert_eq!(shown(&mut calc, "0.1d + 0.2d"), "0.30");
+            assert_eq!(shown(&mut calc, "19.99d * 3 - 0.01d"), "59.96");
+            assert_eq!(shown(&mut calc, "-1.5d"), "-1.50");
+            assert_eq!(shown(&mut calc, "-0.5d + 0.25d"), "-0.25");
+            assert_eq!(shown(&mut calc, "sum(i = 1..10, 0.1d)"), "1.00");
+            assert_eq!(shown(&mut calc, "200d + 10%"), "220.00");
+            assert!(matches!(
+                eval(&mut calc, "0.1d + 0.2d == 0.3d"),
+                Ok(CalcValue::Bool(true))
+            ));
         }
 
         #[test]
-        fn test_parse_single_float() {
+        fn test_rounding() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("42.5", 0, 0, 4);
-            assert!(matches!(result, Ok(CalcValue::Float(42.5))));
+            assert_eq!(shown(&mut calc, "10d / 3"), "3.33");
+            assert_eq!(shown(&mut calc, "2d / 3"), "0.67");
+            // Ties go to the even neighbour
+            assert_eq!(shown(&mut calc, "0.05d * 0.5d"), "0.02");
+            assert_eq!(shown(&mut calc, "0.15d * 0.5d"), "0.08");
+            assert_eq!(shown(&mut calc, "-0.05d * 0.5d"), "-0.02");
+
+            let (kind, span) = error(&mut calc, "0.125d");
+            assert!(matches!(kind, CalcErrorKind::NumberError(_)));
+            assert_eq!(span, (0, 6).into());
         }
 
         #[test]
-        fn test_parse_negative_number() {
+        fn test_mixed_types() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("-42", 0, 0, 3);
-            assert!(matches!(result, Ok(CalcValue::Integer(-42))));
+            assert!(matches!(eval(&mut calc, "0.5d + 0.25"), Ok(CalcValue::Float(x)) if x == 0.75));
+            assert!(matches!(eval(&mut calc, "7d // 2"), Ok(CalcValue::Float(x)) if x == 3.0));
+            assert!(matches!(eval(&mut calc, "sqrt(2.25d)"), Ok(CalcValue::Float(x)) if x == 1.5));
+            assert_eq!(shown(&mut calc, "if 1 > 0 then 2 else 0.5d"), "2.00");
+            assert!(matches!(
+                eval(&mut calc, "1.5d < 2"),
+                Ok(CalcValue::Bool(true))
+            ));
+            let (kind, _) = error(&mut calc, "1d & 1");
+            assert!(matches!(kind, CalcErrorKind::TypeMismatch(_)));
         }
 
         #[test]
-        fn test_parse_invalid_input() {
+        fn test_variables_and_functions() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("abc", 0, 0, 3);
-            assert!(matches!(result, Err(_)));
+            eval(&mut calc, "price = 4.99d").unwrap();
+            assert_eq!(shown(&mut calc, "price * 3"), "14.97");
+            eval(&mut calc, "with_tax(x) = x * 1.2d").unwrap();
+            assert_eq!(shown(&mut calc, "with_tax(price)"), "5.99");
+            assert_eq!(shown(&mut calc, "with_tax(10)"), "12.00");
+        }
+
+        #[test]
+        fn test_places() {
+            let mut calc = setup_test_calculator();
+            calc.set_decimal_places(4);
+            assert_eq!(shown(&mut calc, "1d / 3"), "0.3333");
+            eval(&mut calc, "x = 2.125d").unwrap();
+            assert_eq!(shown(&mut calc, "x"), "2.1250");
+
+            // Stored decimals are rounded to the new places
+            calc.set_decimal_places(2);
+            assert_eq!(calc.variable("x").unwrap().to_string(), "2.12");
+            assert_eq!(shown(&mut calc, "x + 1d"), "3.12");
+
+            calc.set_decimal_places(0);
+            assert_eq!(shown(&mut calc, "7d / 2"), "4");
+        }
+
+        #[test]
+        fn test_overflow() {
+            let mut calc = setup_test_calculator();
+            let max = "1701411834604692317316873037158841057.27d";
+            let (kind, span) = error(&mut calc, &format!("{} + 1d", max));
+            assert!(matches!(kind, CalcErrorKind::DecimalOverflow));
+            assert_eq!(span, (max.len() + 1, 1).into());
+            let (kind, _) = error(&mut calc, "10000000000000000000d * 10000000000000000000d");
+            assert!(matches!(kind, CalcErrorKind::DecimalOverflow));
+            let (kind, span) = error(&mut calc, "1d / (0.5d - 0.5d)");
+            assert!(matches!(kind, CalcErrorKind::DivisionByZero));
+            assert_eq!(span, (5, 13).into());
         }
     }
 
-    mod expression_tests {
+    mod cast_tests {
         use super::*;
 
         #[test]
-        fn test_simple_addition() {
+        fn test_to_int() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("5 + 3", 0, 0, 5);
-            assert!(matches!(result, Ok(CalcValue::Integer(8))));
+            assert_eq!(
+                eval(&mut calc, "3.7 as int").unwrap(),
+                CalcValue::Integer(3)
+            );
+            assert_eq!(
+                eval(&mut calc, "-3.7 as int").unwrap(),
+                CalcValue::Integer(-3)
+            );
+            assert_eq!(
+                eval(&mut calc, "2.99d as int").unwrap(),
+                CalcValue::Integer(2)
+            );
+            assert_eq!(
+                eval(&mut calc, "true as int").unwrap(),
+                CalcValue::Integer(1)
+            );
+            assert_eq!(eval(&mut calc, "7 as int").unwrap(), CalcValue::Integer(7));
+            // Tighter than `*`, looser than a sign
+            assert_eq!(
+                eval(&mut calc, "2 * -1.5 as int").unwrap(),
+                CalcValue::Integer(-2)
+            );
+            assert_eq!(
+                eval(&mut calc, "(2 * 1.5) as int").unwrap(),
+                CalcValue::Integer(3)
+            );
+
+            calc.set_exact(true);
+            assert_eq!(
+                eval(&mut calc, "(7 / 2) as int").unwrap(),
+                CalcValue::Integer(3)
+            );
+            assert_eq!(
+                eval(&mut calc, "(-7 / 2) as int").unwrap(),
+                CalcValue::Integer(-3)
+            );
         }
 
         #[test]
-        fn test_simple_multiplication() {
+        fn test_to_float() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("5 * 3", 0, 0, 5);
-            assert!(matches!(result, Ok(CalcValue::Integer(15))));
+            assert_eq!(
+                eval(&mut calc, "7 as float").unwrap(),
+                CalcValue::Float(7.0)
+            );
+            assert_eq!(
+                eval(&mut calc, "(1 < 2) as float").unwrap(),
+                CalcValue::Float(1.0)
+            );
+            assert_eq!(
+                eval(&mut calc, "1.25d as float").unwrap(),
+                CalcValue::Float(1.25)
+            );
+            eval(&mut calc, "x = 2.5").unwrap();
+            eval(&mut calc, "f(x) = x as int * 2").unwrap();
+            assert_eq!(
+                eval(&mut calc, "f(x) as float").unwrap(),
+                CalcValue::Float(4.0)
+            );
         }
 
         #[test]
-        fn test_mixed_operations() {
+        fn test_large_values() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("2 + 3 * 4", 0, 0, 9);
-            assert!(matches!(result, Ok(CalcValue::Integer(14))));
+            assert_eq!(
+                eval(&mut calc, "1e19 as int").unwrap(),
+                CalcValue::BigInt(BigInt::from(10_u64.pow(19)))
+            );
+            assert_eq!(
+                eval(&mut calc, "-9.223372036854775808e18 as int").unwrap(),
+                CalcValue::Integer(i64::MIN)
+            );
+            assert_eq!(
+                eval(&mut calc, "2^64 as int").unwrap(),
+                CalcValue::BigInt(BigInt::from(1_u128 << 64))
+            );
         }
 
         #[test]
-        fn test_float_operations() {
+        fn test_not_finite() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("2.5 + 3.5", 0, 0, 9);
-            if let Ok(CalcValue::Float(val)) = result {
-                assert!((val - 6.0).abs() < f64::EPSILON);
-            } else {
-                panic!("Expected float result");
-            }
+            let (kind, span) = error(&mut calc, "1 + nan as int");
+            assert!(matches!(kind, CalcErrorKind::NotFinite));
+            assert_eq!(span, (8, 6).into());
+            let (kind, _) = error(&mut calc, "(1 / 0) as int");
+            assert!(matches!(kind, CalcErrorKind::NotFinite));
+            assert!(matches!(
+                eval(&mut calc, "inf as float"),
+                Ok(CalcValue::Float(x)) if x == f64::INFINITY
+            ));
         }
 
         #[test]
-        fn test_mixed_types() {
+        fn test_invalid_casts() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("2 + 3.5", 0, 0, 7);
-            if let Ok(CalcValue::Float(val)) = result {
-                assert!((val - 5.5).abs() < f64::EPSILON);
-            } else {
-                panic!("Expected float result");
-            }
+            let (kind, span) = error(&mut calc, "2 as i33");
+            assert!(matches!(kind, CalcErrorKind::ParseError(_)));
+            assert_eq!(span, (5, 3).into());
+            let (kind, span) = error(&mut calc, "sqrt(-1) as int");
+            assert!(matches!(kind, CalcErrorKind::TypeMismatch(_)));
+            assert_eq!(span, (9, 6).into());
+            let (kind, _) = error(&mut calc, "3 km as float");
+            assert!(matches!(kind, CalcErrorKind::TypeMismatch(_)));
         }
     }
 
-    mod type_determination_tests {
+    mod sized_tests {
         use super::*;
 
+        /// The result as printed, which carries its type, e.g. `255u8`.
+        fn shown(calc: &mut Calculator, input: &str) -> String {
+            eval(calc, input).unwrap().to_string()
+        }
+
         #[test]
-        fn test_determine_type_integer() {
-            let calc = setup_test_calculator();
-            let expr = Expr::Integer(42);
-            let result = calc.determine_type(&expr);
-            assert!(matches!(result, Ok((CalcValue::Integer(42), false))));
+        fn test_literals() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(shown(&mut calc, "255u8"), "255u8");
+            assert_eq!(shown(&mut calc, "0xFF_u8"), "255u8");
+            assert_eq!(shown(&mut calc, "-128i8"), "-128i8");
+            assert_eq!(shown(&mut calc, "0b1_u128"), "1u128");
+            assert_eq!(
+                shown(&mut calc, "340282366920938463463374607431768211455u128"),
+                "340282366920938463463374607431768211455u128"
+            );
+
+            let (kind, span) = error(&mut calc, "256u8");
+            assert!(matches!(kind, CalcErrorKind::NumberError(_)));
+            assert_eq!(span, (0, 5).into());
+            let (kind, _) = error(&mut calc, "-1u8");
+            assert!(matches!(kind, CalcErrorKind::NumberError(_)));
+            let (kind, _) = error(&mut calc, "2u8 m");
+            assert!(matches!(kind, CalcErrorKind::TypeMismatch(_)));
         }
 
         #[test]
-        fn test_determine_type_float() {
-            let calc = setup_test_calculator();
-            let expr = Expr::Float(42.5);
-            let result = calc.determine_type(&expr);
-            assert!(matches!(result, Ok((CalcValue::Float(42.5), true))));
+        fn test_arithmetic() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(shown(&mut calc, "0xFE_u8 + 1_u8"), "255u8");
+            assert_eq!(shown(&mut calc, "-7i8 // 2"), "-4i8");
+            assert_eq!(shown(&mut calc, "-7i8 % 2"), "-1i8");
+            assert_eq!(shown(&mut calc, "200u8 // 3"), "66u8");
+            assert_eq!(shown(&mut calc, "2u8 ^ 7"), "128u8");
+            assert_eq!(shown(&mut calc, "0xF0_u8 >> 4"), "15u8");
+            assert_eq!(shown(&mut calc, "-16i8 >> 2"), "-4i8");
+            assert_eq!(shown(&mut calc, "~0u16"), "65535u16");
+            assert_eq!(
+                shown(&mut calc, "(1u128 << 100) // 3u128"),
+                "422550200076076467165567735125u128"
+            );
+            assert_eq!(eval(&mut calc, "2u8 / 4u8").unwrap(), CalcValue::Float(0.5));
+            assert_eq!(
+                eval(&mut calc, "200u8 > 100").unwrap(),
+                CalcValue::Bool(true)
+            );
+            // Unsigned comparisons don't look at the top bit as a sign
+            assert_eq!(
+                eval(&mut calc, "0x8000_0000_0000_0000_u64 > 1u64").unwrap(),
+                CalcValue::Bool(true)
+            );
+            assert_eq!(shown(&mut calc, "sum(i = 1..10, 3u8)"), "30u8");
         }
 
         #[test]
-        fn test_determine_type_mixed_operation() {
-            let calc = setup_test_calculator();
-            let expr = Expr::BinaryOp {
-                left: Box::new(Expr::Integer(2)),
-                op: BinaryOpKind::Add,
-                right: Box::new(Expr::Float(3.5)),
-            };
-            let result = calc.determine_type(&expr);
-            assert!(matches!(result, Ok((CalcValue::Float(_), true))));
+        fn test_overflow() {
+            let mut calc = setup_test_calculator();
+            let overflow = |kind| matches!(kind, CalcErrorKind::SizedOverflow(ty) if ty == IntType::from_name("u8").unwrap());
+            let (kind, span) = error(&mut calc, "0xFF_u8 + 1_u8");
+            assert!(overflow(kind));
+            assert_eq!(span, (8, 1).into());
+            let (kind, _) = error(&mut calc, "1u8 - 2u8");
+            assert!(overflow(kind));
+            let (kind, _) = error(&mut calc, "16u8 * 16u8");
+            assert!(overflow(kind));
+            let (kind, _) = error(&mut calc, "2u8 ^ 8");
+            assert!(overflow(kind));
+            let (kind, span) = error(&mut calc, "-(1u8)");
+            assert!(overflow(kind));
+            assert_eq!(span, (0, 1).into());
+            // A plain integer has to fit the sized type it is used with
+            let (kind, span) = error(&mut calc, "1u8 + 256");
+            assert!(overflow(kind));
+            assert_eq!(span, (6, 3).into());
+
+            let (kind, _) = error(&mut calc, "-128i8 // -1");
+            assert!(matches!(kind, CalcErrorKind::SizedOverflow(_)));
+            let (kind, _) = error(&mut calc, "(1u128 << 127) * 2u128");
+            assert!(matches!(kind, CalcErrorKind::SizedOverflow(_)));
+            let (kind, _) = error(&mut calc, "7u8 // 0");
+            assert!(matches!(kind, CalcErrorKind::DivisionByZero));
         }
-    }
 
-    mod cache_tests {
-        use super::*;
-        use std::thread;
-        use std::time::Duration;
+        #[test]
+        fn test_mixing() {
+            let mut calc = setup_test_calculator();
+            assert_eq!(shown(&mut calc, "1u8 + 1i16"), "2i16");
+            assert_eq!(shown(&mut calc, "1u8 + 1u32"), "2u32");
+            assert_eq!(
+                eval(&mut calc, "1u32 + 1.5").unwrap(),
+                CalcValue::Float(2.5)
+            );
+            assert_eq!(shown(&mut calc, "true ? 1u8 : 2"), "1u8");
+
+            let (kind, span) = error(&mut calc, "1u64 + 1i64");
+            assert!(matches!(kind, CalcErrorKind::TypeMismatch(_)));
+            assert_eq!(span, (5, 1).into());
+            let (kind, _) = error(&mut calc, "1u8 + 0.5d");
+            assert!(matches!(kind, CalcErrorKind::TypeMismatch(_)));
+            let (kind, _) = error(&mut calc, "true ? 1u8 : 1i8");
+            assert!(matches!(kind, CalcErrorKind::TypeMismatch(_)));
+        }
 
         #[test]
-        fn test_cache_hit() {
+        fn test_casts() {
             let mut calc = setup_test_calculator();
-            // First evaluation should compile
-            let result1 = calc.update_input("2 + 3", 0, 0, 5);
-            assert!(matches!(result1, Ok(CalcValue::Integer(5))));
+            assert_eq!(shown(&mut calc, "100 as i8"), "100i8");
+            assert_eq!(shown(&mut calc, "3.9 as u8"), "3u8");
+            assert_eq!(
+                shown(&mut calc, "1e30 as u128"),
+                "1000000000000000019884624838656u128"
+            );
+            assert_eq!(shown(&mut calc, "0.7d as u8"), "0u8");
+            assert_eq!(shown(&mut calc, "true as u8"), "1u8");
+            assert_eq!(shown(&mut calc, "-1i8 as i64"), "-1i64");
+            assert_eq!(
+                eval(&mut calc, "255u8 as int").unwrap(),
+                CalcValue::Integer(255)
+            );
+            assert_eq!(
+                eval(&mut calc, "18446744073709551615u64 as int").unwrap(),
+                CalcValue::BigInt(BigInt::from(u64::MAX))
+            );
+            assert_eq!(
+                eval(&mut calc, "255u8 as float").unwrap(),
+                CalcValue::Float(255.0)
+            );
 
-            // Second evaluation should use cache
-            let result2 = calc.update_input("2 + 3", 0, 0, 5);
-            assert!(matches!(result2, Ok(CalcValue::Integer(5))));
+            // Unlike `as` in Rust, casts don't wrap or saturate
+            for input in ["1000 as i8", "256.0 as u8", "-1i8 as u8", "2^70 as u64"] {
+                let (kind, _) = error(&mut calc, input);
+                assert!(matches!(kind, CalcErrorKind::SizedOverflow(_)));
+            }
+            let (kind, _) = error(&mut calc, "nan as i32");
+            assert!(matches!(kind, CalcErrorKind::NotFinite));
         }
 
         #[test]
-        fn test_cache_cleanup() {
+        fn test_builtins() {
             let mut calc = setup_test_calculator();
-            calc.update_input("2 + 3", 0, 0, 5).unwrap();
+            assert_eq!(shown(&mut calc, "max(200u8, 1u8)"), "200u8");
+            assert_eq!(shown(&mut calc, "min(200u8, 1u8)"), "1u8");
+            assert_eq!(shown(&mut calc, "max(-5i8, 3)"), "3i8");
+            assert_eq!(shown(&mut calc, "min(1u8, 2i16)"), "1i16");
+            assert_eq!(shown(&mut calc, "abs(-5i8)"), "5i8");
+            assert_eq!(shown(&mut calc, "abs(-5i128)"), "5i128");
+            assert_eq!(shown(&mut calc, "abs(5u8)"), "5u8");
+            assert_eq!(shown(&mut calc, "floor(7u8)"), "7u8");
+            assert_eq!(shown(&mut calc, "round(-7i32)"), "-7i32");
+            // Unsigned values aren't compared as though the top bit were a sign
+            assert_eq!(
+                shown(&mut calc, "max(0x8000_0000_0000_0000_u64, 1u64)"),
+                "9223372036854775808u64"
+            );
+            assert_eq!(shown(&mut calc, "min(1u128 << 127, 1u128)"), "1u128");
+            assert_eq!(
+                eval(&mut calc, "max(2u8, 2.5)").unwrap(),
+                CalcValue::Float(2.5)
+            );
 
-            // Wait for cleanup thread to run
-            thread::sleep(Duration::from_secs(1));
+            let (kind, _) = error(&mut calc, "abs(-128i8)");
+            assert!(matches!(kind, CalcErrorKind::SizedOverflow(_)));
+            let (kind, _) = error(&mut calc, "max(1u8, 256)");
+            assert!(matches!(kind, CalcErrorKind::SizedOverflow(_)));
+            let (kind, _) = error(&mut calc, "max(1u64, 1i64)");
+            assert!(matches!(kind, CalcErrorKind::TypeMismatch(_)));
+        }
 
-            // Check if cache was cleaned
-            assert!(calc.cache.function_cache.len() <= 1);
+        #[test]
+        fn test_variables_and_functions() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "x = 250u8").unwrap();
+            assert_eq!(shown(&mut calc, "x + 5"), "255u8");
+            eval(&mut calc, "f(a, b) = a * b").unwrap();
+            assert_eq!(shown(&mut calc, "f(3u16, 4)"), "12u16");
+            assert_eq!(eval(&mut calc, "f(3, 4)").unwrap(), CalcValue::Integer(12));
+            let (kind, _) = error(&mut calc, "f(300u16, 300u16)");
+            assert!(matches!(kind, CalcErrorKind::SizedOverflow(_)));
         }
     }
 
-    mod error_handling_tests {
+    mod overflow_tests {
         use super::*;
 
-        #[test]
-        fn test_syntax_error() {
+        fn calculator(mode: OverflowMode) -> Calculator {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("2 +", 0, 0, 3);
-            assert!(matches!(result, Err(_)));
+            calc.set_overflow(mode);
+            calc
         }
 
         #[test]
-        fn test_invalid_operator() {
+        fn test_promote() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("2 _ 3", 0, 0, 5);
-            assert!(matches!(result, Err(_))); // First check if it's an error
+            assert_eq!(
+                eval(&mut calc, "9223372036854775807 + 1").unwrap(),
+                CalcValue::BigInt(BigInt::from(i64::MAX) + 1)
+            );
+            assert_eq!(
+                eval(&mut calc, "1 << 63").unwrap(),
+                CalcValue::BigInt(BigInt::from(1_u64 << 63))
+            );
+            // Sized integers have nothing to be promoted to
+            for input in ["255u8 + 1", "1i8 << 7"] {
+                let report = eval(&mut calc, input).unwrap_err();
+                let error = report.downcast::<CalculatorError>().unwrap();
+                assert!(matches!(error.kind, CalcErrorKind::SizedOverflow(_)));
+            }
+        }
 
-            if let Err(e) = result {
-                let diagnostic_msg = format!("{:?}", e);
-                assert!(diagnostic_msg.contains("Invalid operator")); // Then check the error message
-            } else {
-                panic!("Expected error for invalid operator");
+        #[test]
+        fn test_checked() {
+            let mut calc = calculator(OverflowMode::Checked);
+            for (input, span) in [
+                ("9223372036854775807 + 1", (20, 1)),
+                ("2 * 9223372036854775807", (2, 1)),
+                ("2 ^ 64", (2, 1)),
+                ("-(-9223372036854775807 - 1)", (0, 1)),
+                ("(-9223372036854775807 - 1) // -1", (27, 2)),
+                ("sum(i = 1..3, 9223372036854775807)", (0, 3)),
+                ("1 << 63", (2, 2)),
+                ("-3 << 62", (3, 2)),
+                ("abs(-9223372036854775808)", (0, 25)),
+            ] {
+                let report = eval(&mut calc, input).unwrap_err();
+                let error = report.downcast::<CalculatorError>().unwrap();
+                assert!(matches!(error.kind, CalcErrorKind::Overflow), "{}", input);
+                assert_eq!(error.span, span.into(), "{}", input);
             }
+            assert_eq!(
+                eval(&mut calc, "2 ^ 62").unwrap(),
+                CalcValue::Integer(1 << 62)
+            );
+            // Big integer literals are still big integers
+            assert_eq!(
+                eval(&mut calc, "9223372036854775808").unwrap(),
+                CalcValue::BigInt(BigInt::from(1_u64 << 63))
+            );
         }
 
         #[test]
-        fn test_invalid_number() {
+        fn test_wrapping() {
+            let mut calc = calculator(OverflowMode::Wrapping);
+            assert_eq!(
+                eval(&mut calc, "9223372036854775807 + 1").unwrap(),
+                CalcValue::Integer(i64::MIN)
+            );
+            assert_eq!(
+                eval(&mut calc, "3 * 9223372036854775807").unwrap(),
+                CalcValue::Integer(i64::MAX.wrapping_mul(3))
+            );
+            assert_eq!(
+                eval(&mut calc, "3 ^ 41").unwrap(),
+                CalcValue::Integer(3_i64.wrapping_pow(41))
+            );
+            assert_eq!(
+                eval(&mut calc, "(-9223372036854775807 - 1) // -1").unwrap(),
+                CalcValue::Integer(i64::MIN)
+            );
+            assert_eq!(
+                eval(&mut calc, "1 << 63").unwrap(),
+                CalcValue::Integer(i64::MIN)
+            );
+            assert_eq!(
+                eval(&mut calc, "abs(-9223372036854775808)").unwrap(),
+                CalcValue::Integer(i64::MIN)
+            );
+            assert_eq!(
+                eval(&mut calc, "0xFF_u8 + 1_u8").unwrap().to_string(),
+                "0u8"
+            );
+            assert_eq!(
+                eval(&mut calc, "0xFF_u8 << 4").unwrap().to_string(),
+                "240u8"
+            );
+            assert_eq!(eval(&mut calc, "0u8 - 1").unwrap().to_string(), "255u8");
+            assert_eq!(eval(&mut calc, "-(-128i8)").unwrap().to_string(), "-128i8");
+            assert_eq!(eval(&mut calc, "3i8 ^ 5").unwrap().to_string(), "-13i8");
+            assert_eq!(
+                eval(&mut calc, "(1u128 << 127) * 3u128")
+                    .unwrap()
+                    .to_string(),
+                format!("{}u128", (1_u128 << 127).wrapping_mul(3))
+            );
+            // Only results wrap: operands still have to fit their type
+            let report = eval(&mut calc, "1u8 + 256").unwrap_err();
+            let error = report.downcast::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::SizedOverflow(_)));
+        }
+
+        #[test]
+        fn test_saturating() {
+            let mut calc = calculator(OverflowMode::Saturating);
+            for (input, expected) in [
+                ("9223372036854775807 + 1", i64::MAX),
+                ("-9223372036854775807 - 2", i64::MIN),
+                ("-2 * 9223372036854775807", i64::MIN),
+                ("-2 * -9223372036854775807", i64::MAX),
+                ("(-2) ^ 63", i64::MIN),
+                ("(-2) ^ 64", i64::MAX),
+                ("-(-9223372036854775807 - 1)", i64::MAX),
+                ("(-9223372036854775807 - 1) // -1", i64::MAX),
+                ("prod(i = 1..30, i)", i64::MAX),
+                ("1 << 63", i64::MAX),
+                ("-3 << 62", i64::MIN),
+                ("abs(-9223372036854775808)", i64::MAX),
+            ] {
+                assert_eq!(
+                    eval(&mut calc, input).unwrap(),
+                    CalcValue::Integer(expected),
+                    "{}",
+                    input
+                );
+            }
+            assert_eq!(eval(&mut calc, "250u8 + 10").unwrap().to_string(), "255u8");
+            assert_eq!(eval(&mut calc, "3u8 - 10").unwrap().to_string(), "0u8");
+            assert_eq!(eval(&mut calc, "-(5u8)").unwrap().to_string(), "0u8");
+            assert_eq!(eval(&mut calc, "-100i8 * 2").unwrap().to_string(), "-128i8");
+            assert_eq!(eval(&mut calc, "(-3i8) ^ 5").unwrap().to_string(), "-128i8");
+            assert_eq!(
+                eval(&mut calc, "0xFF_u8 << 4").unwrap().to_string(),
+                "255u8"
+            );
+            assert_eq!(
+                eval(&mut calc, "(1u128 << 127) * 3u128")
+                    .unwrap()
+                    .to_string(),
+                format!("{}u128", u128::MAX)
+            );
+        }
+
+        #[test]
+        fn test_switching_modes() {
             let mut calc = setup_test_calculator();
-            let result = calc.update_input("2.a", 0, 0, 3);
-            assert!(matches!(result, Err(_)));
+            eval(&mut calc, "f(x) = x + 1").unwrap();
+            let input = "f(9223372036854775807)";
+            assert!(matches!(eval(&mut calc, input), Ok(CalcValue::BigInt(_))));
+            calc.set_overflow(OverflowMode::Wrapping);
+            assert_eq!(
+                eval(&mut calc, input).unwrap(),
+                CalcValue::Integer(i64::MIN)
+            );
+            calc.set_overflow(OverflowMode::Saturating);
+            assert_eq!(
+                eval(&mut calc, input).unwrap(),
+                CalcValue::Integer(i64::MAX)
+            );
+            calc.set_overflow(OverflowMode::Checked);
+            assert!(eval(&mut calc, input).is_err());
+            calc.set_overflow(OverflowMode::Wrapping);
+            assert_eq!(
+                eval(&mut calc, input).unwrap(),
+                CalcValue::Integer(i64::MIN)
+            );
         }
     }
 
-    mod incremental_update_tests {
+    mod function_tests {
         use super::*;
 
         #[test]
-        fn test_incremental_input() {
+        fn test_define_and_call() {
             let mut calc = setup_test_calculator();
+            assert_eq!(
+                eval(&mut calc, "f(x, y) = x * x + y").unwrap(),
+                CalcValue::Function("f(x, y)".into())
+            );
+            assert!(matches!(
+                eval(&mut calc, "f(3, 4)"),
+                Ok(CalcValue::Integer(13))
+            ));
+            assert!(matches!(
+                eval(&mut calc, "f(1.5, 1) * 4"),
+                Ok(CalcValue::Float(13.0))
+            ));
+        }
 
-            // Type "2"
-            let result1 = calc.update_input("2", 0, 0, 1);
-            assert!(matches!(result1, Ok(CalcValue::Integer(2))));
+        #[test]
+        fn test_calls_between_functions() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "sq(x) = x * x").unwrap();
+            eval(&mut calc, "dist2(x, y) = sq(x) + sq(y)").unwrap();
+            assert!(matches!(
+                eval(&mut calc, "dist2(3, 4)"),
+                Ok(CalcValue::Integer(25))
+            ));
+
+            // Redefining a callee is picked up by its callers
+            eval(&mut calc, "sq(x) = x").unwrap();
+            assert!(matches!(
+                eval(&mut calc, "dist2(3, 4)"),
+                Ok(CalcValue::Integer(7))
+            ));
+        }
 
-            // Type "2 + "
-            let result2 = calc.update_input("2 + ", 1, 1, 4);
-            assert!(matches!(result2, Err(_))); // Incomplete expression
+        #[test]
+        fn test_recursive_definition_compiles() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "r(n) = n * r(n - 1)").unwrap();
 
-            // Type "2 + 3"
-            let result3 = calc.update_input("2 + 3", 4, 4, 5);
-            assert!(matches!(result3, Ok(CalcValue::Integer(5))));
+            let tree = calc.parser.parse("r(3)", None).unwrap();
+            let expr = calc.node_to_expr("r(3)", tree.root_node()).unwrap();
+            assert!(calc.compile_expr("r(3)", expr).is_ok());
         }
 
         #[test]
-        fn test_backspace() {
+        fn test_recursion_limit() {
             let mut calc = setup_test_calculator();
+            eval(&mut calc, "f(n) = f(n - 1)").unwrap();
+            let error = eval(&mut calc, "1 + f(1)").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::RecursionLimit));
+            // The call that goes one level too deep is the one in the body
+            assert_eq!(error.src.inner(), "f(n) = f(n - 1)");
+            assert_eq!(error.span, (7, 8).into());
+
+            // Recursion that stops is unaffected, and the count starts over
+            eval(&mut calc, "s(n) = if n == 0 then 0 else n + s(n - 1)").unwrap();
+            assert!(matches!(
+                eval(&mut calc, "s(1000)"),
+                Ok(CalcValue::Integer(500500))
+            ));
+        }
 
-            // Type "2 + 3"
-            calc.update_input("2 + 3", 0, 0, 5).unwrap();
+        #[test]
+        fn test_arity_error_on_call_site() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "f(x, y) = x + y").unwrap();
+            let error = eval(&mut calc, "1 + f(1)").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(
+                error.kind,
+                CalcErrorKind::ArityMismatch {
+                    expected: 2,
+                    found: 1,
+                    ..
+                }
+            ));
+            assert_eq!(error.span, (4, 4).into());
+        }
 
-            // Backspace to "2 + "
-            let result = calc.update_input("2 + ", 0, 5, 4);
-            assert!(matches!(result, Err(_))); // Incomplete expression
+        #[test]
+        fn test_rejected_definition_keeps_previous() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "f(x) = x + 1").unwrap();
+            assert!(eval(&mut calc, "f(x) = x + nope").is_err());
+            assert!(matches!(eval(&mut calc, "f(1)"), Ok(CalcValue::Integer(2))));
+        }
+
+        #[test]
+        fn test_trap_in_body_after_redefinition() {
+            let mut calc = setup_test_calculator();
+            eval(&mut calc, "f(x) = 1 + 10 % x").unwrap();
+            let error = eval(&mut calc, "f(0)").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert!(matches!(error.kind, CalcErrorKind::DivisionByZero));
+            assert_eq!(error.src.inner(), "f(x) = 1 + 10 % x");
+            assert_eq!(error.span, (16, 1).into());
+            assert_eq!(calc.environment.borrow().pending_trap_sites(), 0);
+
+            // The sites go with the old body
+            eval(&mut calc, "f(x) = 10 % x").unwrap();
+            let error = eval(&mut calc, "f(0)").unwrap_err();
+            let error = error.downcast_ref::<CalculatorError>().unwrap();
+            assert_eq!(error.src.inner(), "f(x) = 10 % x");
+            assert_eq!(error.span, (12, 1).into());
+        }
+    }
+
+    mod builtin_tests {
+        use super::*;
+
+        fn eval(input: &str) -> MietteResult<CalcValue> {
+            setup_test_calculator().update_input(input, 0, 0, input.len())
+        }
+
+        #[test]
+        fn test_native_builtins() {
+            assert_eq!(eval("sqrt(16)").unwrap(), CalcValue::Float(4.0));
+            assert_eq!(eval("abs(2 - 7)").unwrap(), CalcValue::Integer(5));
+            assert_eq!(
+                eval("floor(3.7) + ceil(3.2)").unwrap(),
+                CalcValue::Float(7.0)
+            );
+            assert_eq!(eval("round(2.5)").unwrap(), CalcValue::Float(2.0));
+            assert_eq!(eval("min(3, 9)").unwrap(), CalcValue::Integer(3));
+            assert_eq!(eval("max(3, 9.5)").unwrap(), CalcValue::Float(9.5));
+        }
+
+        #[test]
+        fn test_libm_builtins() {
+            assert_eq!(eval("sin(0) + cos(0)").unwrap(), CalcValue::Float(1.0));
+            assert_eq!(eval("ln(exp(2))").unwrap(), CalcValue::Float(2.0));
+            assert_eq!(eval("pow(2, 10)").unwrap(), CalcValue::Float(1024.0));
+        }
+
+        #[test]
+        fn test_unknown_function_suggestion() {
+            let output = simulate_calc_input("sqr(4)");
+            assert!(output.contains("Undefined function: sqr"));
+            assert!(output.contains("Did you mean `sqrt`?"));
         }
     }
 
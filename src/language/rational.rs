@@ -0,0 +1,77 @@
+use crate::language::bignum;
+use crate::language::heap::alloc;
+use cranelift_jit::JITBuilder;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Signed, ToPrimitive};
+
+/// Read a rational handed out to compiled code. Null only shows up in
+/// variables whose assignment never ran, which read as zero.
+pub fn read(ptr: *const BigRational) -> BigRational {
+    value(ptr).clone()
+}
+
+fn value<'a>(ptr: *const BigRational) -> &'a BigRational {
+    static ZERO: BigRational = BigRational::ZERO;
+    unsafe { ptr.as_ref() }.unwrap_or(&ZERO)
+}
+
+pub fn register_symbols(builder: &mut JITBuilder) {
+    builder.symbol("calc_rat_from_i64", from_i64 as *const u8);
+    builder.symbol("calc_rat_from_big", from_big as *const u8);
+    builder.symbol("calc_rat_to_f64", to_f64 as *const u8);
+    builder.symbol("calc_rat_trunc", trunc as *const u8);
+    builder.symbol("calc_rat_sign", sign as *const u8);
+    builder.symbol("calc_rat_cmp", cmp as *const u8);
+    builder.symbol("calc_rat_neg", neg as *const u8);
+    builder.symbol("calc_rat_add", add as *const u8);
+    builder.symbol("calc_rat_sub", sub as *const u8);
+    builder.symbol("calc_rat_mul", mul as *const u8);
+    builder.symbol("calc_rat_div", div as *const u8);
+}
+
+extern "C" fn from_i64(n: i64) -> *mut BigRational {
+    alloc(BigRational::from_integer(BigInt::from(n)))
+}
+
+extern "C" fn from_big(n: *const BigInt) -> *mut BigRational {
+    alloc(BigRational::from_integer(bignum::read(n)))
+}
+
+extern "C" fn to_f64(a: *const BigRational) -> f64 {
+    value(a).to_f64().unwrap_or(f64::NAN)
+}
+
+/// `a` truncated toward zero, as a big integer.
+extern "C" fn trunc(a: *const BigRational) -> *mut BigInt {
+    alloc(value(a).to_integer())
+}
+
+extern "C" fn sign(a: *const BigRational) -> i64 {
+    value(a).signum().to_integer().to_i64().unwrap_or_default()
+}
+
+extern "C" fn cmp(a: *const BigRational, b: *const BigRational) -> i64 {
+    value(a).cmp(value(b)) as i64
+}
+
+extern "C" fn neg(a: *const BigRational) -> *mut BigRational {
+    alloc(-value(a))
+}
+
+extern "C" fn add(a: *const BigRational, b: *const BigRational) -> *mut BigRational {
+    alloc(value(a) + value(b))
+}
+
+extern "C" fn sub(a: *const BigRational, b: *const BigRational) -> *mut BigRational {
+    alloc(value(a) - value(b))
+}
+
+extern "C" fn mul(a: *const BigRational, b: *const BigRational) -> *mut BigRational {
+    alloc(value(a) * value(b))
+}
+
+/// Compiled code checks for a zero divisor first.
+extern "C" fn div(a: *const BigRational, b: *const BigRational) -> *mut BigRational {
+    alloc(value(a) / value(b))
+}
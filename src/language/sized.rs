@@ -0,0 +1,271 @@
+use crate::language::decimal::{join, Parts};
+use crate::language::heap::alloc;
+use cranelift::prelude::{types, Type};
+use cranelift_jit::JITBuilder;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{Signed, ToPrimitive};
+use std::fmt::{Display, Formatter};
+
+/// Integer type with a fixed width, from a literal suffix such as `u8` or a
+/// cast such as `as i32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IntType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+impl IntType {
+    /// What plain integers are, as far as mixing them with sized ones goes.
+    pub const I64: IntType = IntType::new(true, 64);
+
+    pub const ALL: [IntType; 10] = [
+        IntType::new(true, 8),
+        IntType::new(true, 16),
+        IntType::new(true, 32),
+        IntType::new(true, 64),
+        IntType::new(true, 128),
+        IntType::new(false, 8),
+        IntType::new(false, 16),
+        IntType::new(false, 32),
+        IntType::new(false, 64),
+        IntType::new(false, 128),
+    ];
+
+    const fn new(signed: bool, bits: u32) -> Self {
+        Self { signed, bits }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|ty| ty.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match (self.signed, self.bits) {
+            (true, 8) => "i8",
+            (true, 16) => "i16",
+            (true, 32) => "i32",
+            (true, 64) => "i64",
+            (true, _) => "i128",
+            (false, 8) => "u8",
+            (false, 16) => "u16",
+            (false, 32) => "u32",
+            (false, 64) => "u64",
+            (false, _) => "u128",
+        }
+    }
+
+    pub fn ir_type(self) -> Type {
+        Type::int_with_byte_size(self.bits as u16 / 8).unwrap_or(types::I128)
+    }
+
+    pub fn min(self) -> i128 {
+        match self.signed {
+            true => -1 << (self.bits - 1),
+            false => 0,
+        }
+    }
+
+    pub fn max(self) -> u128 {
+        match self.signed {
+            true => (1 << (self.bits - 1)) - 1,
+            false => u128::MAX >> (128 - self.bits),
+        }
+    }
+
+    pub fn contains(self, n: &BigInt) -> bool {
+        *n >= BigInt::from(self.min()) && *n <= BigInt::from(self.max())
+    }
+
+    /// Whether every value of `self` is also one of `other`.
+    pub fn fits_in(self, other: IntType) -> bool {
+        self.min() >= other.min() && self.max() <= other.max()
+    }
+
+    /// The type both `self` and `other` fit in, if one of them is it: the
+    /// wider of two with the same signedness, or a signed type wider than
+    /// an unsigned one. Mixing e.g. `u64` and `i64` needs a cast instead.
+    pub fn widen(self, other: IntType) -> Option<IntType> {
+        match (self.fits_in(other), other.fits_in(self)) {
+            (true, _) => Some(other),
+            (_, true) => Some(self),
+            _ => None,
+        }
+    }
+
+    /// The range of values, for diagnostics.
+    pub fn describe(self) -> String {
+        format!("`{}` holds {} to {}", self, self.min(), self.max())
+    }
+}
+
+impl Display for IntType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Value of a sized integer type, as its bits (the upper ones unused).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizedInt {
+    pub raw: u128,
+    pub ty: IntType,
+}
+
+impl SizedInt {
+    /// The value whose two's complement is `raw`, truncated to `ty`.
+    pub fn new(raw: u128, ty: IntType) -> Self {
+        Self {
+            raw: raw & IntType::new(false, ty.bits).max(),
+            ty,
+        }
+    }
+
+    pub fn zero(ty: IntType) -> Self {
+        Self { raw: 0, ty }
+    }
+
+    /// The value as an `i128`, which holds every type but `u128` (whose
+    /// upper half comes out negative, with the same bits).
+    pub fn extended(self) -> i128 {
+        match self.ty.signed {
+            true => (self.raw << (128 - self.ty.bits)) as i128 >> (128 - self.ty.bits),
+            false => self.raw as i128,
+        }
+    }
+}
+
+/// With its suffix, so that it reads back as the same type, e.g. `255u8`.
+impl Display for SizedInt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.ty.signed {
+            true => write!(f, "{}{}", self.extended(), self.ty),
+            false => write!(f, "{}{}", self.raw, self.ty),
+        }
+    }
+}
+
+// Compiled code hands values to these helpers (and gets them back) widened
+// to 128 bits, as the low and high halves of a `decimal::Parts`, along with
+// whether the type is signed. `^` always goes through them, while `*`, `//`,
+// `%` and conversions to and from floats only do for 128-bit types, which
+// Cranelift has no instructions for.
+
+pub fn register_symbols(builder: &mut JITBuilder) {
+    builder.symbol("calc_int_to_f64", to_f64 as *const u8);
+    builder.symbol("calc_int_from_f64", from_f64 as *const u8);
+    builder.symbol("calc_int_to_big", to_big as *const u8);
+    builder.symbol("calc_int_mul_overflows", mul_overflows as *const u8);
+    builder.symbol("calc_int_floor_div", floor_div as *const u8);
+    builder.symbol("calc_int_rem", rem as *const u8);
+    builder.symbol("calc_int_pow", pow as *const u8);
+    builder.symbol("calc_int_pow_overflows", pow_overflows as *const u8);
+}
+
+fn value(low: u64, high: i64, signed: i64) -> BigInt {
+    match signed != 0 {
+        true => BigInt::from(join(low, high)),
+        false => BigInt::from(join(low, high) as u128),
+    }
+}
+
+/// The low 128 bits of `n`, which is what a wrapping operation leaves.
+fn wrap(n: &BigInt) -> Parts {
+    let low = (n & BigInt::from(u128::MAX)).to_u128().unwrap_or_default();
+    Parts::from(low as i128)
+}
+
+extern "C" fn to_f64(low: u64, high: i64, signed: i64) -> f64 {
+    value(low, high, signed).to_f64().unwrap_or(f64::NAN)
+}
+
+/// `x` truncated toward zero. Compiled code checks that it is in range.
+extern "C" fn from_f64(x: f64) -> Parts {
+    match x < 0.0 {
+        true => Parts::from(x as i128),
+        false => Parts::from(x as u128 as i128),
+    }
+}
+
+extern "C" fn to_big(low: u64, high: i64, signed: i64) -> *mut BigInt {
+    alloc(value(low, high, signed))
+}
+
+/// Whether `a * b` overflows the (128-bit) type. The wrapped product is
+/// left to `imul`.
+extern "C" fn mul_overflows(a_low: u64, a_high: i64, b_low: u64, b_high: i64, signed: i64) -> i64 {
+    let ty = IntType::new(signed != 0, 128);
+    let product = value(a_low, a_high, signed) * value(b_low, b_high, signed);
+    (!ty.contains(&product)).into()
+}
+
+/// Rounds toward negative infinity like `//` on plain integers. Compiled
+/// code checks for a zero divisor and `i128::MIN // -1` first.
+extern "C" fn floor_div(a_low: u64, a_high: i64, b_low: u64, b_high: i64, signed: i64) -> Parts {
+    let (a, b) = (join(a_low, a_high), join(b_low, b_high));
+    match signed != 0 {
+        true => Parts::from(Integer::div_floor(&a, &b)),
+        false => Parts::from((a as u128 / b as u128) as i128),
+    }
+}
+
+/// Truncated remainder like `srem`. Compiled code checks for a zero divisor
+/// first.
+extern "C" fn rem(a_low: u64, a_high: i64, b_low: u64, b_high: i64, signed: i64) -> Parts {
+    let (a, b) = (join(a_low, a_high), join(b_low, b_high));
+    match signed != 0 {
+        true => Parts::from(a.wrapping_rem(b)),
+        false => Parts::from((a as u128 % b as u128) as i128),
+    }
+}
+
+/// `base ^ exponent`, or `None` when it has more than 128 bits (it then
+/// overflows any type). Compiled code checks for a negative exponent first.
+fn power(base: &BigInt, exponent: &BigInt) -> Option<BigInt> {
+    match exponent.to_u32() {
+        Some(exponent) if exponent <= 128 => Some(base.pow(exponent)),
+        // Only 0, 1 and -1 survive a larger one
+        _ if base.bits() <= 1 => Some(match base.is_negative() && exponent.bit(0) {
+            true => base.clone(),
+            false => base.abs(),
+        }),
+        _ => None,
+    }
+}
+
+/// The wrapped result of `base ^ exponent`, for any width (as `int_pow`
+/// only handles 64 bits).
+extern "C" fn pow(b_low: u64, b_high: i64, e_low: u64, e_high: i64, signed: i64) -> Parts {
+    let (base, exponent) = (value(b_low, b_high, signed), value(e_low, e_high, signed));
+    if let Some(n) = power(&base, &exponent) {
+        return wrap(&n);
+    }
+    // Square-and-multiply modulo 2^128, which two's complement wraps at
+    let (mut result, mut factor) = (1_u128, join(b_low, b_high) as u128);
+    let mut remaining = join(e_low, e_high) as u128;
+    while remaining != 0 {
+        if remaining & 1 == 1 {
+            result = result.wrapping_mul(factor);
+        }
+        factor = factor.wrapping_mul(factor);
+        remaining >>= 1;
+    }
+    Parts::from(result as i128)
+}
+
+/// Whether `base ^ exponent` is out of range for a type of `bits` bits.
+extern "C" fn pow_overflows(
+    b_low: u64,
+    b_high: i64,
+    e_low: u64,
+    e_high: i64,
+    signed: i64,
+    bits: i64,
+) -> i64 {
+    let ty = IntType::new(signed != 0, bits as u32);
+    let (base, exponent) = (value(b_low, b_high, signed), value(e_low, e_high, signed));
+    match power(&base, &exponent) {
+        Some(n) => (!ty.contains(&n)).into(),
+        None => 1,
+    }
+}
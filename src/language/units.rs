@@ -0,0 +1,235 @@
+use std::hash::{Hash, Hasher};
+
+/// Exponents of the base dimensions length, mass and time, e.g. `[1, 0, -1]`
+/// for a speed.
+pub type Dimension = [i8; 3];
+
+const DIMENSIONLESS: Dimension = [0, 0, 0];
+
+/// SI unit of each base dimension, in `Dimension` order.
+const BASE_UNITS: [&str; 3] = ["m", "kg", "s"];
+
+/// Units that can be written after a number, with their dimension and how
+/// many SI base units one of them is.
+const UNITS: &[(&str, Dimension, f64)] = &[
+    // Length
+    ("m", [1, 0, 0], 1.0),
+    ("km", [1, 0, 0], 1e3),
+    ("cm", [1, 0, 0], 1e-2),
+    ("mm", [1, 0, 0], 1e-3),
+    ("nm", [1, 0, 0], 1e-9),
+    ("mi", [1, 0, 0], 1609.344),
+    ("yd", [1, 0, 0], 0.9144),
+    ("ft", [1, 0, 0], 0.3048),
+    // Mass
+    ("kg", [0, 1, 0], 1.0),
+    ("g", [0, 1, 0], 1e-3),
+    ("mg", [0, 1, 0], 1e-6),
+    ("t", [0, 1, 0], 1e3),
+    ("lb", [0, 1, 0], 0.453_592_37),
+    ("oz", [0, 1, 0], 0.028_349_523_125),
+    // Time
+    ("s", [0, 0, 1], 1.0),
+    ("ms", [0, 0, 1], 1e-3),
+    ("min", [0, 0, 1], 60.0),
+    ("h", [0, 0, 1], 3600.0),
+    ("day", [0, 0, 1], 86400.0),
+    ("week", [0, 0, 1], 604_800.0),
+    // Derived
+    ("mph", [1, 0, -1], 0.44704),
+    ("Hz", [0, 0, -1], 1.0),
+    ("L", [3, 0, 0], 1e-3),
+    ("mL", [3, 0, 0], 1e-6),
+    ("N", [1, 1, -2], 1.0),
+    ("Pa", [-1, 1, -2], 1.0),
+    ("J", [2, 1, -2], 1.0),
+    ("kJ", [2, 1, -2], 1e3),
+    ("kWh", [2, 1, -2], 3.6e6),
+    ("W", [2, 1, -3], 1.0),
+    ("kW", [2, 1, -3], 1e3),
+];
+
+/// Names of dimensions, for diagnostics.
+const DIMENSION_NAMES: &[(Dimension, &str)] = &[
+    ([1, 0, 0], "a length"),
+    ([0, 1, 0], "a mass"),
+    ([0, 0, 1], "a time"),
+    ([2, 0, 0], "an area"),
+    ([3, 0, 0], "a volume"),
+    ([1, 0, -1], "a speed"),
+    ([1, 0, -2], "an acceleration"),
+    ([0, 0, -1], "a frequency"),
+    ([1, 1, -2], "a force"),
+    ([-1, 1, -2], "a pressure"),
+    ([2, 1, -2], "an energy"),
+    ([2, 1, -3], "a power"),
+];
+
+/// A unit of measurement such as `km/h`: its dimension, how many SI base
+/// units (m, kg, s) one of it is, and how it is written. Compiled code only
+/// ever works on values in SI base units, so units only exist while typing.
+#[derive(Debug, Clone)]
+pub struct Unit {
+    pub dimension: Dimension,
+    pub scale: f64,
+    pub name: String,
+}
+
+impl PartialEq for Unit {
+    fn eq(&self, other: &Self) -> bool {
+        self.dimension == other.dimension
+            && self.scale.to_bits() == other.scale.to_bits()
+            && self.name == other.name
+    }
+}
+
+impl Eq for Unit {}
+
+impl Hash for Unit {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.dimension.hash(state);
+        self.scale.to_bits().hash(state);
+        self.name.hash(state);
+    }
+}
+
+impl Unit {
+    pub fn named(name: &str) -> Option<Self> {
+        UNITS
+            .iter()
+            .find(|(unit, _, _)| *unit == name)
+            .map(|&(name, dimension, scale)| Self {
+                dimension,
+                scale,
+                name: name.to_string(),
+            })
+    }
+
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        UNITS.iter().map(|(name, _, _)| *name)
+    }
+
+    /// What plain numbers have, e.g. the `2` in `2 * 3 km`.
+    pub fn dimensionless() -> Self {
+        Self {
+            dimension: DIMENSIONLESS,
+            scale: 1.0,
+            name: String::new(),
+        }
+    }
+
+    pub fn is_dimensionless(&self) -> bool {
+        self.dimension == DIMENSIONLESS
+    }
+
+    pub fn mul(&self, other: &Self) -> Option<Self> {
+        let name = match (self.name.is_empty(), other.name.is_empty()) {
+            (true, _) => other.name.clone(),
+            (_, true) => self.name.clone(),
+            _ => format!("{}*{}", self.name, other.name),
+        };
+        Some(Self {
+            dimension: combine(self.dimension, other.dimension, |a, b| a.checked_add(b))?,
+            scale: self.scale * other.scale,
+            name,
+        })
+    }
+
+    pub fn div(&self, other: &Self) -> Option<Self> {
+        let denominator = match other.name.contains(['*', '/']) {
+            true => format!("({})", other.name),
+            false => other.name.clone(),
+        };
+        let name = match (self.name.is_empty(), other.name.is_empty()) {
+            (_, true) => self.name.clone(),
+            (true, _) => format!("1/{}", denominator),
+            _ => format!("{}/{}", self.name, denominator),
+        };
+        Some(Self {
+            dimension: combine(self.dimension, other.dimension, |a, b| a.checked_sub(b))?,
+            scale: self.scale / other.scale,
+            name,
+        })
+    }
+
+    pub fn pow(&self, exponent: i64) -> Option<Self> {
+        let exponent = i8::try_from(exponent).ok()?;
+        if exponent == 1 {
+            return Some(self.clone());
+        }
+        let base = match self.name.contains(['*', '/', '^']) {
+            true => format!("({})", self.name),
+            false => self.name.clone(),
+        };
+        Some(Self {
+            dimension: combine(self.dimension, self.dimension, |a, _| {
+                a.checked_mul(exponent)
+            })?,
+            scale: self.scale.powi(exponent.into()),
+            name: format!("{}^{}", base, exponent),
+        })
+    }
+
+    /// The unit whose square this is, if every exponent is even. It is
+    /// written in SI base units, e.g. the square root of `mi^2` is in `m`.
+    pub fn sqrt(&self) -> Option<Self> {
+        if self.dimension.iter().any(|exponent| exponent % 2 != 0) {
+            return None;
+        }
+        let dimension = self.dimension.map(|exponent| exponent / 2);
+        Some(Self {
+            dimension,
+            scale: 1.0,
+            name: si_name(dimension),
+        })
+    }
+
+    /// How the unit's dimension is described in diagnostics, e.g.
+    /// "a speed (km/h)".
+    pub fn describe(&self) -> String {
+        if self.is_dimensionless() {
+            return "a plain number".into();
+        }
+        match DIMENSION_NAMES.iter().find(|(d, _)| *d == self.dimension) {
+            Some((_, name)) => format!("{} ({})", name, self.name),
+            None => format!("a quantity in {}", si_name(self.dimension)),
+        }
+    }
+}
+
+fn combine(a: Dimension, b: Dimension, f: impl Fn(i8, i8) -> Option<i8>) -> Option<Dimension> {
+    let mut result = DIMENSIONLESS;
+    for ((exponent, a), b) in result.iter_mut().zip(a).zip(b) {
+        *exponent = f(a, b)?;
+    }
+    Some(result)
+}
+
+/// A dimension written in SI base units, e.g. `m*kg/s^2`.
+fn si_name(dimension: Dimension) -> String {
+    let part = |unit: &str, exponent: i8| match exponent {
+        1 => unit.to_string(),
+        _ => format!("{}^{}", unit, exponent),
+    };
+    let numerator: Vec<String> = BASE_UNITS
+        .iter()
+        .zip(dimension)
+        .filter(|(_, exponent)| *exponent > 0)
+        .map(|(unit, exponent)| part(unit, exponent))
+        .collect();
+    let denominator: Vec<String> = BASE_UNITS
+        .iter()
+        .zip(dimension)
+        .filter(|(_, exponent)| *exponent < 0)
+        .map(|(unit, exponent)| part(unit, -exponent))
+        .collect();
+    let numerator = match numerator.is_empty() {
+        true => "1".to_string(),
+        false => numerator.join("*"),
+    };
+    match denominator.len() {
+        0 => numerator,
+        1 => format!("{}/{}", numerator, denominator[0]),
+        _ => format!("{}/({})", numerator, denominator.join("*")),
+    }
+}
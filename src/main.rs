@@ -1,9 +1,22 @@
 #![feature(str_as_str)]
 
+use adder_treesitter_cranelift::language::OverflowMode;
 use adder_treesitter_cranelift::repl::run_repl;
 use miette::Result as MietteResult;
 
 fn main() -> MietteResult<()> {
     miette::set_panic_hook();
-    run_repl()
+    let args: Vec<String> = std::env::args().collect();
+    // `--places 4` keeps four digits after the point in decimals
+    let places = args
+        .iter()
+        .position(|arg| arg == "--places")
+        .and_then(|i| args.get(i + 1)?.parse().ok());
+    // `--overflow wrapping` (or `checked`, `saturating`) instead of promoting
+    // integers that overflow to big integers
+    let overflow = args
+        .iter()
+        .position(|arg| arg == "--overflow")
+        .and_then(|i| OverflowMode::from_name(args.get(i + 1)?));
+    run_repl(args.iter().any(|arg| arg == "--exact"), places, overflow)
 }
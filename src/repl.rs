@@ -1,4 +1,4 @@
-use crate::language::{CalcValue, Calculator};
+use crate::language::{CalcValue, Calculator, OverflowMode};
 use crossterm::cursor::MoveTo;
 use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
 use crossterm::style::{Color, ResetColor, SetBackgroundColor, SetForegroundColor};
@@ -7,6 +7,7 @@ use crossterm::terminal::{
 };
 use crossterm::{event, execute};
 use miette::{IntoDiagnostic, Result as MietteResult};
+use num_traits::ToPrimitive;
 use std::io;
 use std::io::Write;
 use std::time::Duration;
@@ -25,6 +26,7 @@ pub enum InputAction {
     MoveCursorHome,
     MoveCursorEnd,
     SetContent(String), // New action for setting entire content at once
+    Submit,             // Keep the evaluated line's side effects and start a fresh one
 }
 pub struct InputState {
     pub content: String,
@@ -122,6 +124,11 @@ impl InputState {
                 self.cursor_position = self.content.len();
                 true
             }
+            InputAction::Submit => {
+                self.content.clear();
+                self.cursor_position = 0;
+                true
+            }
         }
     }
 
@@ -186,8 +193,29 @@ impl InputState {
                     "float" => {
                         execute!(stdout, SetForegroundColor(Color::Cyan)).into_diagnostic()?
                     }
+                    "boolean" => {
+                        execute!(stdout, SetForegroundColor(Color::DarkYellow)).into_diagnostic()?
+                    }
+                    "variable" => {
+                        execute!(stdout, SetForegroundColor(Color::Blue)).into_diagnostic()?
+                    }
+                    "constant" => {
+                        execute!(stdout, SetForegroundColor(Color::DarkCyan)).into_diagnostic()?
+                    }
+                    "function" => {
+                        execute!(stdout, SetForegroundColor(Color::Magenta)).into_diagnostic()?
+                    }
+                    "keyword" => {
+                        execute!(stdout, SetForegroundColor(Color::DarkBlue)).into_diagnostic()?
+                    }
+                    "type" => {
+                        execute!(stdout, SetForegroundColor(Color::DarkGreen)).into_diagnostic()?
+                    }
                     "punctuation" => execute!(stdout, SetForegroundColor(Color::DarkMagenta))
                         .into_diagnostic()?,
+                    "comment" => {
+                        execute!(stdout, SetForegroundColor(Color::DarkGrey)).into_diagnostic()?
+                    }
                     "error" => {
                         execute!(stdout, SetForegroundColor(Color::Red)).into_diagnostic()?
                     }
@@ -228,8 +256,19 @@ impl InputState {
     }
 }
 
-pub fn run_repl() -> MietteResult<()> {
+pub fn run_repl(
+    exact: bool,
+    places: Option<u32>,
+    overflow: Option<OverflowMode>,
+) -> MietteResult<()> {
     let mut calculator = Calculator::new()?;
+    calculator.set_exact(exact);
+    if let Some(places) = places {
+        calculator.set_decimal_places(places);
+    }
+    if let Some(overflow) = overflow {
+        calculator.set_overflow(overflow);
+    }
     let mut input_state = InputState::new();
     let mut last_input = String::new();
     let mut stdout = io::stdout();
@@ -269,6 +308,7 @@ pub fn run_repl() -> MietteResult<()> {
                             (KeyCode::Char(c), KeyModifiers::NONE) => {
                                 Some(InputAction::InsertChar(c))
                             }
+                            (KeyCode::Enter, _) => Some(InputAction::Submit),
                             (KeyCode::Backspace, _) => Some(InputAction::Backspace),
                             (KeyCode::Delete, _) => Some(InputAction::Delete),
                             (KeyCode::Left, _) => Some(InputAction::MoveCursorLeft),
@@ -279,12 +319,19 @@ pub fn run_repl() -> MietteResult<()> {
                         };
 
                         let input_changed = if let Some(action) = action {
+                            // Only a submitted line's assignments are kept
+                            if matches!(action, InputAction::Submit) {
+                                calculator.commit();
+                            }
                             input_state.handle_action(action)
                         } else {
                             false
                         };
 
-                        if input_changed {
+                        if input_changed && input_state.content == last_input {
+                            // Just the cursor moved, so nothing is evaluated again
+                            input_state.draw_input_line(&mut stdout, &mut calculator)?;
+                        } else if input_changed {
                             let common_prefix = input_state
                                 .content
                                 .chars()
@@ -297,49 +344,84 @@ pub fn run_repl() -> MietteResult<()> {
                             // Draw input line with cursor
                             input_state.draw_input_line(&mut stdout, &mut calculator)?;
 
-                            match calculator.update_input(
+                            // One row per statement, or more for its diagnostic.
+                            // The line is evaluated from the last submitted state
+                            calculator.rollback();
+                            let mut current_row = 1;
+                            for result in calculator.update_program(
                                 &input_state.content,
                                 common_prefix,
                                 last_input.len(),
                                 input_state.content.len(),
                             ) {
-                                Ok(value) => {
-                                    execute!(stdout, MoveTo(0, 1)).into_diagnostic()?;
-                                    match value {
-                                        CalcValue::Integer(_) => {
-                                            execute!(stdout, SetForegroundColor(Color::Yellow))
-                                                .into_diagnostic()?
+                                match result {
+                                    Ok(value) => {
+                                        execute!(stdout, MoveTo(0, current_row))
+                                            .into_diagnostic()?;
+                                        match value {
+                                            CalcValue::Integer(_)
+                                            | CalcValue::BigInt(_)
+                                            | CalcValue::Sized(_) => {
+                                                execute!(stdout, SetForegroundColor(Color::Yellow))
+                                                    .into_diagnostic()?
+                                            }
+                                            CalcValue::Float(_) | CalcValue::Quantity(..) => {
+                                                execute!(stdout, SetForegroundColor(Color::Cyan))
+                                                    .into_diagnostic()?
+                                            }
+                                            CalcValue::Decimal(_) => {
+                                                execute!(stdout, SetForegroundColor(Color::Green))
+                                                    .into_diagnostic()?
+                                            }
+                                            CalcValue::Rational(_) => execute!(
+                                                stdout,
+                                                SetForegroundColor(Color::DarkCyan)
+                                            )
+                                            .into_diagnostic()?,
+                                            CalcValue::Complex(_) => {
+                                                execute!(stdout, SetForegroundColor(Color::Blue))
+                                                    .into_diagnostic()?
+                                            }
+                                            CalcValue::Bool(_) => execute!(
+                                                stdout,
+                                                SetForegroundColor(Color::DarkYellow)
+                                            )
+                                            .into_diagnostic()?,
+                                            CalcValue::Function(_) => {
+                                                execute!(stdout, SetForegroundColor(Color::Magenta))
+                                                    .into_diagnostic()?
+                                            }
                                         }
-                                        CalcValue::Float(_) => {
-                                            execute!(stdout, SetForegroundColor(Color::Cyan))
-                                                .into_diagnostic()?
+                                        match &value {
+                                            CalcValue::Rational(r) => {
+                                                let approximation = r.to_f64().unwrap_or(f64::NAN);
+                                                writeln!(stdout, "= {} ≈ {}", value, approximation)
+                                            }
+                                            _ => writeln!(stdout, "= {}", value),
                                         }
-                                    }
-                                    writeln!(stdout, "= {}", value).into_diagnostic()?;
-                                    execute!(stdout, ResetColor).into_diagnostic()?;
-                                    // Return cursor to input line
-                                    execute!(stdout, MoveTo(input_state.cursor_position as u16, 0))
                                         .into_diagnostic()?;
-                                }
-                                Err(error) => {
-                                    let mut error_buf = Vec::new();
-                                    writeln!(error_buf, "{:?}", error).into_diagnostic()?;
-                                    let error_str = String::from_utf8_lossy(&error_buf);
-
-                                    let mut current_row = 1;
-                                    for line in error_str.lines() {
-                                        if !line.is_empty() {
-                                            execute!(stdout, MoveTo(0, current_row))
-                                                .into_diagnostic()?;
-                                            writeln!(stdout, "{}", line).into_diagnostic()?;
-                                            current_row += 1;
+                                        execute!(stdout, ResetColor).into_diagnostic()?;
+                                        current_row += 1;
+                                    }
+                                    Err(error) => {
+                                        let mut error_buf = Vec::new();
+                                        writeln!(error_buf, "{:?}", error).into_diagnostic()?;
+                                        let error_str = String::from_utf8_lossy(&error_buf);
+
+                                        for line in error_str.lines() {
+                                            if !line.is_empty() {
+                                                execute!(stdout, MoveTo(0, current_row))
+                                                    .into_diagnostic()?;
+                                                writeln!(stdout, "{}", line).into_diagnostic()?;
+                                                current_row += 1;
+                                            }
                                         }
                                     }
-                                    // Return cursor to input line
-                                    execute!(stdout, MoveTo(input_state.cursor_position as u16, 0))
-                                        .into_diagnostic()?;
                                 }
                             }
+                            // Return cursor to input line
+                            execute!(stdout, MoveTo(input_state.cursor_position as u16, 0))
+                                .into_diagnostic()?;
 
                             stdout.flush().into_diagnostic()?;
                             last_input = input_state.content.clone();
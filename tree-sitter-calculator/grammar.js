@@ -6,21 +6,90 @@
 
 /// <reference types="tree-sitter-cli/dsl" />
 // @ts-check
+
+// Logical, comparison and bitwise operators sit below arithmetic, in C order
+const PREC = {
+    unsupported: 0,
+    conditional: 1,
+    conversion: 2,
+    logical_or: 3,
+    logical_and: 4,
+    bitwise_or: 5,
+    bitwise_xor: 6,
+    bitwise_and: 7,
+    equality: 8,
+    relational: 9,
+    shift: 10,
+    additive: 11,
+    multiplicative: 12,
+    implicit: 13,
+    cast: 14,
+    unary: 15,
+    power: 16,
+    call: 17,
+};
+
 module.exports = grammar({
     name: 'calculator',
 
+    // Newlines end statements, so they aren't skipped like other whitespace
     extras: $ => [
-        /\s/
+        /[ \t\r\f\v]/,
+        $.comment,
     ],
 
     rules: {
-        source: $ => $.expression,
+        // Statements separated by `;` or newlines, any of which may be empty
+        source: $ => seq(
+            optional($._statement),
+            repeat(seq($._separator, optional($._statement)))
+        ),
+
+        _statement: $ => choice(
+            $.function_definition,
+            $.assignment,
+            $.expression,
+        ),
+
+        _separator: $ => choice(';', '\n'),
+
+        // `# to the end of the line` (which still ends the statement) or
+        // `/* anywhere */`
+        comment: $ => token(choice(
+            /#[^\n]*/,
+            seq('/*', /[^*]*\*+([^/*][^*]*\*+)*/, '/'),
+        )),
+
+        assignment: $ => seq(
+            field('name', $.identifier),
+            '=',
+            field('value', $.expression)
+        ),
+
+        function_definition: $ => seq(
+            field('signature', $.call_expression),
+            '=',
+            field('body', $.expression)
+        ),
 
         expression: $ => choice(
             $.number,
             $.float,
+            $.imaginary,
+            $.decimal,
+            $.quantity,
+            $.boolean,
+            $.identifier,
             $.parenthesized_expression,
+            $.call_expression,
+            $.aggregate,
+            $.implicit_multiplication,
+            $.unary_expression,
+            $.percent,
+            $.cast,
             $.binary_expression,
+            $.conditional_expression,
+            $.conversion,
         ),
 
         parenthesized_expression: $ => seq(
@@ -29,44 +98,190 @@ module.exports = grammar({
             ')'
         ),
 
-        number: $ => choice(
-            /[0-9]+/,
-            seq('-', /[0-9]+/),
+        // An integer, optionally with a type suffix such as `u8` or `_i32`.
+        // Digits are checked when the literal is parsed, so that `0b102`
+        // is reported as a bad digit rather than a syntax error (and
+        // prefixed literals take a suffix the same way, e.g. `0xFF_u8`)
+        number: $ => token(choice(
+            /0[xXoObB][0-9a-zA-Z_]*/,
+            /[0-9][0-9_]*/,
+            /[0-9][0-9_]*[iu](8|16|32|64|128)/,
+        )),
+
+        float: $ => token(choice(
+            /([0-9][0-9_]*)?\.[0-9][0-9_]*([eE][+-]?[0-9][0-9_]*)?/,
+            /[0-9][0-9_]*[eE][+-]?[0-9][0-9_]*/,
+        )),
+
+        // A number or float followed by `i` (or `j`), e.g. `4i` or `2.5j`
+        imaginary: $ => token(choice(
+            /[0-9][0-9_]*[ij]/,
+            /([0-9][0-9_]*)?\.[0-9][0-9_]*([eE][+-]?[0-9][0-9_]*)?[ij]/,
+            /[0-9][0-9_]*[eE][+-]?[0-9][0-9_]*[ij]/,
+        )),
+
+        // A number or float followed by `d`, e.g. `0.1d` or `19.99d`, which
+        // is kept exact to a fixed number of places
+        decimal: $ => token(choice(
+            /[0-9][0-9_]*(\.[0-9][0-9_]*)?d/,
+            /\.[0-9][0-9_]*d/,
+        )),
+
+        // A number with a unit attached, e.g. `3 km` or `20 min`. A power
+        // written right after the unit belongs to it, so `4 m^2` is four
+        // square metres
+        quantity: $ => seq(
+            field('value', choice($.number, $.float)),
+            field('unit', $.unit)
+        ),
+
+        // A name, the same token as an identifier, so that a name followed
+        // by `(` can still be called, as in `2 sqrt(9)`
+        unit: $ => choice($.identifier, /[a-zA-Z_][a-zA-Z0-9_]*\^-?[0-9]+/),
+
+        boolean: $ => choice('true', 'false'),
+
+        identifier: $ => /[a-zA-Z_][a-zA-Z0-9_]*/,
+
+        // Above implicit multiplication, so `(a) f(x)` calls `f`
+        call_expression: $ => prec(PREC.call, seq(
+            field('function', $.identifier),
+            '(',
+            optional(seq(
+                field('argument', $.expression),
+                repeat(seq(',', field('argument', $.expression)))
+            )),
+            ')'
+        )),
+
+        // `sum(i = 1..n, i * i)` or `prod(k = 1..n, k)`, with both bounds
+        // included
+        aggregate: $ => seq(
+            field('function', choice('sum', 'prod')),
+            '(',
+            field('variable', $.identifier),
+            '=',
+            field('start', $.expression),
+            '..',
+            field('end', $.expression),
+            ',',
+            field('body', $.expression),
+            ')'
+        ),
+
+        // Juxtaposition, e.g. `2(3 + 4)`, `(a)(b)` or `(a + b) c`. Only
+        // literals and parentheses can be on the left, so it binds tighter
+        // than every operator: `1 / 2(3)` is `1 / (2 * 3)`. A name right
+        // after a number, as in `2x` or `2 pi`, is parsed as a quantity,
+        // unless it is called as in `2 sqrt(9)`
+        implicit_multiplication: $ => choice(
+            prec.left(PREC.implicit, seq(
+                field('left', choice(
+                    $.number,
+                    $.float,
+                    $.decimal,
+                    $.quantity,
+                    $.parenthesized_expression,
+                    $.implicit_multiplication
+                )),
+                field('right', $.parenthesized_expression)
+            )),
+            prec.left(PREC.implicit, seq(
+                field('left', choice(
+                    $.quantity,
+                    $.parenthesized_expression,
+                    $.implicit_multiplication
+                )),
+                field('right', choice($.identifier, $.call_expression))
+            )),
+            prec.left(PREC.implicit, seq(
+                field('left', choice($.number, $.float)),
+                field('right', $.call_expression)
+            )),
         ),
 
-        float: $ => choice(
-            /[0-9]*\.[0-9]+/,
-            seq('-', /[0-9]*\.[0-9]+/),
+        // Binds tighter than every binary operator, so `-2 * 3` is `(-2) * 3`
+        unary_expression: $ => prec(PREC.unary, seq(
+            field('operator', choice('-', '+', '~', '!')),
+            field('operand', $.expression)
+        )),
+
+        // `50%`, which is a remainder instead whenever an operand follows,
+        // signed or not, as in `7 % 3` or `7 % -3` (and `50% - 3`). Only
+        // the `%` has no precedence, so it loses to anything that can
+        // start a right side, but still binds like `/` to what precedes it
+        percent: $ => prec.left(PREC.multiplicative, seq(
+            field('value', $.expression),
+            prec(PREC.unsupported, field('operator', '%'))
+        )),
+
+        // `x as int` or `3.7 as float`. Binds tighter than `*` but looser
+        // than a sign, as in Rust, so `2 * -3.7 as int` is `2 * ((-3.7) as int)`
+        cast: $ => prec.left(PREC.cast, seq(
+            field('value', $.expression),
+            field('operator', 'as'),
+            field('type', $.identifier)
+        )),
+
+        // Loosest of all, so each branch extends as far as it can
+        conditional_expression: $ => choice(
+            prec.right(PREC.conditional, seq(
+                'if',
+                field('condition', $.expression),
+                'then',
+                field('consequence', $.expression),
+                'else',
+                field('alternative', $.expression)
+            )),
+            prec.right(PREC.conditional, seq(
+                field('condition', $.expression),
+                '?',
+                field('consequence', $.expression),
+                ':',
+                field('alternative', $.expression)
+            )),
         ),
 
+        // Looser than every operator, so `3 km / 20 min in km/h` converts
+        // the whole quotient
+        conversion: $ => prec.left(PREC.conversion, seq(
+            field('value', $.expression),
+            field('operator', 'in'),
+            field('unit', $.expression)
+        )),
+
         binary_expression: $ => choice(
             // Unsupported operators (probably a better way...)
-            ..."!@#$%^&,.=_~|".split("").map((op) =>
-                prec.left(0, seq(
+            ..."@$._".split("").map((op) =>
+                prec.left(PREC.unsupported, seq(
                     field('left', $.expression),
                     field('operator', op),
                     field('right', $.expression)
                 ))
             ),
             // Supported operators
-            prec.left(1, seq(
-                field('left', $.expression),
-                field('operator', "-"),
-                field('right', $.expression)
-            )),
-            prec.left(2, seq(
-                field('left', $.expression),
-                field('operator', "+"),
-                field('right', $.expression)
-            )),
-            prec.left(3, seq(
-                field('left', $.expression),
-                field('operator', "/"),
-                field('right', $.expression)
-            )),
-            prec.left(4, seq(
+            ...[
+                [PREC.logical_or, "||"],
+                [PREC.logical_and, "&&"],
+                [PREC.bitwise_or, "|"],
+                [PREC.bitwise_xor, "xor"],
+                [PREC.bitwise_and, "&"],
+                [PREC.equality, choice("==", "!=")],
+                [PREC.relational, choice("<", "<=", ">", ">=")],
+                [PREC.shift, choice("<<", ">>")],
+                [PREC.additive, choice("+", "-")],
+                [PREC.multiplicative, choice("*", "/", "//", "%")],
+            ].map(([precedence, operator]) =>
+                prec.left(precedence, seq(
+                    field('left', $.expression),
+                    field('operator', operator),
+                    field('right', $.expression)
+                ))
+            ),
+            // Above unary minus, so `-2^2` is `-(2^2)`
+            prec.right(PREC.power, seq(
                 field('left', $.expression),
-                field('operator', "*"),
+                field('operator', choice("^", "**")),
                 field('right', $.expression)
             )),
         )
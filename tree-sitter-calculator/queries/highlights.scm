@@ -1,9 +1,24 @@
 ; Operators
 (binary_expression
-  operator: ["+" "*" "-" "/"] @operator)
+  operator: ["+" "*" "-" "/" "//" "%" "^" "**" "&" "|" "xor" "<<" ">>"
+             "==" "!=" "<" "<=" ">" ">=" "&&" "||"] @operator)
+
+(unary_expression
+  operator: ["-" "+" "~" "!"] @operator)
+
+(percent operator: "%" @operator)
+
+(assignment "=" @operator)
+
+(conditional_expression ["?" ":"] @operator)
+
+(aggregate ["=" ".."] @operator)
+
+; Keywords
+["if" "then" "else" "in" "as"] @keyword
 
 ; Parens
-["(" ")"] @punctuation
+["(" ")" ","] @punctuation
 
 ; Numbers
 (number) @number
@@ -11,5 +26,43 @@
 ; Floats
 (float) @float
 
+; Imaginary numbers
+(imaginary) @number
+
+; Decimals
+(decimal) @number
+
+; Booleans
+(boolean) @boolean
+
+; Constants, including after a number as in `2pi`
+((identifier) @constant
+  (#any-of? @constant "pi" "e" "tau" "inf" "nan"))
+
+((unit) @constant
+  (#any-of? @constant "pi" "e" "tau" "inf" "nan"))
+
+; Units
+(unit) @type
+
+(conversion
+  unit: (expression (identifier) @type))
+
+(cast
+  type: (identifier) @type)
+
+; Functions
+(call_expression
+  function: (identifier) @function)
+
+(aggregate
+  function: _ @function)
+
+; Variables
+(identifier) @variable
+
+; Comments
+(comment) @comment
+
 ; Errors
-(ERROR) @error
\ No newline at end of file
+(ERROR) @error